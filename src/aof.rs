@@ -0,0 +1,740 @@
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::future;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use tokio::codec::Decoder;
+use tokio::timer::Interval;
+
+use super::command;
+use super::database::{self, Database, Value};
+use super::dump;
+use super::resp;
+use super::snapshot::{self, Entries};
+
+// The append only file (AOF) logs every command that modified the dataset,
+// as the RESP array a client would send. Each shard appends to its own
+// segment, which only holds commands on keys the shard owns, so segments
+// are independent of each other and are replayed concurrently on startup.
+// A manifest lists the segments, one per shard.
+//
+// A segment is rewritten (BGREWRITEAOF) into a new one holding a RESTORE
+// per key, taken the way BGSAVE takes a snapshot, followed by the commands
+// logged since the rewrite started. Each shard swaps its segment for the
+// new one on its own, by pointing the manifest at it, so the manifest
+// always lists complete segments.
+//
+// Commands are logged the way they ran rather than the way they were sent:
+// relative deadlines are made absolute, keys removed at random (SPOP) are
+// named, expired and evicted keys are logged as deleted, and a command
+// spanning several shards logs its effect on each of them. Replaying a
+// segment thus gives the same dataset whenever it is replayed.
+const MANIFEST: &str = "mkii-aof.manifest";
+const MANIFEST_HEADER: &str = "mkii-aof-manifest";
+
+// SYNC_INTERVAL is how often the segments are synced under the everysec
+// policy, and how often writes that failed are retried.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// READ_CHUNK is the number of bytes read from a segment at a time while
+// replaying it.
+const READ_CHUNK: usize = 64 * 1024;
+
+// REWRITE_CHUNK is the number of keys BGREWRITEAOF writes at a time before
+// letting the shard serve requests again.
+const REWRITE_CHUNK: usize = 1024;
+
+lazy_static! {
+    // CURRENT is the manifest of the segments being appended to.
+    static ref CURRENT: Mutex<Option<Manifest>> = Mutex::new(None);
+}
+
+// Fsync tells when the segments are synced to disk: after every write
+// (always, writes wait for it), once per second in the background
+// (everysec) or never, leaving it to the OS (no). Commands are written to
+// their segment before they are replied to under every policy.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fsync {
+    Always,
+    Everysec,
+    No,
+}
+
+const FSYNCS: [(Fsync, &str); 3] = [(Fsync::Always, "always"), (Fsync::Everysec, "everysec"), (Fsync::No, "no")];
+
+impl Fsync {
+    pub fn from_name(name: &str) -> Option<Fsync> {
+        FSYNCS.iter().find(|(_, n)| n.eq_ignore_ascii_case(name)).map(|(f, _)| *f)
+    }
+
+    pub fn name(self) -> &'static str {
+        FSYNCS[self as usize].1
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static FSYNC: AtomicUsize = AtomicUsize::new(Fsync::Everysec as usize);
+
+pub fn configure(enabled: bool, fsync: Fsync) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+    FSYNC.store(fsync as usize, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn fsync() -> Fsync {
+    FSYNCS[FSYNC.load(Ordering::Relaxed)].0
+}
+
+// Segment is the segment a shard appends to, along with the commands not
+// written to it yet.
+struct Segment {
+    file: File,
+    buf: BytesMut,
+    // database the last command in the segment applies to, None until the
+    // segment holds a SELECT
+    db: Option<usize>,
+    // commands logged after the next one, see defer
+    deferred: Vec<(usize, Vec<Bytes>)>,
+    // length of the segment, up to the end of the last complete write
+    len: u64,
+    // set once data was written that wasn't synced since, and while a sync
+    // runs in the background
+    unsynced: bool,
+    syncing: Arc<AtomicBool>,
+    // set while writes fail, so that the failure is only reported once
+    failing: bool,
+    // rewrite of the segment in progress
+    rewrite: Option<Rewrite>,
+}
+
+// Rewrite is the rewrite of a segment in progress: the commands logged
+// since it started, which are appended to the new segment once it holds
+// the dataset as of then.
+struct Rewrite {
+    buf: BytesMut,
+    db: Option<usize>,
+}
+
+impl Segment {
+    fn open(path: &Path) -> io::Result<Segment> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Segment {
+            file,
+            buf: BytesMut::new(),
+            db: None,
+            deferred: Vec::new(),
+            len,
+            unsynced: false,
+            syncing: Arc::new(AtomicBool::new(false)),
+            failing: false,
+            rewrite: None,
+        })
+    }
+
+    fn append<A: AsRef<[u8]>>(&mut self, db: usize, args: &[A]) {
+        put_selected(&mut self.buf, &mut self.db, db, args);
+        if let Some(rewrite) = self.rewrite.as_mut() {
+            put_selected(&mut rewrite.buf, &mut rewrite.db, db, args);
+        }
+    }
+
+    fn append_deferred(&mut self) {
+        for (db, args) in std::mem::take(&mut self.deferred) {
+            self.append(db, &args);
+        }
+    }
+
+    // write writes the buffered commands to the segment, then syncs it if
+    // sync is set. If the write fails the segment is truncated back to its
+    // last complete write, so that it doesn't end in the middle of a
+    // command, and the commands stay buffered to be written again.
+    fn write(&mut self, sync: bool) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            if let Err(e) = self.file.write_all(&self.buf) {
+                let _ = self.file.set_len(self.len);
+                return Err(e);
+            }
+            self.len += self.buf.len() as u64;
+            self.buf.clear();
+            self.unsynced = true;
+        }
+        if sync && self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    // sync_in_background syncs the segment on another thread, unless the
+    // previous sync is still running.
+    fn sync_in_background(&mut self) {
+        if !self.unsynced || self.syncing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let file = match self.file.try_clone() {
+            Ok(file) => file,
+            Err(e) => {
+                self.syncing.store(false, Ordering::SeqCst);
+                error!("Unable to sync the append only file: {}", e);
+                return;
+            }
+        };
+        self.unsynced = false;
+        let syncing = self.syncing.clone();
+        thread::spawn(move || {
+            if let Err(e) = file.sync_data() {
+                error!("Unable to sync the append only file: {}", e);
+            }
+            syncing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    // report logs the outcome of a write, only once for a run of failures.
+    fn report(&mut self, result: io::Result<()>) {
+        match result {
+            Ok(()) if self.failing => {
+                warn!("Writing to the append only file succeeded again");
+                self.failing = false;
+            }
+            Ok(()) => {}
+            Err(e) => {
+                if !self.failing {
+                    error!("Unable to write to the append only file: {}", e);
+                }
+                self.failing = true;
+            }
+        }
+    }
+}
+
+thread_local! {
+    static SEGMENT: RefCell<Option<Segment>> = const { RefCell::new(None) };
+}
+
+// active tells whether the current shard logs the commands it runs.
+pub fn active() -> bool {
+    SEGMENT.with(|s| s.borrow().is_some())
+}
+
+// feed logs a command that ran on the database numbered db of the current
+// shard. Nothing is logged until the segments are opened, so that loading
+// the dataset on startup isn't.
+pub fn feed<A: AsRef<[u8]>>(db: usize, args: &[A]) {
+    SEGMENT.with(|s| {
+        if let Some(segment) = s.borrow_mut().as_mut() {
+            segment.append(db, args);
+            segment.append_deferred();
+        }
+    })
+}
+
+// defer logs a command after the next one fed, or at the latest when the
+// commands are flushed. A push serving blocked clients runs their pops
+// before it is logged itself, the pops are deferred so that they are
+// replayed after the push.
+pub fn defer(db: usize, args: Vec<Bytes>) {
+    SEGMENT.with(|s| {
+        if let Some(segment) = s.borrow_mut().as_mut() {
+            segment.deferred.push((db, args));
+        }
+    })
+}
+
+// feed_key logs the current state of key in db, as a RESTORE of its value
+// or a DEL if it doesn't exist. It is used by commands whose effect is
+// cheaper to log than to reproduce.
+pub fn feed_key(db: &mut Database, key: &Bytes) {
+    if !active() {
+        return;
+    }
+    let expires_at = db.expires_at(key);
+    match (db.get(key).map(dump::dump), expires_at) {
+        (Some(payload), Some(expires_at)) => feed(db.number(), &restore(key.clone(), payload, expires_at)),
+        _ => feed(db.number(), &[&b"DEL"[..], &key[..]]),
+    }
+}
+
+// write_entry appends the RESTORE command that sets key to value, with the
+// deadline expires_at, to buf.
+pub fn write_entry(buf: &mut BytesMut, key: &[u8], value: &Value, expires_at: Option<u64>) {
+    put_command(buf, &restore(Bytes::from(key), dump::dump(value), expires_at));
+}
+
+// restore returns the RESTORE command that sets key to the value dumped in
+// payload, with the deadline expires_at.
+fn restore(key: Bytes, payload: Bytes, expires_at: Option<u64>) -> Vec<Bytes> {
+    vec![
+        Bytes::from_static(b"RESTORE"),
+        key,
+        Bytes::from(expires_at.unwrap_or(0).to_string()),
+        payload,
+        Bytes::from_static(b"REPLACE"),
+        Bytes::from_static(b"ABSTTL"),
+    ]
+}
+
+// pexpireat returns the PEXPIREAT command setting the deadline of key to t.
+pub fn pexpireat(key: &Bytes, t: u64) -> Vec<Bytes> {
+    vec![Bytes::from_static(b"PEXPIREAT"), key.clone(), Bytes::from(t.to_string())]
+}
+
+// flush writes the commands logged by the current shard to its segment.
+// It is called before the shard replies to the commands, so that they are
+// logged by then, and under the always policy synced. A shard unable to
+// write under the always policy stops the server rather than reply to
+// commands that weren't logged.
+pub fn flush() {
+    if !enabled() {
+        return;
+    }
+    SEGMENT.with(|s| {
+        if let Some(segment) = s.borrow_mut().as_mut() {
+            segment.append_deferred();
+            let always = fsync() == Fsync::Always;
+            let result = segment.write(always);
+            if always {
+                if let Err(e) = result {
+                    error!("Unable to write to the append only file: {}", e);
+                    std::process::exit(1);
+                }
+            } else {
+                segment.report(result);
+            }
+        }
+    })
+}
+
+// cron runs the periodic maintenance of the segment of the shard it is
+// spawned on: it retries writes that failed, and syncs the segment under
+// the everysec policy.
+async fn cron() {
+    let mut interval = Interval::new_interval(SYNC_INTERVAL);
+    while interval.next().await.is_some() {
+        SEGMENT.with(|s| {
+            if let Some(segment) = s.borrow_mut().as_mut() {
+                let result = segment.write(false);
+                segment.report(result);
+                if fsync() == Fsync::Everysec {
+                    segment.sync_in_background();
+                }
+            }
+        })
+    }
+}
+
+fn put_header(buf: &mut BytesMut, kind: u8, n: usize) {
+    buf.extend_from_slice(&[kind]);
+    buf.extend_from_slice(n.to_string().as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+// put_selected appends args to buf, preceded by a SELECT if the commands
+// in buf so far apply to another database than db.
+fn put_selected<A: AsRef<[u8]>>(buf: &mut BytesMut, selected: &mut Option<usize>, db: usize, args: &[A]) {
+    if *selected != Some(db) {
+        put_select(buf, db);
+        *selected = Some(db);
+    }
+    put_command(buf, args);
+}
+
+fn put_select(buf: &mut BytesMut, db: usize) {
+    let n = db.to_string();
+    put_command(buf, &[&b"SELECT"[..], n.as_bytes()]);
+}
+
+// put_command appends the RESP array of args to buf.
+fn put_command<A: AsRef<[u8]>>(buf: &mut BytesMut, args: &[A]) {
+    put_header(buf, b'*', args.len());
+    for arg in args.iter() {
+        let arg = arg.as_ref();
+        put_header(buf, b'$', arg.len());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+// Manifest lists the segments of the AOF, segment i belongs to shard i.
+struct Manifest {
+    generation: u64,
+    shards: Vec<String>,
+}
+
+impl Manifest {
+    // read reads the manifest in dir, returns None if there is none.
+    fn read(dir: &Path) -> io::Result<Option<Manifest>> {
+        let text = match fs::read_to_string(dir.join(MANIFEST)) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Manifest::parse(&text).map(Some).ok_or_else(|| snapshot::invalid("invalid append only file manifest"))
+    }
+
+    fn parse(text: &str) -> Option<Manifest> {
+        let mut lines = text.lines().map(|l| l.split_whitespace().collect::<Vec<_>>());
+        let mut field = |name: &str| match lines.next() {
+            Some(ref l) if l.len() == 2 && l[0] == name => Some(l[1].to_string()),
+            _ => None,
+        };
+        if field(MANIFEST_HEADER)?.parse::<u16>().ok()? > dump::VERSION {
+            return None;
+        }
+        let generation = field("generation")?.parse().ok()?;
+        let n: usize = field("shards")?.parse().ok()?;
+        let mut shards = Vec::with_capacity(n);
+        for (i, l) in lines.enumerate() {
+            if l.len() != 3 || l[0] != "shard" || l[1].parse() != Ok(i) {
+                return None;
+            }
+            shards.push(l[2].to_string());
+        }
+        if shards.len() != n {
+            return None;
+        }
+        Some(Manifest { generation, shards })
+    }
+
+    // write replaces the manifest in dir, atomically.
+    fn write(&self, dir: &Path) -> io::Result<()> {
+        let mut text = format!(
+            "{} {}\ngeneration {}\nshards {}\n",
+            MANIFEST_HEADER,
+            dump::VERSION,
+            self.generation,
+            self.shards.len()
+        );
+        for (i, name) in self.shards.iter().enumerate() {
+            text.push_str(&format!("shard {} {}\n", i, name));
+        }
+        snapshot::replace_file(dir, MANIFEST, text.as_bytes())
+    }
+}
+
+fn segment_name(generation: u64, shard: usize) -> String {
+    format!("mkii-{}-{}.aof", generation, shard)
+}
+
+// replay applies the commands of the segment at path to dbs, the databases
+// of a shard. It returns the length of the segment up to the end of its
+// last complete command, along with its length: a crash may have left the
+// segment ending in the middle of a command.
+fn replay(path: &Path, dbs: &mut [Database]) -> io::Result<(u64, u64)> {
+    let mut file = File::open(path)?;
+    let (mut codec, mut buf, mut chunk) = (resp::Codec::new(), BytesMut::new(), vec![0; READ_CHUNK]);
+    let (mut read, mut complete, mut db) = (0, 0, 0);
+    let at = |offset: u64, e: &dyn std::fmt::Display| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} at offset {}: {}", path.display(), offset, e))
+    };
+    loop {
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            return Ok((complete, read));
+        }
+        read += n as u64;
+        buf.extend_from_slice(&chunk[..n]);
+        while let Some(msg) = codec.decode(&mut buf).map_err(|e| at(complete, &e))? {
+            let args = match msg {
+                resp::Msg::Array(Some(args)) => args,
+                _ => return Err(at(complete, &"not a command")),
+            };
+            command::replay(dbs, &mut db, command::Args(args)).map_err(|e| at(complete, &e))?;
+            complete = read - buf.len() as u64;
+        }
+    }
+}
+
+// replay_in_place replays the segment at path into dbs, the databases of
+// the current shard, dropping the incomplete command it may end with. It
+// returns the number of keys in dbs.
+fn replay_in_place(path: &Path, dbs: &mut [Database]) -> io::Result<usize> {
+    let (complete, len) = replay(path, dbs)?;
+    if complete < len {
+        warn!(
+            "{} ends with an incomplete command, truncating it from {} to {} bytes",
+            path.display(),
+            len,
+            complete
+        );
+        OpenOptions::new().write(true).open(path)?.set_len(complete)?;
+    }
+    Ok(dbs.iter().map(|db| db.len()).sum())
+}
+
+// replay_aside replays the segment at path into databases of its own and
+// returns their keys.
+fn replay_aside(path: &Path) -> io::Result<Entries> {
+    let mut dbs = database::databases();
+    let (complete, len) = replay(path, &mut dbs)?;
+    if complete < len {
+        warn!("{} ends with an incomplete command, ignoring it", path.display());
+    }
+    let mut entries = Entries::new();
+    for (i, db) in dbs.iter_mut().enumerate() {
+        let keys: Vec<Bytes> = db.keys().cloned().collect();
+        for key in keys {
+            if let Some((value, expires_at)) = db.take(&key) {
+                entries.push((i, key, value, expires_at));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// load replays the AOF in the snapshot directory into the shards. It
+// returns None if there is no AOF, otherwise the number of keys loaded and
+// whether the AOF must be rewritten before it is appended to: if it was
+// written with a different number of shards, its segments are replayed
+// aside and their keys handed to the shards owning them, so the segments
+// no longer match the shards.
+pub async fn load(pool: &tokio_io_pool::Handle) -> io::Result<Option<(usize, bool)>> {
+    let dir = snapshot::dir();
+    let manifest = match Manifest::read(&dir)? {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    let shards = database::shards();
+    let reshard = manifest.shards.len() != shards;
+    database::set_loading(true);
+    let mut keys = 0;
+    if reshard {
+        info!(
+            "Append only file was written with {} shards, rehashing its keys onto {}",
+            manifest.shards.len(),
+            shards
+        );
+        let replayed = future::join_all(manifest.shards.iter().enumerate().map(|(i, name)| {
+            let path = dir.join(name);
+            database::with_shard_dbs(pool, i as u64, move |_| replay_aside(&path))
+        }))
+        .await;
+        for entries in replayed {
+            let entries = entries?;
+            keys += entries.len();
+            snapshot::distribute(pool, entries).await;
+        }
+    } else {
+        let replayed = future::join_all(manifest.shards.iter().enumerate().map(|(i, name)| {
+            let path = dir.join(name);
+            database::with_shard_dbs(pool, i as u64, move |dbs| replay_in_place(&path, dbs))
+        }))
+        .await;
+        for n in replayed {
+            keys += n?;
+        }
+    }
+    database::set_loading(false);
+    Ok(Some((keys, reshard)))
+}
+
+// begin starts the rewrite of the segments of dbs, the databases of a
+// shard.
+fn begin(dbs: &mut [Database]) {
+    for (i, db) in dbs.iter_mut().enumerate() {
+        db.begin_save(i, database::Format::Aof);
+    }
+}
+
+fn abort(dbs: &mut [Database]) {
+    for db in dbs.iter_mut() {
+        db.abort_save();
+    }
+    SEGMENT.with(|s| {
+        if let Some(segment) = s.borrow_mut().as_mut() {
+            segment.rewrite = None;
+        }
+    })
+}
+
+// step writes up to chunk keys of the rewrite in progress in dbs, the
+// databases of a shard, to w. It returns true once all of them are written.
+fn step(dbs: &mut [Database], w: &mut BufWriter<File>, chunk: usize) -> io::Result<bool> {
+    let (mut budget, mut finished) = (chunk, true);
+    let mut select = BytesMut::new();
+    for db in dbs.iter_mut() {
+        if let Some((n, records, done, complete)) = db.save_step(budget) {
+            budget -= done;
+            if !records.is_empty() {
+                put_select(&mut select, n);
+                w.write_all(&select)?;
+                select.clear();
+                w.write_all(&records)?;
+            }
+            finished &= complete;
+        }
+    }
+    Ok(finished)
+}
+
+// write_segment writes a segment at path holding the dataset of dbs, the
+// databases of a shard, in one go, and syncs it.
+fn write_segment(path: &Path, dbs: &mut [Database]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    begin(dbs);
+    if let Err(e) = step(dbs, &mut w, usize::MAX) {
+        abort(dbs);
+        return Err(e);
+    }
+    w.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+// start opens the segments every shard appends to from now on. If rewrite
+// is set, or there is no AOF yet, the segments of a new generation are
+// first written from the dataset, and replace the previous ones.
+pub async fn start(pool: &tokio_io_pool::Handle, rewrite: bool) -> io::Result<()> {
+    let dir = snapshot::dir();
+    let shards = database::shards();
+    let manifest = match Manifest::read(&dir)? {
+        Some(manifest) if !rewrite && manifest.shards.len() == shards => manifest,
+        previous => {
+            let generation = previous.as_ref().map_or(1, |m| m.generation + 1);
+            let names: Vec<String> = (0..shards).map(|i| segment_name(generation, i)).collect();
+            let written = future::join_all(names.iter().enumerate().map(|(i, name)| {
+                let path = dir.join(name);
+                database::with_shard_dbs(pool, i as u64, move |dbs| write_segment(&path, dbs))
+            }))
+            .await;
+            let manifest = Manifest { generation, shards: names };
+            if let Some(e) = written.into_iter().find_map(Result::err).or_else(|| manifest.write(&dir).err()) {
+                for name in manifest.shards.iter() {
+                    let _ = fs::remove_file(dir.join(name));
+                }
+                return Err(e);
+            }
+            if let Some(previous) = previous {
+                for name in previous.shards.iter() {
+                    let _ = fs::remove_file(dir.join(name));
+                }
+            }
+            manifest
+        }
+    };
+    let opened = future::join_all(manifest.shards.iter().enumerate().map(|(i, name)| {
+        let path = dir.join(name);
+        database::with_shard_dbs(pool, i as u64, move |_| -> io::Result<()> {
+            let segment = Segment::open(&path)?;
+            SEGMENT.with(|s| *s.borrow_mut() = Some(segment));
+            Ok(())
+        })
+    }))
+    .await;
+    opened.into_iter().collect::<io::Result<()>>()?;
+    *CURRENT.lock().unwrap() = Some(manifest);
+    for i in 0..shards {
+        let _ = pool.spawn_on(i as u64, cron());
+    }
+    Ok(())
+}
+
+// rewrite rewrites the segment of every shard, as BGREWRITEAOF does. The
+// shards write REWRITE_CHUNK keys at a time, serving requests in between.
+// The rewrite takes the snapshot in progress of the databases, the caller
+// must have called snapshot::start.
+pub async fn rewrite(pool: &tokio_io_pool::Handle) -> io::Result<()> {
+    let generation = match CURRENT.lock().unwrap().as_ref() {
+        Some(manifest) => manifest.generation + 1,
+        None => return Err(snapshot::invalid("append only file is not enabled")),
+    };
+    let rewritten = future::join_all((0..database::shards()).map(|i| rewrite_shard(pool, generation, i))).await;
+    rewritten.into_iter().collect()
+}
+
+// rewrite_shard rewrites the segment of shard into the segment of
+// generation.
+async fn rewrite_shard(pool: &tokio_io_pool::Handle, generation: u64, shard: usize) -> io::Result<()> {
+    let (dir, name) = (snapshot::dir(), segment_name(generation, shard));
+    let path = dir.join(&name);
+    let created = File::create(&path).map(BufWriter::new);
+    database::with_shard_dbs(pool, shard as u64, |dbs| {
+        begin(dbs);
+        SEGMENT.with(|s| {
+            if let Some(segment) = s.borrow_mut().as_mut() {
+                segment.rewrite = Some(Rewrite {
+                    buf: BytesMut::new(),
+                    db: None,
+                });
+            }
+        })
+    })
+    .await;
+    let rewritten = match created {
+        Ok(w) => write_rewrite(pool, w, dir, name, generation, shard).await,
+        Err(e) => Err(e),
+    };
+    if rewritten.is_err() {
+        database::with_shard_dbs(pool, shard as u64, abort).await;
+        let _ = fs::remove_file(&path);
+    }
+    rewritten
+}
+
+async fn write_rewrite(
+    pool: &tokio_io_pool::Handle,
+    mut w: BufWriter<File>,
+    dir: PathBuf,
+    name: String,
+    generation: u64,
+    shard: usize,
+) -> io::Result<()> {
+    loop {
+        let (returned, result) = database::with_shard_dbs(pool, shard as u64, move |dbs| {
+            let result = step(dbs, &mut w, REWRITE_CHUNK);
+            (w, result)
+        })
+        .await;
+        w = returned;
+        if result? {
+            break;
+        }
+    }
+    database::with_shard_dbs(pool, shard as u64, move |_| finish(&dir, w, &name, generation, shard)).await
+}
+
+// finish completes the rewrite of the segment of the current shard: the
+// commands logged since the rewrite started are appended to w, the new
+// segment named name, which then replaces the segment appended to. The
+// segment appended to is complete until then, so the manifest always lists
+// complete segments.
+fn finish(dir: &Path, mut w: BufWriter<File>, name: &str, generation: u64, shard: usize) -> io::Result<()> {
+    SEGMENT.with(|s| {
+        let mut s = s.borrow_mut();
+        let segment = s.as_mut().ok_or_else(|| snapshot::invalid("append only file is not enabled"))?;
+        let rewrite = segment.rewrite.take().ok_or_else(|| snapshot::invalid("no rewrite in progress"))?;
+        segment.write(false)?;
+        w.write_all(&rewrite.buf)?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        let mut replacement = Segment::open(&dir.join(name))?;
+        let previous = swap(dir, name, generation, shard)?;
+        replacement.deferred = std::mem::take(&mut segment.deferred);
+        *segment = replacement;
+        let _ = fs::remove_file(dir.join(previous));
+        Ok(())
+    })
+}
+
+// swap points the manifest at the segment name for shard, and returns the
+// name of the segment it replaces.
+fn swap(dir: &Path, name: &str, generation: u64, shard: usize) -> io::Result<String> {
+    let mut current = CURRENT.lock().unwrap();
+    let manifest = current.as_mut().ok_or_else(|| snapshot::invalid("append only file is not enabled"))?;
+    let previous = (manifest.generation, std::mem::replace(&mut manifest.shards[shard], name.to_string()));
+    manifest.generation = std::cmp::max(manifest.generation, generation);
+    if let Err(e) = manifest.write(dir) {
+        manifest.generation = previous.0;
+        manifest.shards[shard] = previous.1;
+        return Err(e);
+    }
+    Ok(previous.1)
+}
@@ -1,8 +1,9 @@
-use super::{resp, Args, Command, Database, Error, Execute};
+use super::{db_index, resp, Args, Command, Database, Error, Execute};
 use bytes::Bytes;
 
 pub struct Ping(pub Bytes);
 pub struct Echo(pub Bytes);
+pub struct Select(usize);
 
 impl Execute for Ping {
     fn parse(mut args: Args) -> Result<Self, Error> {
@@ -21,6 +22,10 @@ impl Execute for Ping {
         std::u64::MAX
     }
 
+    fn writes(&self) -> bool {
+        false
+    }
+
     fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
         /*
             TODO: If the client is subscribed to a channel or a pattern,
@@ -62,6 +67,10 @@ impl Execute for Echo {
         std::u64::MAX
     }
 
+    fn writes(&self) -> bool {
+        false
+    }
+
     fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
         Ok(resp::Msg::BulkString(Some(self.0.clone())))
     }
@@ -70,3 +79,36 @@ impl Execute for Echo {
         Command::Echo(self)
     }
 }
+
+impl Select {
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+// SELECT only changes the state of the connection, which handles it
+// itself, exec is never called.
+impl Execute for Select {
+    fn parse(args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'select' command"));
+        }
+        Ok(Select(db_index(&args, 1)?))
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Str("OK"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Select(self)
+    }
+}
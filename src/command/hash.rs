@@ -0,0 +1,468 @@
+use std::str;
+
+use bytes::Bytes;
+
+use super::{database::HashValue, database::Scalar, database::Value as DBValue, format_float, resp, shard_for, Args, Command, Database, Error, Execute};
+
+pub struct Hset(Bytes, Vec<(Bytes, Bytes)>, HsetOpt);
+pub enum HsetOpt {
+    None,
+    Multi, // HMSET, replies with OK
+    NX,
+}
+pub struct Hget(Bytes, Bytes);
+pub struct Hmget(Bytes, Vec<Bytes>);
+pub struct Hdel(Bytes, Vec<Bytes>);
+pub struct Hlen(Bytes);
+pub struct Hstrlen(Bytes, Bytes);
+pub struct Hexists(Bytes, Bytes);
+pub struct Hgetall(Bytes, bool, bool); // key, reply fields, reply values
+pub struct Hincrby(Bytes, Bytes, i64);
+pub struct Hincrbyfloat(Bytes, Bytes, f64);
+
+type Hash = HashValue;
+
+fn get_hash<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a mut Hash>, Error> {
+    match db.get_mut(key) {
+        Some(DBValue::HashMap(h)) => Ok(Some(h)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+// read_hash is like get_hash, for commands that don't modify the hash.
+fn read_hash<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a Hash>, Error> {
+    match db.get(key) {
+        Some(DBValue::HashMap(v)) => Ok(Some(v)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn get_or_create_hash<'a>(db: &'a mut Database, key: &Bytes) -> Result<&'a mut Hash, Error> {
+    if get_hash(db, key)?.is_none() {
+        db.insert(key.clone(), DBValue::HashMap(Hash::default()));
+    }
+    Ok(get_hash(db, key)?.unwrap())
+}
+
+// own_fields moves the string arguments from start onwards out of args.
+fn own_fields(args: &mut Args, start: usize, name: &'static str) -> Result<Vec<Bytes>, Error> {
+    let mut fields = Vec::with_capacity(args.len() - start);
+    for i in start..args.len() {
+        match args.own_bytes(i) {
+            Some(field) => fields.push(field),
+            None => return Err(Error::Error(format!("invalid parameter for '{}' command", name))),
+        }
+    }
+    Ok(fields)
+}
+
+impl Execute for Hset {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let opt = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"HSET" => HsetOpt::None,
+                b"HMSET" => HsetOpt::Multi,
+                b"HSETNX" => HsetOpt::NX,
+                _ => return Err(Error::Err("invalid name for 'hset' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'hset' command")),
+        };
+        let ok_len = match opt {
+            HsetOpt::NX => args.len() == 4,
+            _ => args.len() >= 4 && args.len().is_multiple_of(2),
+        };
+        if !ok_len {
+            return Err(Error::Err("wrong number of arguments for 'hset' command"));
+        }
+        let key = match args.own_bytes(1) {
+            Some(key) => key,
+            None => return Err(Error::Err("invalid parameter for 'hset' command")),
+        };
+        let mut pairs = Vec::with_capacity((args.len() - 2) / 2);
+        for i in (2..args.len()).step_by(2) {
+            match (args.own_bytes(i), args.own_bytes(i + 1)) {
+                (Some(field), Some(value)) => pairs.push((field, value)),
+                _ => return Err(Error::Err("invalid parameter for 'hset' command")),
+            }
+        }
+        Ok(Hset(key, pairs, opt))
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let hash = get_or_create_hash(db, &self.0)?;
+        let mut added = 0;
+        for (field, value) in self.1.iter() {
+            if let HsetOpt::NX = self.2 {
+                if hash.contains_key(field) {
+                    continue;
+                }
+            }
+            if hash.insert(field.clone(), Scalar::String(value.clone())).is_none() {
+                added += 1;
+            }
+        }
+        match self.2 {
+            HsetOpt::Multi => Ok(resp::Msg::Str("OK")),
+            _ => Ok(resp::Msg::Int(added)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hset(self)
+    }
+}
+
+impl Execute for Hget {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'hget' command"));
+        }
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(field)) => Ok(Hget(key, field)),
+            _ => Err(Error::Err("invalid parameter for 'hget' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        match read_hash(db, &self.0)? {
+            Some(hash) => Ok(resp::Msg::BulkString(hash.get(&self.1).map(|v| v.to_bytes()))),
+            None => Ok(resp::Msg::BulkString(None)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hget(self)
+    }
+}
+
+impl Execute for Hmget {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 3 {
+            return Err(Error::Err("wrong number of arguments for 'hmget' command"));
+        }
+        let fields = own_fields(&mut args, 2, "hmget")?;
+        match args.own_bytes(1) {
+            Some(key) => Ok(Hmget(key, fields)),
+            None => Err(Error::Err("invalid parameter for 'hmget' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let hash = read_hash(db, &self.0)?;
+        Ok(resp::Msg::Array(Some(
+            self.1
+                .iter()
+                .map(|field| {
+                    resp::Msg::BulkString(hash.as_ref().and_then(|h| h.get(field)).map(|v| v.to_bytes()))
+                })
+                .collect(),
+        )))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hmget(self)
+    }
+}
+
+impl Execute for Hdel {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 3 {
+            return Err(Error::Err("wrong number of arguments for 'hdel' command"));
+        }
+        let fields = own_fields(&mut args, 2, "hdel")?;
+        match args.own_bytes(1) {
+            Some(key) => Ok(Hdel(key, fields)),
+            None => Err(Error::Err("invalid parameter for 'hdel' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let (removed, empty) = match get_hash(db, &self.0)? {
+            Some(hash) => {
+                let removed = self.1.iter().filter(|f| hash.remove(f).is_some()).count();
+                (removed, hash.is_empty())
+            }
+            None => return Ok(resp::Msg::Int(0)),
+        };
+        if empty {
+            db.remove(&self.0);
+        }
+        Ok(resp::Msg::Int(removed as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hdel(self)
+    }
+}
+
+impl Execute for Hlen {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'hlen' command"));
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Hlen(key)),
+            None => Err(Error::Err("invalid parameter for 'hlen' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        match read_hash(db, &self.0)? {
+            Some(hash) => Ok(resp::Msg::Int(hash.len() as i64)),
+            None => Ok(resp::Msg::Int(0)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hlen(self)
+    }
+}
+
+impl Execute for Hstrlen {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'hstrlen' command"));
+        }
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(field)) => Ok(Hstrlen(key, field)),
+            _ => Err(Error::Err("invalid parameter for 'hstrlen' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let len = match read_hash(db, &self.0)?.and_then(|h| h.get(&self.1)) {
+            Some(Scalar::String(s)) => s.len(),
+            Some(Scalar::Integer(i)) => i.to_string().len(),
+            None => 0,
+        };
+        Ok(resp::Msg::Int(len as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hstrlen(self)
+    }
+}
+
+impl Execute for Hexists {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'hexists' command"));
+        }
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(field)) => Ok(Hexists(key, field)),
+            _ => Err(Error::Err("invalid parameter for 'hexists' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        match read_hash(db, &self.0)? {
+            Some(hash) if hash.contains_key(&self.1) => Ok(resp::Msg::Int(1)),
+            _ => Ok(resp::Msg::Int(0)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hexists(self)
+    }
+}
+
+impl Execute for Hgetall {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'hgetall' command"));
+        }
+        let key = match args.own_bytes(1) {
+            Some(key) => key,
+            None => return Err(Error::Err("invalid parameter for 'hgetall' command")),
+        };
+        match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"HKEYS" => Ok(Hgetall(key, true, false)),
+                b"HVALS" => Ok(Hgetall(key, false, true)),
+                _ => Ok(Hgetall(key, true, true)),
+            },
+            None => Err(Error::Err("invalid parameter for 'hgetall' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let hash = match read_hash(db, &self.0)? {
+            Some(hash) => hash,
+            None => return Ok(resp::Msg::Array(Some(Vec::new()))),
+        };
+        let mut reply = Vec::with_capacity(hash.len() * (self.1 as usize + self.2 as usize));
+        for (field, value) in hash.iter() {
+            if self.1 {
+                reply.push(resp::Msg::BulkString(Some(field.clone())));
+            }
+            if self.2 {
+                reply.push(resp::Msg::BulkString(Some(value.to_bytes())));
+            }
+        }
+        Ok(resp::Msg::Array(Some(reply)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hgetall(self)
+    }
+}
+
+impl Execute for Hincrby {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'hincrby' command"));
+        }
+        let n = match args.int(3) {
+            Some(n) => n,
+            None => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(field)) => Ok(Hincrby(key, field, n)),
+            _ => Err(Error::Err("invalid parameter for 'hincrby' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let hash = get_or_create_hash(db, &self.0)?;
+        let current = match hash.get(&self.1) {
+            Some(Scalar::Integer(i)) => *i,
+            Some(Scalar::String(s)) => match str::from_utf8(s.as_ref()).ok().and_then(|s| s.parse::<i64>().ok()) {
+                Some(i) => i,
+                None => return Err(Error::Err("hash value is not an integer")),
+            },
+            None => 0,
+        };
+        match current.checked_add(self.2) {
+            Some(new_val) => {
+                hash.insert(self.1.clone(), Scalar::Integer(new_val));
+                Ok(resp::Msg::Int(new_val))
+            }
+            None => Err(Error::Err("increment or decrement would overflow")),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hincrby(self)
+    }
+}
+
+impl Execute for Hincrbyfloat {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'hincrbyfloat' command"));
+        }
+        let n = match args.own_bytes(3).and_then(|b| str::from_utf8(b.as_ref()).ok().and_then(|s| s.parse::<f64>().ok())) {
+            Some(n) if n.is_finite() => n,
+            _ => return Err(Error::Err("value is not a valid float")),
+        };
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(field)) => Ok(Hincrbyfloat(key, field, n)),
+            _ => Err(Error::Err("invalid parameter for 'hincrbyfloat' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let hash = get_or_create_hash(db, &self.0)?;
+        let current = match hash.get(&self.1) {
+            Some(Scalar::Integer(i)) => *i as f64,
+            Some(Scalar::String(s)) => match str::from_utf8(s.as_ref()).ok().and_then(|s| s.parse::<f64>().ok()) {
+                Some(f) => f,
+                None => return Err(Error::Err("hash value is not a float")),
+            },
+            None => 0.0,
+        };
+        let new_val = current + self.2;
+        if !new_val.is_finite() {
+            return Err(Error::Err("increment would produce NaN or Infinity"));
+        }
+        let formatted = format_float(new_val);
+        hash.insert(self.1.clone(), Scalar::String(formatted.clone()));
+        Ok(resp::Msg::BulkString(Some(formatted)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Hincrbyfloat(self)
+    }
+}
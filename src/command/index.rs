@@ -1,7 +1,7 @@
-use phf::phf_map;
+use phf::{phf_map, phf_set};
 
-use super::{connection, keys, string};
-use super::{Args, Error, Execute, Quit, Unimplemented};
+use super::{connection, hash, keys, list, scan, server, set, string, zset};
+use super::{Args, Coordinate, Error, Execute, Quit, Scatter, Unimplemented};
 
 pub enum Command {
     Unimplemented(Unimplemented),
@@ -17,9 +17,75 @@ pub enum Command {
     GetRange(string::GetRange),
     SetRange(string::SetRange),
     Incr(string::Incr),
+    Mget(string::Mget),
+    Mset(string::Mset),
+    Msetnx(string::Msetnx),
     Keys(keys::Keys),
+    Exists(keys::Exists),
+    Expire(keys::Expire),
+    Ttl(keys::Ttl),
+    Persist(keys::Persist),
+    Rename(keys::Rename),
+    Move(keys::Move),
+    Object(keys::Object),
+    Dump(keys::Dump),
+    Restore(keys::Restore),
+    Push(list::Push),
+    Pop(list::Pop),
+    Lrange(list::Lrange),
+    Llen(list::Llen),
+    Lindex(list::Lindex),
+    Lset(list::Lset),
+    Ltrim(list::Ltrim),
+    Lrem(list::Lrem),
+    Linsert(list::Linsert),
+    Bpop(list::Bpop),
+    Rpoplpush(list::Rpoplpush),
+    Hset(hash::Hset),
+    Hget(hash::Hget),
+    Hmget(hash::Hmget),
+    Hdel(hash::Hdel),
+    Hlen(hash::Hlen),
+    Hstrlen(hash::Hstrlen),
+    Hexists(hash::Hexists),
+    Hgetall(hash::Hgetall),
+    Hincrby(hash::Hincrby),
+    Hincrbyfloat(hash::Hincrbyfloat),
+    Sadd(set::Sadd),
+    Srem(set::Srem),
+    Sismember(set::Sismember),
+    Scard(set::Scard),
+    Spop(set::Spop),
+    Srandmember(set::Srandmember),
+    Smembers(set::Smembers),
+    Smove(set::Smove),
+    Setop(set::Setop),
+    Zadd(zset::Zadd),
+    Zrange(zset::Zrange),
+    Zrank(zset::Zrank),
+    Zscore(zset::Zscore),
+    Zrem(zset::Zrem),
+    Zcard(zset::Zcard),
+    Zcount(zset::Zcount),
+    Zrangeby(zset::Zrangeby),
+    Zremrange(zset::Zremrange),
+    Zpop(zset::Zpop),
+    Zstore(zset::Zstore),
+    Scan(scan::Scan),
+    Cscan(scan::Cscan),
+    Type(keys::Type),
+    Randomkey(keys::Randomkey),
+    Dbsize(server::Dbsize),
+    Flush(server::Flush),
+    Swapdb(server::Swapdb),
+    MemoryUsage(server::MemoryUsage),
+    MemoryStats(server::MemoryStats),
+    Save(server::Save),
+    Lastsave(server::Lastsave),
+    Rewriteaof(server::Rewriteaof),
     Ping(connection::Ping),
     Echo(connection::Echo),
+    Select(connection::Select),
 }
 
 impl Command {
@@ -38,9 +104,108 @@ impl Command {
             Command::GetRange(s) => s,
             Command::SetRange(s) => s,
             Command::Incr(s) => s,
+            Command::Mget(s) => s,
+            Command::Mset(s) => s,
+            Command::Msetnx(s) => s,
             Command::Keys(s) => s,
+            Command::Exists(s) => s,
+            Command::Expire(s) => s,
+            Command::Ttl(s) => s,
+            Command::Persist(s) => s,
+            Command::Rename(s) => s,
+            Command::Move(s) => s,
+            Command::Object(s) => s,
+            Command::Dump(s) => s,
+            Command::Restore(s) => s,
+            Command::Push(s) => s,
+            Command::Pop(s) => s,
+            Command::Lrange(s) => s,
+            Command::Llen(s) => s,
+            Command::Lindex(s) => s,
+            Command::Lset(s) => s,
+            Command::Ltrim(s) => s,
+            Command::Lrem(s) => s,
+            Command::Linsert(s) => s,
+            Command::Bpop(s) => s,
+            Command::Rpoplpush(s) => s,
+            Command::Hset(s) => s,
+            Command::Hget(s) => s,
+            Command::Hmget(s) => s,
+            Command::Hdel(s) => s,
+            Command::Hlen(s) => s,
+            Command::Hstrlen(s) => s,
+            Command::Hexists(s) => s,
+            Command::Hgetall(s) => s,
+            Command::Hincrby(s) => s,
+            Command::Hincrbyfloat(s) => s,
+            Command::Sadd(s) => s,
+            Command::Srem(s) => s,
+            Command::Sismember(s) => s,
+            Command::Scard(s) => s,
+            Command::Spop(s) => s,
+            Command::Srandmember(s) => s,
+            Command::Smembers(s) => s,
+            Command::Smove(s) => s,
+            Command::Setop(s) => s,
+            Command::Zadd(s) => s,
+            Command::Zrange(s) => s,
+            Command::Zrank(s) => s,
+            Command::Zscore(s) => s,
+            Command::Zrem(s) => s,
+            Command::Zcard(s) => s,
+            Command::Zcount(s) => s,
+            Command::Zrangeby(s) => s,
+            Command::Zremrange(s) => s,
+            Command::Zpop(s) => s,
+            Command::Zstore(s) => s,
+            Command::Scan(s) => s,
+            Command::Cscan(s) => s,
+            Command::Type(s) => s,
+            Command::Randomkey(s) => s,
+            Command::Dbsize(s) => s,
+            Command::Flush(s) => s,
+            Command::Swapdb(s) => s,
+            Command::MemoryUsage(s) => s,
+            Command::MemoryStats(s) => s,
+            Command::Save(s) => s,
+            Command::Lastsave(s) => s,
+            Command::Rewriteaof(s) => s,
             Command::Ping(s) => s,
             Command::Echo(s) => s,
+            Command::Select(s) => s,
+        }
+    }
+
+    pub fn to_coordinate(&self) -> Option<&dyn Coordinate> {
+        match self {
+            Command::Keys(s) => Some(s),
+            Command::Scan(s) => Some(s),
+            Command::Randomkey(s) => Some(s),
+            Command::Dbsize(s) => Some(s),
+            Command::Flush(s) => Some(s),
+            Command::Rename(s) => Some(s),
+            Command::Move(s) => Some(s),
+            Command::Swapdb(s) => Some(s),
+            Command::MemoryStats(s) => Some(s),
+            Command::Save(s) => Some(s),
+            Command::Rewriteaof(s) => Some(s),
+            Command::Bpop(s) => Some(s),
+            Command::Rpoplpush(s) => Some(s),
+            Command::Smove(s) => Some(s),
+            Command::Setop(s) => Some(s),
+            Command::Zstore(s) => Some(s),
+            Command::Msetnx(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn to_scatter(&self) -> Option<&dyn Scatter> {
+        match self {
+            Command::Mget(s) => Some(s),
+            Command::Mset(s) => Some(s),
+            Command::Del(s) => Some(s),
+            Command::Exists(s) => Some(s),
+            _ => None,
         }
     }
 }
@@ -58,7 +223,7 @@ pub static COMMANDS: phf::Map<&'static [u8], NewCmd> = phf_map! {
     b"STRLEN" => string::Strlen::new,
     b"DEL" => keys::Del::new,
     b"UNLINK" => keys::Del::new,
-    b"EXISTS" => Unimplemented::new,
+    b"EXISTS" => keys::Exists::new,
     b"SETBIT" => string::Setbit::new,
     b"GETBIT" => string::Getbit::new,
     b"BITFIELD" => string::Bitfield::new,
@@ -67,126 +232,128 @@ pub static COMMANDS: phf::Map<&'static [u8], NewCmd> = phf_map! {
     b"SUBSTR" => string::GetRange::new,
     b"INCR" => string::Incr::new,
     b"DECR" => string::Incr::new,
-    b"MGET" => Unimplemented::new,
-    b"RPUSH" => Unimplemented::new,
-    b"LPUSH" => Unimplemented::new,
-    b"RPUSHX" => Unimplemented::new,
-    b"LPUSHX" => Unimplemented::new,
-    b"LINSERT9" => Unimplemented::new,
-    b"RPOP" => Unimplemented::new,
-    b"LPOP" => Unimplemented::new,
-    b"BRPOP" => Unimplemented::new,
-    b"BRPOPLPUSH" => Unimplemented::new,
-    b"BLPOP" => Unimplemented::new,
-    b"LLEN" => Unimplemented::new,
-    b"LINDEX" => Unimplemented::new,
-    b"LSET" => Unimplemented::new,
-    b"LRANGE" => Unimplemented::new,
-    b"LTRIM" => Unimplemented::new,
-    b"LREM" => Unimplemented::new,
-    b"RPOPLPUSH" => Unimplemented::new,
-    b"SADD" => Unimplemented::new,
-    b"SREM" => Unimplemented::new,
-    b"SMOVE" => Unimplemented::new,
-    b"SISMEMBER" => Unimplemented::new,
-    b"SCARD" => Unimplemented::new,
-    b"SPOP" => Unimplemented::new,
-    b"SRANDMEMBER" => Unimplemented::new,
-    b"SINTER" => Unimplemented::new,
-    b"SINTERSTORE" => Unimplemented::new,
-    b"SUNION" => Unimplemented::new,
-    b"SUNIONSTORE" => Unimplemented::new,
-    b"SDIFF" => Unimplemented::new,
-    b"SDIFFSTORE" => Unimplemented::new,
-    b"SMEMBERS" => Unimplemented::new,
-    b"SSCAN" => Unimplemented::new,
-    b"ZADD" => Unimplemented::new,
-    b"ZINCRBY" => Unimplemented::new,
-    b"ZREM" => Unimplemented::new,
-    b"ZREMRANGEBYSCORE" => Unimplemented::new,
-    b"ZREMRANGEBYRANK" => Unimplemented::new,
-    b"ZREMRANGEBYLEX" => Unimplemented::new,
-    b"ZUNIONSTORE" => Unimplemented::new,
-    b"ZINTERSTORE" => Unimplemented::new,
-    b"ZRANGE" => Unimplemented::new,
-    b"ZRANGEBYSCORE" => Unimplemented::new,
-    b"ZREVRANGEBYSCORE" => Unimplemented::new,
-    b"ZRANGEBYLEX" => Unimplemented::new,
-    b"ZREVRANGEBYLEX" => Unimplemented::new,
-    b"ZCOUNT" => Unimplemented::new,
-    b"ZLEXCOUNT" => Unimplemented::new,
-    b"ZREVRANGE" => Unimplemented::new,
-    b"ZCARD" => Unimplemented::new,
-    b"ZSCORE" => Unimplemented::new,
-    b"ZRANK" => Unimplemented::new,
-    b"ZREVRANK" => Unimplemented::new,
-    b"ZSCAN" => Unimplemented::new,
-    b"ZPOPMIN" => Unimplemented::new,
-    b"ZPOPMAX" => Unimplemented::new,
+    b"MGET" => string::Mget::new,
+    b"RPUSH" => list::Push::new,
+    b"LPUSH" => list::Push::new,
+    b"RPUSHX" => list::Push::new,
+    b"LPUSHX" => list::Push::new,
+    b"LINSERT" => list::Linsert::new,
+    b"RPOP" => list::Pop::new,
+    b"LPOP" => list::Pop::new,
+    b"BRPOP" => list::Bpop::new,
+    b"BRPOPLPUSH" => list::Rpoplpush::new,
+    b"BLPOP" => list::Bpop::new,
+    b"LLEN" => list::Llen::new,
+    b"LINDEX" => list::Lindex::new,
+    b"LSET" => list::Lset::new,
+    b"LRANGE" => list::Lrange::new,
+    b"LTRIM" => list::Ltrim::new,
+    b"LREM" => list::Lrem::new,
+    b"RPOPLPUSH" => list::Rpoplpush::new,
+    b"SADD" => set::Sadd::new,
+    b"SREM" => set::Srem::new,
+    b"SMOVE" => set::Smove::new,
+    b"SISMEMBER" => set::Sismember::new,
+    b"SCARD" => set::Scard::new,
+    b"SPOP" => set::Spop::new,
+    b"SRANDMEMBER" => set::Srandmember::new,
+    b"SINTER" => set::Setop::new,
+    b"SINTERSTORE" => set::Setop::new,
+    b"SUNION" => set::Setop::new,
+    b"SUNIONSTORE" => set::Setop::new,
+    b"SDIFF" => set::Setop::new,
+    b"SDIFFSTORE" => set::Setop::new,
+    b"SMEMBERS" => set::Smembers::new,
+    b"SSCAN" => scan::Cscan::new,
+    b"ZADD" => zset::Zadd::new,
+    b"ZINCRBY" => zset::Zadd::new,
+    b"ZREM" => zset::Zrem::new,
+    b"ZREMRANGEBYSCORE" => zset::Zremrange::new,
+    b"ZREMRANGEBYRANK" => zset::Zremrange::new,
+    b"ZREMRANGEBYLEX" => zset::Zremrange::new,
+    b"ZUNIONSTORE" => zset::Zstore::new,
+    b"ZINTERSTORE" => zset::Zstore::new,
+    b"ZRANGE" => zset::Zrange::new,
+    b"ZRANGEBYSCORE" => zset::Zrangeby::new,
+    b"ZREVRANGEBYSCORE" => zset::Zrangeby::new,
+    b"ZRANGEBYLEX" => zset::Zrangeby::new,
+    b"ZREVRANGEBYLEX" => zset::Zrangeby::new,
+    b"ZCOUNT" => zset::Zcount::new,
+    b"ZLEXCOUNT" => zset::Zcount::new,
+    b"ZREVRANGE" => zset::Zrange::new,
+    b"ZCARD" => zset::Zcard::new,
+    b"ZSCORE" => zset::Zscore::new,
+    b"ZRANK" => zset::Zrank::new,
+    b"ZREVRANK" => zset::Zrank::new,
+    b"ZSCAN" => scan::Cscan::new,
+    b"ZPOPMIN" => zset::Zpop::new,
+    b"ZPOPMAX" => zset::Zpop::new,
     b"BZPOPMIN" => Unimplemented::new,
     b"BZPOPMAX" => Unimplemented::new,
-    b"HSET" => Unimplemented::new,
-    b"HSETNX" => Unimplemented::new,
-    b"HGET" => Unimplemented::new,
-    b"HMSET" => Unimplemented::new,
-    b"HMGET" => Unimplemented::new,
-    b"HINCRBY" => Unimplemented::new,
-    b"HINCRBYFLOAT" => Unimplemented::new,
-    b"HDEL" => Unimplemented::new,
-    b"HLEN" => Unimplemented::new,
-    b"HSTRLEN" => Unimplemented::new,
-    b"HKEYS" => Unimplemented::new,
-    b"HVALS" => Unimplemented::new,
-    b"HGETALL" => Unimplemented::new,
-    b"HEXISTS" => Unimplemented::new,
-    b"HSCAN" => Unimplemented::new,
+    b"HSET" => hash::Hset::new,
+    b"HSETNX" => hash::Hset::new,
+    b"HGET" => hash::Hget::new,
+    b"HMSET" => hash::Hset::new,
+    b"HMGET" => hash::Hmget::new,
+    b"HINCRBY" => hash::Hincrby::new,
+    b"HINCRBYFLOAT" => hash::Hincrbyfloat::new,
+    b"HDEL" => hash::Hdel::new,
+    b"HLEN" => hash::Hlen::new,
+    b"HSTRLEN" => hash::Hstrlen::new,
+    b"HKEYS" => hash::Hgetall::new,
+    b"HVALS" => hash::Hgetall::new,
+    b"HGETALL" => hash::Hgetall::new,
+    b"HEXISTS" => hash::Hexists::new,
+    b"HSCAN" => scan::Cscan::new,
     b"INCRBY" => string::Incr::new,
     b"DECRBY" => string::Incr::new,
     b"INCRBYFLOAT" => Unimplemented::new,
     b"GETSET" => Unimplemented::new,
-    b"MSET" => Unimplemented::new,
-    b"MSETNX" => Unimplemented::new,
-    b"RANDOMKEY" => Unimplemented::new,
-    b"SELECT" => Unimplemented::new,
-    b"SWAPDB" => Unimplemented::new,
-    b"MOVE" => Unimplemented::new,
-    b"RENAME" => Unimplemented::new,
-    b"RENAMENX" => Unimplemented::new,
-    b"EXPIRE" => Unimplemented::new,
-    b"EXPIREAT" => Unimplemented::new,
-    b"PEXPIRE" => Unimplemented::new,
-    b"PEXPIREAT" => Unimplemented::new,
+    b"MSET" => string::Mset::new,
+    b"MSETNX" => string::Msetnx::new,
+    b"RANDOMKEY" => keys::Randomkey::new,
+    b"SELECT" => connection::Select::new,
+    b"SWAPDB" => server::Swapdb::new,
+    b"MOVE" => keys::Move::new,
+    b"RENAME" => keys::Rename::new,
+    b"RENAMENX" => keys::Rename::new,
+    b"OBJECT" => keys::Object::new,
+    b"EXPIRE" => keys::Expire::new,
+    b"EXPIREAT" => keys::Expire::new,
+    b"PEXPIRE" => keys::Expire::new,
+    b"PEXPIREAT" => keys::Expire::new,
     b"KEYS" => keys::Keys::new,
-    b"SCAN" => Unimplemented::new,
-    b"DBSIZE" => Unimplemented::new,
+    b"SCAN" => scan::Scan::new,
+    b"DBSIZE" => server::Dbsize::new,
     b"AUTH" => Unimplemented::new,
     b"PING" => connection::Ping::new,
     b"ECHO" => connection::Echo::new,
-    b"SAVE" => Unimplemented::new,
-    b"BGSAVE" => Unimplemented::new,
-    b"BGREWRITEAOF" => Unimplemented::new,
+    b"SAVE" => server::Save::new,
+    b"BGSAVE" => server::Save::new,
+    b"BGREWRITEAOF" => server::Rewriteaof::new,
     b"SHUTDOWN" => Unimplemented::new,
-    b"LASTSAVE" => Unimplemented::new,
-    b"TYPE" => Unimplemented::new,
+    b"LASTSAVE" => server::Lastsave::new,
+    b"TYPE" => keys::Type::new,
     b"MULTI" => Unimplemented::new,
     b"EXEC" => Unimplemented::new,
     b"DISCARD" => Unimplemented::new,
     b"SYNC" => Unimplemented::new,
     b"PSYNC" => Unimplemented::new,
     b"REPLCONF" => Unimplemented::new,
-    b"FLUSHDB" => Unimplemented::new,
-    b"FLUSHALL" => Unimplemented::new,
+    b"FLUSHDB" => server::Flush::new,
+    b"FLUSHALL" => server::Flush::new,
+    b"MEMORY" => server::memory,
     b"SORT" => Unimplemented::new,
     b"INFO" => Unimplemented::new,
     b"MONITOR" => Unimplemented::new,
-    b"TTL" => Unimplemented::new,
-    b"TOUCH" => Unimplemented::new,
-    b"PTTL" => Unimplemented::new,
-    b"PERSIST" => Unimplemented::new,
+    b"TTL" => keys::Ttl::new,
+    b"TOUCH" => keys::Exists::new,
+    b"PTTL" => keys::Ttl::new,
+    b"PERSIST" => keys::Persist::new,
     b"SLAVEOF" => Unimplemented::new,
     b"REPLICAOF" => Unimplemented::new,
     b"ROLE" => Unimplemented::new,
-    b"DEBUG" => Unimplemented::new,
+    b"DEBUG" => keys::Keys::new,
     b"CONFIG" => Unimplemented::new,
     b"SUBSCRIBE" => Unimplemented::new,
     b"UNSUBSCRIBE" => Unimplemented::new,
@@ -197,15 +364,13 @@ pub static COMMANDS: phf::Map<&'static [u8], NewCmd> = phf_map! {
     b"WATCH" => Unimplemented::new,
     b"UNWATCH" => Unimplemented::new,
     b"CLUSTER" => Unimplemented::new,
-    b"RESTORE" => Unimplemented::new,
-    b"RESTORE-ASKING" => Unimplemented::new,
+    b"RESTORE" => keys::Restore::new,
+    b"RESTORE-ASKING" => keys::Restore::new,
     b"MIGRATE" => Unimplemented::new,
     b"ASKING" => Unimplemented::new,
     b"READONLY" => Unimplemented::new,
     b"READWRITE" => Unimplemented::new,
-    b"DUMP" => Unimplemented::new,
-    b"OBJECT" => Unimplemented::new,
-    b"MEMORY" => Unimplemented::new,
+    b"DUMP" => keys::Dump::new,
     b"CLIENT" => Unimplemented::new,
     b"EVAL" => Unimplemented::new,
     b"EVALSHA" => Unimplemented::new,
@@ -248,3 +413,46 @@ pub static COMMANDS: phf::Map<&'static [u8], NewCmd> = phf_map! {
     b"LATENCY" => Unimplemented::new,
     b"QUIT" => Quit::new,
 };
+
+// DENYOOM lists the commands that may grow the dataset, they are refused
+// once maxmemory is reached and no key can be evicted.
+pub static DENYOOM: phf::Set<&'static [u8]> = phf_set! {
+    b"SET",
+    b"SETNX",
+    b"SETEX",
+    b"PSETEX",
+    b"APPEND",
+    b"SETBIT",
+    b"BITFIELD",
+    b"SETRANGE",
+    b"INCR",
+    b"DECR",
+    b"INCRBY",
+    b"DECRBY",
+    b"MSET",
+    b"MSETNX",
+    b"RPUSH",
+    b"LPUSH",
+    b"RPUSHX",
+    b"LPUSHX",
+    b"LINSERT",
+    b"LSET",
+    b"RPOPLPUSH",
+    b"BRPOPLPUSH",
+    b"RESTORE",
+    b"RESTORE-ASKING",
+    b"SADD",
+    b"SMOVE",
+    b"SINTERSTORE",
+    b"SUNIONSTORE",
+    b"SDIFFSTORE",
+    b"ZADD",
+    b"ZINCRBY",
+    b"ZUNIONSTORE",
+    b"ZINTERSTORE",
+    b"HSET",
+    b"HSETNX",
+    b"HMSET",
+    b"HINCRBY",
+    b"HINCRBYFLOAT",
+};
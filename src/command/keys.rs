@@ -1,49 +1,72 @@
-use std::str;
 use bytes::{Bytes};
-use seahash::SeaHasher;
-use std::hash::{Hash, Hasher};
+use rand::Rng;
 
-use super::{resp, Args, Command, Database, Error, Execute};
+use super::{aof, database, database::Value as DBValue, db_index, dump, glob, one_worker, resp, shard_for, Args, Command, Coordinate, Database, Error, Execute, Reply, Scatter};
 
-pub struct Del(bool, Bytes, pub Vec<Bytes>);
-pub struct Keys(pub i64);
+pub struct Del(bool, Vec<Bytes>); // unlink, keys
+pub struct Exists(bool, Vec<Bytes>); // touch, keys
+pub struct Type(Bytes);
+pub struct Randomkey;
+pub struct Keys(Bytes, Option<u64>); // pattern, shard (DEBUG KEYS)
+pub struct Expire(Bytes, i64, bool); // key, milliseconds, absolute
+pub struct Ttl(Bytes, bool); // key, milliseconds
+pub struct Persist(Bytes);
+pub struct Rename(Bytes, Bytes, bool); // key, newkey, nx
+pub struct Move(Bytes, usize); // key, db
+pub struct Object(ObjectField, Bytes); // field, key
+pub struct Dump(Bytes);
+pub struct Restore(Bytes, i64, Bytes, bool, bool); // key, ttl, payload, replace, absttl
+
+pub enum ObjectField {
+    Encoding,
+    Idletime,
+    Freq,
+    Refcount,
+}
 
 impl Execute for Keys {
     fn parse(mut args: Args) -> Result<Self, Error> {
-        if args.len() == 1 {
-            Ok(Keys(-1))
-        } else {
-            match args.own(1) {
-                resp::Msg::BulkString(Some(key)) | resp::Msg::String(key) => {
-                    match str::from_utf8(key.as_ref()) {
-                        Ok(k) => match k.parse::<i64>() {
-                            Ok(n) => Ok(Keys(n)),
-                            Err(_) => Err(Error::Err("invalid parameter for 'keys' command")),
-                        },
-                        Err(_) => Err(Error::Err("invalid parameter for 'keys' command")),
-                    }
+        match args.own_bytes(0) {
+            Some(ref name) if name.as_ref() == b"KEYS" => {
+                if args.len() != 2 {
+                    return Err(Error::Err("wrong number of arguments for 'keys' command"));
+                }
+                match args.own_bytes(1) {
+                    Some(pattern) => Ok(Keys(pattern, None)),
+                    None => Err(Error::Err("invalid parameter for 'keys' command")),
+                }
+            }
+            // DEBUG KEYS <shard> lists the keys of a single shard
+            Some(ref name) if name.as_ref() == b"DEBUG" => {
+                let is_keys = match args.get(1) {
+                    Some(resp::Msg::String(b)) | Some(resp::Msg::BulkString(Some(b))) => b.eq_ignore_ascii_case(b"KEYS"),
+                    _ => false,
+                };
+                if !is_keys {
+                    return Err(Error::Err("unknown subcommand for 'debug' command"));
+                }
+                if args.len() != 3 {
+                    return Err(Error::Err("wrong number of arguments for 'debug keys' command"));
+                }
+                match args.int(2) {
+                    Some(n) if n >= 0 && (n as usize) < database::shards() => Ok(Keys(Bytes::from_static(b"*"), Some(n as u64))),
+                    _ => Err(Error::Err("invalid shard for 'debug keys' command")),
                 }
-                resp::Msg::Int(n) => Ok(Keys(n)),
-                _ => Err(Error::Err("invalid parameter for 'keys' command")),
             }
+            _ => Err(Error::Err("invalid parameter for 'keys' command")),
         }
     }
 
     fn shard(&self) -> u64 {
-        match self.0 {
-            -1 => 0, // TODO, -1 will be all keys
-            0..=std::i64::MAX => self.0 as u64,
-            // parse should reject commands with invalid shards
-            _ => panic!(),
-        }
+        self.1.unwrap_or(u64::MAX)
+    }
+
+    fn writes(&self) -> bool {
+        false
     }
 
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
-        Ok(resp::Msg::Array(Some(
-            db.keys()
-                .map(|key| resp::Msg::BulkString(Some(key.clone())))
-                .collect(),
-        )))
+        Ok(resp::Msg::Array(Some(matching_keys(db, &self.0))))
     }
 
     fn to_command(self) -> Command {
@@ -51,54 +74,751 @@ impl Execute for Keys {
     }
 }
 
+impl Coordinate for Keys {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let pattern = self.0.clone();
+        let shard = self.1;
+        Box::pin(async move {
+            let keys = match shard {
+                Some(shard) => vec![database::with_unlocked(&pool, shard, db, vec![shard], move |db| matching_keys(db, &pattern)).await],
+                None => {
+                    let _ticket = database::quiesce().await;
+                    database::with_every_shard(&pool, db, move |db| matching_keys(db, &pattern)).await
+                }
+            };
+            Ok(resp::Msg::Array(Some(keys.into_iter().flatten().collect())))
+        })
+    }
+}
+
+fn matching_keys(db: &mut Database, pattern: &[u8]) -> Vec<resp::Msg> {
+    let all = pattern == b"*";
+    db.keys()
+        .filter(|key| all || glob::matches(pattern, key))
+        .map(|key| resp::Msg::BulkString(Some(key.clone())))
+        .collect()
+}
+
+// del removes keys from the current shard. Unlinked values are freed in
+// the background when they are large.
+fn del(db: &mut Database, keys: &[Bytes], unlink: bool) -> i64 {
+    let mut removed = 0;
+    for key in keys.iter() {
+        if let Some(value) = db.remove(key) {
+            if unlink {
+                database::lazy_free(value);
+            }
+            removed += 1;
+        }
+    }
+    if removed > 0 && !unlink {
+        db.shrink_to_fit();
+    }
+    removed
+}
+
+// exists counts the keys that exist. TOUCH looks them up like a read does,
+// which updates their access time and frequency.
+fn exists(db: &mut Database, keys: &[Bytes], touch: bool) -> i64 {
+    if touch {
+        keys.iter().filter(|key| db.get(key).is_some()).count() as i64
+    } else {
+        keys.iter().filter(|key| db.contains_key(key)).count() as i64
+    }
+}
+
+// count sums the counts replied by the parts of a scattered command.
+fn count(replies: Vec<(Vec<usize>, Result<resp::Msg, Error>)>) -> Result<resp::Msg, Error> {
+    let mut n = 0;
+    for (_, reply) in replies.into_iter() {
+        if let resp::Msg::Int(part) = reply? {
+            n += part;
+        }
+    }
+    Ok(resp::Msg::Int(n))
+}
+
 impl Execute for Del {
     fn parse(mut args: Args) -> Result<Self, Error> {
         if args.len() < 2 {
             return Err(Error::Err("wrong number of arguments for 'del' command"));
         }
-        let keyname = match args.own(0) {
-            resp::Msg::String(key) | resp::Msg::BulkString(Some(key)) => key,
-            _ => return Err(Error::Err("invalid parameter for 'del' command")),
+        let unlink = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"DEL" => false,
+                b"UNLINK" => true,
+                _ => return Err(Error::Err("invalid name for 'del' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'del' command")),
+        };
+        let mut keys = Vec::with_capacity(args.len() - 1);
+        for i in 1..args.len() {
+            match args.own_bytes(i) {
+                Some(key) => keys.push(key),
+                None => return Err(Error::Err("invalid parameter for 'del' command")),
+            }
+        }
+        Ok(Del(unlink, keys))
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.1[0])
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(del(db, &self.1, self.0)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Del(self)
+    }
+}
+
+impl Scatter for Del {
+    fn keys(&self) -> Vec<&[u8]> {
+        self.1.iter().map(|key| key.as_ref()).collect()
+    }
+
+    fn part(&self, positions: &[usize]) -> Box<dyn Execute> {
+        Box::new(Del(self.0, positions.iter().map(|&i| self.1[i].clone()).collect()))
+    }
+
+    fn gather(&self, replies: Vec<(Vec<usize>, Result<resp::Msg, Error>)>) -> Result<resp::Msg, Error> {
+        count(replies)
+    }
+}
+
+impl Execute for Exists {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 2 {
+            return Err(Error::Err("wrong number of arguments for 'exists' command"));
+        }
+        let touch = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"EXISTS" => false,
+                b"TOUCH" => true,
+                _ => return Err(Error::Err("invalid name for 'exists' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'exists' command")),
         };
-        let del0 = match args.own(1) {
-            resp::Msg::String(key) => key,
-            resp::Msg::BulkString(Some(key)) => key,
-            _ => return Err(Error::Err("invalid parameter for 'del' command")),
+        let mut keys = Vec::with_capacity(args.len() - 1);
+        for i in 1..args.len() {
+            match args.own_bytes(i) {
+                Some(key) => keys.push(key),
+                None => return Err(Error::Err("invalid parameter for 'exists' command")),
+            }
+        }
+        Ok(Exists(touch, keys))
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.1[0])
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(exists(db, &self.1, self.0)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Exists(self)
+    }
+}
+
+impl Scatter for Exists {
+    fn keys(&self) -> Vec<&[u8]> {
+        self.1.iter().map(|key| key.as_ref()).collect()
+    }
+
+    fn part(&self, positions: &[usize]) -> Box<dyn Execute> {
+        Box::new(Exists(self.0, positions.iter().map(|&i| self.1[i].clone()).collect()))
+    }
+
+    fn gather(&self, replies: Vec<(Vec<usize>, Result<resp::Msg, Error>)>) -> Result<resp::Msg, Error> {
+        count(replies)
+    }
+}
+
+impl Execute for Expire {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'expire' command"));
+        }
+        let n = match args.int(2) {
+            Some(n) => n,
+            None => return Err(Error::Err("value is not an integer or out of range")),
         };
-        let mut del = match keyname.as_ref() {
-            b"DEL" | b"del" => Del(true, del0, Vec::new()),
-            b"UNLINK" | b"unlink" => Del(false, del0, Vec::new()),
-            _ => Del(false, del0, Vec::new()),
+        let key = match args.own_bytes(1) {
+            Some(key) => key,
+            None => return Err(Error::Err("invalid parameter for 'expire' command")),
         };
-        for i in 2..args.len() {
-            match args.own(i) {
-                resp::Msg::String(key) | resp::Msg::BulkString(Some(key)) => del.2.push(key),
-                _ => return Err(Error::Err("invalid parameter for 'del' command")),
+        match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"EXPIRE" => Ok(Expire(key, n.saturating_mul(1000), false)),
+                b"PEXPIRE" => Ok(Expire(key, n, false)),
+                b"EXPIREAT" => Ok(Expire(key, n.saturating_mul(1000), true)),
+                b"PEXPIREAT" => Ok(Expire(key, n, true)),
+                _ => Err(Error::Err("invalid name for 'expire' command")),
+            },
+            None => Err(Error::Err("invalid parameter for 'expire' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let now = database::now_ms() as i64;
+        let deadline = if self.2 { self.1 } else { now.saturating_add(self.1) };
+        // a deadline in the past deletes the key right away, unless the AOF
+        // is being replayed: the deletion is in the log
+        if deadline <= now && !database::loading() {
+            return match db.remove(&self.0) {
+                Some(_) => Ok(resp::Msg::Int(1)),
+                None => Ok(resp::Msg::Int(0)),
             };
         }
+        if db.set_expires_at(&self.0, Some(std::cmp::max(deadline, 0) as u64)) {
+            Ok(resp::Msg::Int(1))
+        } else {
+            Ok(resp::Msg::Int(0))
+        }
+    }
+
+    // a relative deadline is logged as an absolute one
+    fn propagate(&self, args: Vec<Bytes>, db: &mut Database, reply: &resp::Msg) -> Vec<Vec<Bytes>> {
+        if let resp::Msg::Int(0) = reply {
+            return Vec::new();
+        }
+        if self.2 {
+            return vec![args];
+        }
+        match db.expires_at(&self.0) {
+            Some(Some(t)) => vec![aof::pexpireat(&self.0, t)],
+            _ => vec![vec![Bytes::from_static(b"DEL"), self.0.clone()]],
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Expire(self)
+    }
+}
+
+impl Execute for Ttl {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'ttl' command"));
+        }
+        let key = match args.own_bytes(1) {
+            Some(key) => key,
+            None => return Err(Error::Err("invalid parameter for 'ttl' command")),
+        };
+        match args.own_bytes(0) {
+            Some(name) => Ok(Ttl(key, name.as_ref() == b"PTTL")),
+            None => Err(Error::Err("invalid parameter for 'ttl' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        match db.expires_at(&self.0) {
+            None => Ok(resp::Msg::Int(-2)),
+            Some(None) => Ok(resp::Msg::Int(-1)),
+            Some(Some(t)) => {
+                let ttl = t.saturating_sub(database::now_ms()) as i64;
+                if self.1 {
+                    Ok(resp::Msg::Int(ttl))
+                } else {
+                    Ok(resp::Msg::Int((ttl + 500) / 1000))
+                }
+            }
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Ttl(self)
+    }
+}
 
-        Ok(del)
+impl Execute for Persist {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'persist' command"));
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Persist(key)),
+            None => Err(Error::Err("invalid parameter for 'persist' command")),
+        }
     }
 
     fn shard(&self) -> u64 {
-        // TODO manage other args
-        let mut hasher = SeaHasher::new();
-        self.1.hash(&mut hasher);
-        hasher.finish()
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
     }
 
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
-         // TODO remove other args
-        match db.remove(&self.1) {
-            Some(_) => {
-                db.shrink_to_fit();
+        match db.expires_at(&self.0) {
+            Some(Some(_)) => {
+                db.set_expires_at(&self.0, None);
                 Ok(resp::Msg::Int(1))
+            }
+            _ => Ok(resp::Msg::Int(0)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Persist(self)
+    }
+}
+
+impl Execute for Type {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'type' command"));
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Type(key)),
+            None => Err(Error::Err("invalid parameter for 'type' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Str(db.get(&self.0).map_or("none", |v| v.type_name())))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Type(self)
+    }
+}
+
+impl Execute for Randomkey {
+    fn parse(args: Args) -> Result<Self, Error> {
+        if args.len() != 1 {
+            return Err(Error::Err("wrong number of arguments for 'randomkey' command"));
+        }
+        Ok(Randomkey)
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::BulkString(db.random_key()))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Randomkey(self)
+    }
+}
+
+// RANDOMKEY asks every shard for a random key along with its size, and
+// picks one of them with a probability proportional to the shard's size.
+impl Coordinate for Randomkey {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        Box::pin(async move {
+            let picks = {
+                let _ticket = database::quiesce().await;
+                database::with_every_shard(&pool, db, |db| (db.len(), db.random_key())).await
+            };
+            let total: usize = picks.iter().map(|(len, _)| len).sum();
+            if total == 0 {
+                return Ok(resp::Msg::BulkString(None));
+            }
+            let mut n = rand::thread_rng().gen_range(0, total);
+            for (len, key) in picks.into_iter() {
+                if n < len {
+                    return Ok(resp::Msg::BulkString(key));
+                }
+                n -= len;
+            }
+            Ok(resp::Msg::BulkString(None))
+        })
+    }
+}
+
+impl Execute for Rename {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'rename' command"));
+        }
+        let nx = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"RENAME" => false,
+                b"RENAMENX" => true,
+                _ => return Err(Error::Err("invalid name for 'rename' command")),
             },
-            None => Ok(resp::Msg::Int(0)),
+            None => return Err(Error::Err("invalid parameter for 'rename' command")),
+        };
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(newkey)) => Ok(Rename(key, newkey, nx)),
+            _ => Err(Error::Err("invalid parameter for 'rename' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        if !db.contains_key(&self.0) {
+            return Err(Error::Err("no such key"));
+        }
+        if self.0 == self.1 || (self.2 && db.contains_key(&self.1)) {
+            return Ok(renamed(self.2, self.0 != self.1));
         }
+        let (value, expires_at) = db.take(&self.0).unwrap();
+        put(db, self.1.clone(), value, expires_at, false);
+        Ok(renamed(self.2, true))
     }
 
     fn to_command(self) -> Command {
-        Command::Del(self)
+        Command::Rename(self)
+    }
+}
+
+// When the two keys are owned by different workers RENAME locks both of
+// them first, so that every other command on either key waits until the
+// value has been moved. Nobody can see the value in both places or in
+// neither.
+impl Coordinate for Rename {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let (key, newkey, nx) = (self.0.clone(), self.1.clone(), self.2);
+        Box::pin(async move {
+            let hashes = vec![shard_for(&key), shard_for(&newkey)];
+            if let Some(shard) = one_worker(&pool, &hashes) {
+                return database::with_unlocked(&pool, shard, db, hashes, move |db| {
+                    let reply = Rename(key.clone(), newkey.clone(), nx).exec(db)?;
+                    let name = if nx { &b"RENAMENX"[..] } else { &b"RENAME"[..] };
+                    aof::feed(db.number(), &[name, &key[..], &newkey[..]]);
+                    Ok(reply)
+                })
+                .await;
+            }
+
+            let locks = database::lock_keys(&pool, db, hashes).await;
+            let reply = move_value(&pool, db, key, newkey, nx).await;
+            database::unlock_keys(&pool, locks).await;
+            reply
+        })
+    }
+}
+
+// move_value moves key from its shard to the shard of newkey, both keys
+// must be locked. Each shard logs its side of the move.
+async fn move_value(pool: &tokio_io_pool::Handle, db: usize, key: Bytes, newkey: Bytes, nx: bool) -> Result<resp::Msg, Error> {
+    let (from, to) = (shard_for(&key), shard_for(&newkey));
+    if nx {
+        let dst = newkey.clone();
+        if database::with_shard(pool, to, db, move |db| db.contains_key(&dst)).await {
+            return match database::with_shard(pool, from, db, move |db| db.contains_key(&key)).await {
+                true => Ok(renamed(nx, false)),
+                false => Err(Error::Err("no such key")),
+            };
+        }
+    }
+    let taken = database::with_shard(pool, from, db, move |db| {
+        let taken = db.take(&key);
+        if taken.is_some() {
+            aof::feed(db.number(), &[&b"DEL"[..], &key[..]]);
+        }
+        taken
+    })
+    .await;
+    match taken {
+        Some((value, expires_at)) => {
+            database::with_shard(pool, to, db, move |db| put(db, newkey, value, expires_at, true)).await;
+            Ok(renamed(nx, true))
+        }
+        None => Err(Error::Err("no such key")),
+    }
+}
+
+// put stores the value of a renamed key, serving the clients blocked on
+// newkey if it is a list. If feed is set the value is logged as a RESTORE
+// first, so that the pops of the clients served are replayed after it.
+fn put(db: &mut Database, newkey: Bytes, value: DBValue, expires_at: Option<u64>, feed: bool) {
+    let list = matches!(value, DBValue::List(_));
+    db.set(newkey.clone(), value, expires_at);
+    if feed {
+        aof::feed_key(db, &newkey);
+    }
+    if list {
+        db.serve_blocked(&newkey);
+    }
+}
+
+impl Execute for Move {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'move' command"));
+        }
+        let db = db_index(&args, 2)?;
+        match args.own_bytes(1) {
+            Some(key) => Ok(Move(key, db)),
+            None => Err(Error::Err("invalid parameter for 'move' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    // MOVE needs two databases of the shard, so it is always coordinated.
+    fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
+        Err(Error::Err("'move' command can't run on a single database"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Move(self)
+    }
+}
+
+impl Move {
+    // apply moves the key from the database numbered db to the destination
+    // database, among dbs, the databases of the shard owning the key. It
+    // returns whether the key was moved.
+    pub fn apply(&self, dbs: &mut [Database], db: usize) -> bool {
+        if dbs[self.1].contains_key(&self.0) {
+            return false;
+        }
+        match dbs[db].take(&self.0) {
+            Some((value, expires_at)) => {
+                put(&mut dbs[self.1], self.0.clone(), value, expires_at, false);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Coordinate for Move {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let (key, dst) = (self.0.clone(), self.1);
+        Box::pin(async move {
+            if db == dst {
+                return Err(Error::Err("source and destination objects are the same"));
+            }
+            let hash = shard_for(&key);
+            let moved = database::with_unlocked_dbs(&pool, hash, vec![db, dst], vec![hash], move |dbs| {
+                let m = Move(key, dst);
+                let moved = m.apply(dbs, db);
+                if moved {
+                    let n = dst.to_string();
+                    aof::feed(db, &[&b"MOVE"[..], &m.0[..], n.as_bytes()]);
+                }
+                moved
+            })
+            .await;
+            Ok(resp::Msg::Int(moved as i64))
+        })
+    }
+}
+
+fn renamed(nx: bool, done: bool) -> resp::Msg {
+    match (nx, done) {
+        (false, _) => resp::Msg::Str("OK"),
+        (true, done) => resp::Msg::Int(done as i64),
+    }
+}
+
+impl Execute for Object {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("unknown subcommand or wrong number of arguments for 'object' command"));
+        }
+        let field = match &args[1] {
+            resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => {
+                match b.to_ascii_uppercase().as_slice() {
+                    b"ENCODING" => Some(ObjectField::Encoding),
+                    b"IDLETIME" => Some(ObjectField::Idletime),
+                    b"FREQ" => Some(ObjectField::Freq),
+                    b"REFCOUNT" => Some(ObjectField::Refcount),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match (field, args.own_bytes(2)) {
+            (Some(field), Some(key)) => Ok(Object(field, key)),
+            _ => Err(Error::Err("unknown subcommand or wrong number of arguments for 'object' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.1)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    // OBJECT looks at the key without counting as an access to it.
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let entry = match db.entry(&self.1) {
+            Some(entry) => entry,
+            None => return Ok(resp::Msg::BulkString(None)),
+        };
+        let now = database::lru_clock();
+        match self.0 {
+            ObjectField::Encoding => Ok(resp::Msg::Str(entry.value.encoding())),
+            ObjectField::Idletime => Ok(resp::Msg::Int(entry.idle(now) as i64)),
+            ObjectField::Freq => match database::policy() {
+                database::Policy::AllKeysLfu | database::Policy::VolatileLfu => Ok(resp::Msg::Int(entry.freq(now) as i64)),
+                _ => Err(Error::Err("an LFU maxmemory policy is not selected, access frequency not tracked")),
+            },
+            ObjectField::Refcount => Ok(resp::Msg::Int(1)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Object(self)
+    }
+}
+
+impl Execute for Dump {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'dump' command"));
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Dump(key)),
+            None => Err(Error::Err("invalid parameter for 'dump' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        match db.get(&self.0) {
+            Some(value) => Ok(resp::Msg::BulkString(Some(dump::dump(value)))),
+            None => Ok(resp::Msg::BulkString(None)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Dump(self)
+    }
+}
+
+impl Execute for Restore {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 4 {
+            return Err(Error::Err("wrong number of arguments for 'restore' command"));
+        }
+        let (mut replace, mut absttl) = (false, false);
+        for i in 4..args.len() {
+            match &args[i] {
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"REPLACE") => replace = true,
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"ABSTTL") => absttl = true,
+                _ => return Err(Error::Err("syntax error")),
+            }
+        }
+        let ttl = match args.int(2) {
+            Some(ttl) if ttl >= 0 => ttl,
+            Some(_) => return Err(Error::Err("Invalid TTL value, must be >= 0")),
+            None => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        match (args.own_bytes(1), args.own_bytes(3)) {
+            (Some(key), Some(payload)) => Ok(Restore(key, ttl, payload, replace, absttl)),
+            _ => Err(Error::Err("invalid parameter for 'restore' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    // RESTORE decodes the payload on the shard, as values can't be moved
+    // out of a command that is only borrowed.
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let value = dump::restore(&self.2).map_err(Error::Err)?;
+        if !self.3 && db.contains_key(&self.0) {
+            return Err(Error::BusyKey);
+        }
+        let now = database::now_ms() as i64;
+        let expires_at = match self.1 {
+            0 => None,
+            ttl if self.4 => Some(ttl),
+            ttl => Some(now.saturating_add(ttl)),
+        };
+        // a deadline in the past only deletes the key being replaced, unless
+        // the AOF is being replayed
+        match expires_at {
+            Some(deadline) if deadline <= now && !database::loading() => {
+                db.remove(&self.0);
+            }
+            _ => put(db, self.0.clone(), value, expires_at.map(|t| std::cmp::max(t, 0) as u64), false),
+        }
+        Ok(resp::Msg::Str("OK"))
+    }
+
+    // a relative TTL is logged as an absolute deadline
+    fn propagate(&self, mut args: Vec<Bytes>, db: &mut Database, _reply: &resp::Msg) -> Vec<Vec<Bytes>> {
+        if let (false, Some(Some(t))) = (self.4, db.expires_at(&self.0)) {
+            args[2] = Bytes::from(t.to_string());
+            args.push(Bytes::from_static(b"ABSTTL"));
+        }
+        vec![args]
+    }
+
+    fn to_command(self) -> Command {
+        Command::Restore(self)
     }
 }
@@ -0,0 +1,841 @@
+use std::collections::VecDeque;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time;
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+use tokio::sync::oneshot;
+use tokio::timer::Timeout;
+
+use super::{aof, database, database::Ticket, database::Value as DBValue, database::Waiter, one_worker, range, resp, shard_for, Args, Command, Coordinate, Database, Error, Execute, Reply};
+
+pub struct Push(Bytes, Vec<Bytes>, bool, bool); // key, values, left, only if the key exists
+pub struct Pop(Bytes, bool); // key, left
+pub struct Lrange(Bytes, i64, i64); // key, start, stop
+pub struct Llen(Bytes);
+pub struct Lindex(Bytes, i64); // key, index
+pub struct Lset(Bytes, i64, Bytes); // key, index, value
+pub struct Ltrim(Bytes, i64, i64); // key, start, stop
+pub struct Lrem(Bytes, i64, Bytes); // key, count, value
+pub struct Linsert(Bytes, bool, Bytes, Bytes); // key, before, pivot, value
+pub struct Bpop(Vec<Bytes>, bool, time::Duration); // keys, left, timeout (zero blocks forever)
+pub struct Rpoplpush(Bytes, Bytes, Option<time::Duration>); // source, destination, timeout
+
+fn get_list<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a mut VecDeque<Bytes>>, Error> {
+    match db.get_mut(key) {
+        Some(DBValue::List(l)) => Ok(Some(l)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+// read_list is like get_list, for commands that don't modify the list.
+fn read_list<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a VecDeque<Bytes>>, Error> {
+    match db.get(key) {
+        Some(DBValue::List(v)) => Ok(Some(v)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn pop(db: &mut Database, key: &[u8], left: bool) -> Result<Option<Bytes>, Error> {
+    let (value, empty) = match get_list(db, key)? {
+        Some(list) => {
+            let value = if left { list.pop_front() } else { list.pop_back() };
+            (value, list.is_empty())
+        }
+        None => return Ok(None),
+    };
+    if empty {
+        db.remove(key);
+    }
+    Ok(value)
+}
+
+// pop_or_block pops an element from key for waiter, or if the list is empty
+// registers waiter to be served by a later push. So does it if waiter moves
+// the element and the gate can't be held meanwhile, see Waiter.
+fn pop_or_block(db: &mut Database, key: Bytes, waiter: Waiter) -> Result<Option<(Bytes, Bytes, Option<Ticket>)>, Error> {
+    let ticket = match read_list(db, &key)? {
+        Some(_) if waiter.moves => match database::try_hold() {
+            Some(ticket) => Some(ticket),
+            None => {
+                if !waiter.is_done() {
+                    db.block(key.clone(), waiter);
+                    db.stall(key);
+                }
+                return Ok(None);
+            }
+        },
+        Some(_) => None,
+        None => {
+            if !waiter.is_done() {
+                db.block(key, waiter);
+            }
+            return Ok(None);
+        }
+    };
+    // another key this client is blocked on was already served
+    if !waiter.try_claim() {
+        return Ok(None);
+    }
+    let popped = pop(db, &key, waiter.left)?;
+    if popped.is_some() {
+        aof::feed(db.number(), &[if waiter.left { &b"LPOP"[..] } else { &b"RPOP"[..] }, &key[..]]);
+    }
+    Ok(popped.map(|value| (key, value, ticket)))
+}
+
+// push_logged pushes value to the head (left) or tail of the list at key,
+// and logs it to the AOF.
+fn push_logged(db: &mut Database, key: Bytes, value: Bytes, left: bool) -> Result<resp::Msg, Error> {
+    let reply = Push(key.clone(), vec![value.clone()], left, false).exec(db)?;
+    aof::feed(db.number(), &[if left { &b"LPUSH"[..] } else { &b"RPUSH"[..] }, &key[..], &value[..]]);
+    Ok(reply)
+}
+
+// rpoplpush moves the tail of src to the head of dst, both keys must be
+// owned by the current shard.
+fn rpoplpush(db: &mut Database, src: &Bytes, dst: &Bytes) -> Result<Option<Bytes>, Error> {
+    read_list(db, dst)?;
+    let value = match pop(db, src, false)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    Push(dst.clone(), vec![value.clone()], true, false).exec(db)?;
+    Ok(Some(value))
+}
+
+// Blocked is the client waiting in block_pop. Dropping it gives up the
+// wait, as happens when the client disconnects: unless a shard already
+// claimed the client, it claims it so that no shard serves it anymore,
+// otherwise it gives the element popped for the client back to its list.
+struct Blocked {
+    pool: tokio_io_pool::Handle,
+    db: usize,
+    left: bool,
+    claim: Arc<AtomicBool>,
+    rx: mpsc::UnboundedReceiver<(Bytes, Bytes, Option<Ticket>)>,
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        if self.claim.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            return;
+        }
+        // an element sent from now on is given back by the shard sending it
+        self.rx.close();
+        while let Ok(Some((key, value, ticket))) = self.rx.try_next() {
+            give_back(&self.pool, self.db, key, value, self.left, ticket);
+        }
+    }
+}
+
+// give_back pushes value back to the head (left) or tail of the list at key
+// it was popped from, on the shard owning key. It doesn't wait for the push
+// to be done, so that it can't be interrupted. ticket, the gate held for
+// the element if any, is given back once the element is.
+fn give_back(pool: &tokio_io_pool::Handle, db: usize, key: Bytes, value: Bytes, left: bool, ticket: Option<Ticket>) {
+    let hash = shard_for(&key);
+    let _ = pool.spawn_on(hash, async move {
+        database::unlocked(&[db], &[hash]).await;
+        database::with_dbs(|dbs| {
+            let _ = push_logged(&mut dbs[db], key, value, left);
+        });
+        drop(ticket);
+    });
+}
+
+// block_pop pops the first element available from any of keys, waiting up
+// to timeout (or forever if timeout is zero) for one of them to be pushed
+// to. Keys are tried in order, so when several keys already hold elements
+// the first one wins. moves is set if the element is to be moved to another
+// key, it then comes with a ticket holding the gate, see Waiter.
+async fn block_pop(
+    pool: &tokio_io_pool::Handle,
+    db: usize,
+    keys: Vec<Bytes>,
+    left: bool,
+    moves: bool,
+    timeout: time::Duration,
+) -> Result<Option<(Bytes, Bytes, Option<Ticket>)>, Error> {
+    // tx is kept alive so rx only ever resolves with an element
+    let (tx, rx) = mpsc::unbounded();
+    let mut blocked = Blocked {
+        pool: pool.clone(),
+        db,
+        left,
+        claim: Arc::new(AtomicBool::new(false)),
+        rx,
+    };
+    for key in keys.into_iter() {
+        let waiter = Waiter {
+            claim: blocked.claim.clone(),
+            left,
+            moves,
+            tx: tx.clone(),
+        };
+        let hash = shard_for(&key);
+        match database::with_unlocked(pool, hash, db, vec![hash], move |db| pop_or_block(db, key, waiter)).await {
+            Ok(Some(popped)) => return Ok(Some(popped)),
+            Ok(None) => {}
+            Err(e) => {
+                blocked.claim.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+    }
+
+    let popped = if timeout.as_nanos() == 0 {
+        blocked.rx.next().await
+    } else {
+        Timeout::new(blocked.rx.next(), timeout).await.unwrap_or_default()
+    };
+    if popped.is_some() {
+        return Ok(popped);
+    }
+    // we timed out, unless a shard claimed us while the timer fired in which
+    // case its element is already on the way.
+    if blocked
+        .claim
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+    {
+        return Ok(None);
+    }
+    Ok(blocked.rx.next().await)
+}
+
+// MAX_TIMEOUT is the longest timeout a blocked client is given, in seconds.
+// The timer can't hold deadlines much further out, longer ones block
+// forever like a timeout of zero.
+const MAX_TIMEOUT: f64 = 365.0 * 24.0 * 3600.0;
+
+fn parse_timeout(args: &Args, i: usize) -> Result<time::Duration, Error> {
+    let timeout = match &args[i] {
+        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => {
+            str::from_utf8(b.as_ref()).ok().and_then(|s| s.parse::<f64>().ok())
+        }
+        resp::Msg::Int(n) => Some(*n as f64),
+        _ => None,
+    };
+    match timeout {
+        Some(t) if t < 0.0 => Err(Error::Err("timeout is negative")),
+        Some(t) if t.is_finite() && t > MAX_TIMEOUT => Ok(time::Duration::from_millis(0)),
+        Some(t) if t.is_finite() => Ok(time::Duration::from_millis((t * 1000.0) as u64)),
+        _ => Err(Error::Err("timeout is not a float or out of range")),
+    }
+}
+
+// index converts a redis list index (where negative indexes count from the
+// tail) to a position in a list of len elements.
+fn index(i: i64, len: usize) -> Option<usize> {
+    let i = if i < 0 { len as i64 + i } else { i };
+    if i < 0 || i >= len as i64 {
+        None
+    } else {
+        Some(i as usize)
+    }
+}
+
+impl Execute for Push {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 3 {
+            return Err(Error::Err("wrong number of arguments for 'push' command"));
+        }
+        let mut cmd = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"LPUSH" => Push(Bytes::new(), Vec::with_capacity(args.len() - 2), true, false),
+                b"RPUSH" => Push(Bytes::new(), Vec::with_capacity(args.len() - 2), false, false),
+                b"LPUSHX" => Push(Bytes::new(), Vec::with_capacity(args.len() - 2), true, true),
+                b"RPUSHX" => Push(Bytes::new(), Vec::with_capacity(args.len() - 2), false, true),
+                _ => return Err(Error::Err("invalid name for 'push' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'push' command")),
+        };
+        cmd.0 = match args.own_bytes(1) {
+            Some(key) => key,
+            None => return Err(Error::Err("invalid parameter for 'push' command")),
+        };
+        for i in 2..args.len() {
+            match args.own_bytes(i) {
+                Some(value) => cmd.1.push(value),
+                None => return Err(Error::Err("invalid parameter for 'push' command")),
+            }
+        }
+        Ok(cmd)
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let list = match get_list(db, &self.0)? {
+            Some(l) => l,
+            None => {
+                if self.3 {
+                    return Ok(resp::Msg::Int(0));
+                }
+                db.insert(self.0.clone(), DBValue::List(VecDeque::with_capacity(self.1.len())));
+                get_list(db, &self.0)?.unwrap()
+            }
+        };
+        for value in self.1.iter() {
+            if self.2 {
+                list.push_front(value.clone());
+            } else {
+                list.push_back(value.clone());
+            }
+        }
+        let len = list.len();
+        db.serve_blocked(&self.0);
+        Ok(resp::Msg::Int(len as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Push(self)
+    }
+}
+
+impl Execute for Pop {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'pop' command"));
+        }
+        let key = match args.own_bytes(1) {
+            Some(key) => key,
+            None => return Err(Error::Err("invalid parameter for 'pop' command")),
+        };
+        match args.own_bytes(0) {
+            Some(name) => Ok(Pop(key, name.as_ref() == b"LPOP")),
+            None => Err(Error::Err("invalid parameter for 'pop' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::BulkString(pop(db, &self.0, self.1)?))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Pop(self)
+    }
+}
+
+impl Execute for Lrange {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'lrange' command"));
+        }
+        let (start, stop) = match (args.int(2), args.int(3)) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        match args.own_bytes(1) {
+            Some(key) => Ok(Lrange(key, start, stop)),
+            None => Err(Error::Err("invalid parameter for 'lrange' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let list = match read_list(db, &self.0)? {
+            Some(l) => l,
+            None => return Ok(resp::Msg::Array(Some(Vec::new()))),
+        };
+        match range(self.1, self.2, list.len()) {
+            Some((start, end)) => Ok(resp::Msg::Array(Some(
+                list.range(start..end)
+                    .map(|v| resp::Msg::BulkString(Some(v.clone())))
+                    .collect(),
+            ))),
+            None => Ok(resp::Msg::Array(Some(Vec::new()))),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Lrange(self)
+    }
+}
+
+impl Execute for Llen {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'llen' command"));
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Llen(key)),
+            None => Err(Error::Err("invalid parameter for 'llen' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        match read_list(db, &self.0)? {
+            Some(list) => Ok(resp::Msg::Int(list.len() as i64)),
+            None => Ok(resp::Msg::Int(0)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Llen(self)
+    }
+}
+
+impl Execute for Lindex {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'lindex' command"));
+        }
+        let i = match args.int(2) {
+            Some(i) => i,
+            None => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        match args.own_bytes(1) {
+            Some(key) => Ok(Lindex(key, i)),
+            None => Err(Error::Err("invalid parameter for 'lindex' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let list = match read_list(db, &self.0)? {
+            Some(l) => l,
+            None => return Ok(resp::Msg::BulkString(None)),
+        };
+        match index(self.1, list.len()) {
+            Some(i) => Ok(resp::Msg::BulkString(Some(list[i].clone()))),
+            None => Ok(resp::Msg::BulkString(None)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Lindex(self)
+    }
+}
+
+impl Execute for Lset {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'lset' command"));
+        }
+        let i = match args.int(2) {
+            Some(i) => i,
+            None => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        match (args.own_bytes(1), args.own_bytes(3)) {
+            (Some(key), Some(value)) => Ok(Lset(key, i, value)),
+            _ => Err(Error::Err("invalid parameter for 'lset' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let list = match get_list(db, &self.0)? {
+            Some(l) => l,
+            None => return Err(Error::Err("no such key")),
+        };
+        match index(self.1, list.len()) {
+            Some(i) => {
+                list[i] = self.2.clone();
+                Ok(resp::Msg::Str("OK"))
+            }
+            None => Err(Error::Err("index out of range")),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Lset(self)
+    }
+}
+
+impl Execute for Ltrim {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'ltrim' command"));
+        }
+        let (start, stop) = match (args.int(2), args.int(3)) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        match args.own_bytes(1) {
+            Some(key) => Ok(Ltrim(key, start, stop)),
+            None => Err(Error::Err("invalid parameter for 'ltrim' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let empty = match get_list(db, &self.0)? {
+            Some(list) => match range(self.1, self.2, list.len()) {
+                Some((start, end)) => {
+                    list.truncate(end);
+                    list.drain(..start);
+                    false
+                }
+                None => true,
+            },
+            None => return Ok(resp::Msg::Str("OK")),
+        };
+        if empty {
+            db.remove(&self.0);
+        }
+        Ok(resp::Msg::Str("OK"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Ltrim(self)
+    }
+}
+
+impl Execute for Lrem {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'lrem' command"));
+        }
+        let count = match args.int(2) {
+            Some(count) => count,
+            None => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        match (args.own_bytes(1), args.own_bytes(3)) {
+            (Some(key), Some(value)) => Ok(Lrem(key, count, value)),
+            _ => Err(Error::Err("invalid parameter for 'lrem' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let (removed, empty) = match get_list(db, &self.0)? {
+            Some(list) => {
+                let limit = if self.1 == 0 { usize::MAX } else { self.1.unsigned_abs() as usize };
+                // a negative count removes the matches closest to the tail,
+                // that is all of them but the first ones
+                let mut kept = if self.1 >= 0 {
+                    0
+                } else {
+                    list.iter().filter(|v| **v == self.2).count().saturating_sub(limit)
+                };
+                let mut removed = 0;
+                list.retain(|v| {
+                    if *v != self.2 {
+                        true
+                    } else if kept > 0 {
+                        kept -= 1;
+                        true
+                    } else if removed < limit {
+                        removed += 1;
+                        false
+                    } else {
+                        true
+                    }
+                });
+                (removed, list.is_empty())
+            }
+            None => return Ok(resp::Msg::Int(0)),
+        };
+        if empty {
+            db.remove(&self.0);
+        }
+        Ok(resp::Msg::Int(removed as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Lrem(self)
+    }
+}
+
+impl Execute for Linsert {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 5 {
+            return Err(Error::Err("wrong number of arguments for 'linsert' command"));
+        }
+        let before = match args.own_bytes(2) {
+            Some(ref w) if w.eq_ignore_ascii_case(b"BEFORE") => true,
+            Some(ref w) if w.eq_ignore_ascii_case(b"AFTER") => false,
+            _ => return Err(Error::Err("syntax error")),
+        };
+        match (args.own_bytes(1), args.own_bytes(3), args.own_bytes(4)) {
+            (Some(key), Some(pivot), Some(value)) => Ok(Linsert(key, before, pivot, value)),
+            _ => Err(Error::Err("invalid parameter for 'linsert' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let list = match get_list(db, &self.0)? {
+            Some(l) => l,
+            None => return Ok(resp::Msg::Int(0)),
+        };
+        match list.iter().position(|v| *v == self.2) {
+            Some(i) => {
+                let i = if self.1 { i } else { i + 1 };
+                list.insert(i, self.3.clone());
+                Ok(resp::Msg::Int(list.len() as i64))
+            }
+            None => Ok(resp::Msg::Int(-1)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Linsert(self)
+    }
+}
+
+impl Execute for Bpop {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 3 {
+            return Err(Error::Err("wrong number of arguments for 'bpop' command"));
+        }
+        let timeout = parse_timeout(&args, args.len() - 1)?;
+        let left = match args.own_bytes(0) {
+            Some(name) => name.as_ref() == b"BLPOP",
+            None => return Err(Error::Err("invalid parameter for 'bpop' command")),
+        };
+        let mut keys = Vec::with_capacity(args.len() - 2);
+        for i in 1..args.len() - 1 {
+            match args.own_bytes(i) {
+                Some(key) => keys.push(key),
+                None => return Err(Error::Err("invalid parameter for 'bpop' command")),
+            }
+        }
+        Ok(Bpop(keys, left, timeout))
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0[0])
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    // exec pops without blocking, it is only used when the command can't
+    // wait (for example inside a transaction).
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        for key in self.0.iter() {
+            if let Some(value) = pop(db, key, self.1)? {
+                return Ok(resp::Msg::Array(Some(vec![
+                    resp::Msg::BulkString(Some(key.clone())),
+                    resp::Msg::BulkString(Some(value)),
+                ])));
+            }
+        }
+        Ok(resp::Msg::Array(None))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Bpop(self)
+    }
+}
+
+impl Coordinate for Bpop {
+    fn blocks(&self) -> bool {
+        true
+    }
+
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let keys = self.0.clone();
+        let left = self.1;
+        let timeout = self.2;
+        Box::pin(async move {
+            match block_pop(&pool, db, keys, left, false, timeout).await? {
+                Some((key, value, _)) => Ok(resp::Msg::Array(Some(vec![
+                    resp::Msg::BulkString(Some(key)),
+                    resp::Msg::BulkString(Some(value)),
+                ]))),
+                None => Ok(resp::Msg::Array(None)),
+            }
+        })
+    }
+}
+
+impl Execute for Rpoplpush {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let timeout = match args.len() {
+            3 => None,
+            4 => Some(parse_timeout(&args, 3)?),
+            _ => return Err(Error::Err("wrong number of arguments for 'rpoplpush' command")),
+        };
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(src), Some(dst)) => Ok(Rpoplpush(src, dst, timeout)),
+            _ => Err(Error::Err("invalid parameter for 'rpoplpush' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::BulkString(rpoplpush(db, &self.0, &self.1)?))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Rpoplpush(self)
+    }
+}
+
+// When the two keys are owned by different workers RPOPLPUSH locks both
+// of them while it moves the element, like RENAME. BRPOPLPUSH can't wait
+// for an element with the keys locked, the element it is served is pushed
+// once it gets there.
+impl Coordinate for Rpoplpush {
+    fn blocks(&self) -> bool {
+        self.2.is_some()
+    }
+
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let src = self.0.clone();
+        let dst = self.1.clone();
+        let timeout = self.2;
+        Box::pin(async move {
+            let hashes = vec![shard_for(&src), shard_for(&dst)];
+            let moved = match one_worker(&pool, &hashes) {
+                Some(shard) => {
+                    // both keys live on the same worker, move the element in
+                    // one step.
+                    let (s, d) = (src.clone(), dst.clone());
+                    database::with_unlocked(&pool, shard, db, hashes, move |db| -> Result<Option<Bytes>, Error> {
+                        let moved = rpoplpush(db, &s, &d)?;
+                        if moved.is_some() {
+                            aof::feed(db.number(), &[&b"RPOPLPUSH"[..], &s[..], &d[..]]);
+                        }
+                        Ok(moved)
+                    })
+                    .await?
+                }
+                None => {
+                    // BRPOPLPUSH may be dropped at any point, the keys must
+                    // be unlocked all the same.
+                    let (p, c) = oneshot::channel();
+                    let (pl, s, d) = (pool.clone(), src.clone(), dst.clone());
+                    let _ = pool.spawn_on(hashes[0], async move {
+                        let locks = database::lock_keys(&pl, db, hashes).await;
+                        let moved = move_tail(&pl, db, s, d).await;
+                        database::unlock_keys(&pl, locks).await;
+                        let _ = p.send(moved);
+                    });
+                    c.await.unwrap()?
+                }
+            };
+            let timeout = match timeout {
+                Some(timeout) if moved.is_none() => timeout,
+                _ => return Ok(resp::Msg::BulkString(moved)),
+            };
+
+            let (value, ticket) = match block_pop(&pool, db, vec![src.clone()], false, true, timeout).await? {
+                Some((_, value, ticket)) => (value, ticket),
+                None => return Ok(resp::Msg::BulkString(None)),
+            };
+            // each shard logs its side of the move, the gate is held until
+            // the element got to either key.
+            let (p, v) = (pool.clone(), value.clone());
+            let hash = shard_for(&dst);
+            database::with_unlocked(&pool, hash, db, vec![hash], move |dst_db| {
+                let pushed = push_logged(dst_db, dst, v.clone(), true);
+                if pushed.is_err() {
+                    // the destination stopped being a list while we were
+                    // waiting, give the element back to the source.
+                    give_back(&p, db, src, v, false, ticket);
+                }
+                pushed
+            })
+            .await?;
+            Ok(resp::Msg::BulkString(Some(value)))
+        })
+    }
+}
+
+// move_tail moves the tail of the list at src to the head of the list at
+// dst, owned by different shards, both keys must be locked. Each shard
+// logs its side of the move.
+async fn move_tail(pool: &tokio_io_pool::Handle, db: usize, src: Bytes, dst: Bytes) -> Result<Option<Bytes>, Error> {
+    let d = dst.clone();
+    database::with_shard(pool, shard_for(&dst), db, move |db| read_list(db, &d).map(|_| ())).await?;
+    let s = src.clone();
+    let popped = database::with_shard(pool, shard_for(&src), db, move |db| -> Result<Option<Bytes>, Error> {
+        let popped = pop(db, &s, false)?;
+        if popped.is_some() {
+            aof::feed(db.number(), &[&b"RPOP"[..], &s[..]]);
+        }
+        Ok(popped)
+    })
+    .await?;
+    let value = match popped {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let v = value.clone();
+    database::with_shard(pool, shard_for(&dst), db, move |db| push_logged(db, dst, v, true)).await?;
+    Ok(Some(value))
+}
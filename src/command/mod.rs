@@ -1,16 +1,32 @@
 mod connection;
+mod hash;
 mod index;
 mod keys;
+mod list;
+mod scan;
+mod server;
+mod set;
 mod string;
+mod zset;
 
 use std::error;
 use std::fmt;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::mem;
+use std::pin::Pin;
+use std::str;
 
-pub use self::index::{Command, COMMANDS};
+pub use self::index::{Command, COMMANDS, DENYOOM};
+use super::aof;
 use super::database::{self, Database};
+use super::dump;
+use super::snapshot;
+use super::glob;
 use super::resp;
 use bytes::Bytes;
+use futures::future;
+use seahash::SeaHasher;
 
 pub trait Execute: Send + Sync {
     fn parse(args: Args) -> Result<Self, Error>
@@ -18,8 +34,19 @@ pub trait Execute: Send + Sync {
         Self: Sized;
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error>;
     fn shard(&self) -> u64;
+    // writes tells whether the command may modify the dataset, only those
+    // commands are appended to the AOF.
+    fn writes(&self) -> bool;
     fn to_command(self) -> index::Command;
 
+    // propagate returns the commands appended to the AOF once the command
+    // ran successfully on db, args being the arguments it was called with.
+    // Commands whose effect depends on when they run or on chance return a
+    // deterministic equivalent instead of themselves.
+    fn propagate(&self, args: Vec<Bytes>, _db: &mut Database, _reply: &resp::Msg) -> Vec<Vec<Bytes>> {
+        vec![args]
+    }
+
     fn new(args: Args) -> Result<index::Command, Error>
     where
         Self: 'static + Sized,
@@ -31,6 +58,36 @@ pub trait Execute: Send + Sync {
     }
 }
 
+pub type Reply = Pin<Box<dyn Future<Output = Result<resp::Msg, Error>> + Send>>;
+
+// Coordinate is implemented by commands that can't be answered by a single
+// call to Execute::exec on the shard returned by Execute::shard, either
+// because their keys may be owned by different workers or because they
+// have to wait for other clients. The connection awaits the returned future
+// instead of executing the command, db is the database it has selected.
+pub trait Coordinate: Send + Sync {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply;
+
+    // blocks tells whether the command may wait for other clients. The
+    // connection drops the returned future if the client disconnects while
+    // it waits, so it must be safe to drop at any point.
+    fn blocks(&self) -> bool {
+        false
+    }
+}
+
+// Scatter is implemented by commands made of independent per-key parts
+// (MGET, MSET, DEL, EXISTS). The connection splits the keys by owning
+// worker, runs one part per worker and hands the replies back to gather.
+pub trait Scatter: Send + Sync {
+    fn keys(&self) -> Vec<&[u8]>;
+    // part returns the command for the keys at positions.
+    fn part(&self, positions: &[usize]) -> Box<dyn Execute>;
+    // gather merges the reply of every part, along with the positions of
+    // its keys, into the reply of the command.
+    fn gather(&self, replies: Vec<(Vec<usize>, Result<resp::Msg, Error>)>) -> Result<resp::Msg, Error>;
+}
+
 pub struct Args(pub Vec<resp::Msg>);
 
 // http://xion.io/post/code/rust-move-out-of-container.html
@@ -38,6 +95,220 @@ impl Args {
     pub fn own(&mut self, i: usize) -> resp::Msg {
         mem::replace(&mut self.0[i], resp::Msg::None)
     }
+
+    // own_bytes moves the string argument at i out of args, returning None
+    // if the argument isn't a string.
+    pub fn own_bytes(&mut self, i: usize) -> Option<Bytes> {
+        match self.own(i) {
+            resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => Some(b),
+            _ => None,
+        }
+    }
+
+    // int parses the argument at i as a signed 64 bit integer.
+    pub fn int(&self, i: usize) -> Option<i64> {
+        match &self.0[i] {
+            resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => {
+                str::from_utf8(b.as_ref()).ok().and_then(|s| s.parse::<i64>().ok())
+            }
+            resp::Msg::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    // float parses the argument at i as a double, inf and -inf are accepted
+    // but NaN is not.
+    pub fn float(&self, i: usize) -> Option<f64> {
+        match &self.0[i] {
+            resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => str::from_utf8(b.as_ref())
+                .ok()
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|f| !f.is_nan()),
+            resp::Msg::Int(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+}
+
+// range converts the inclusive redis range [start, stop] to a half open
+// range over a list of len elements, returns None if the range is empty.
+pub fn range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { std::cmp::max(len + start, 0) } else { start };
+    let stop = if stop < 0 { len + stop } else { std::cmp::min(stop, len - 1) };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize + 1))
+    }
+}
+
+// format_float formats f the way redis replies with floating point values.
+pub fn format_float(f: f64) -> Bytes {
+    Bytes::from(format!("{}", f))
+}
+
+// db_index parses the argument at i as the number of a database.
+pub fn db_index(args: &Args, i: usize) -> Result<usize, Error> {
+    match args.int(i) {
+        Some(n) if n >= 0 && (n as usize) < database::DATABASES => Ok(n as usize),
+        Some(_) => Err(Error::Err("DB index is out of range")),
+        None => Err(Error::Err("value is not an integer or out of range")),
+    }
+}
+
+// shard_for returns the shard hash that owns key.
+pub fn shard_for(key: &[u8]) -> u64 {
+    let mut hasher = SeaHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// split_by_worker groups items by the pool worker owning their shard. Each
+// group is returned along with the shard of one of its items, suitable for
+// database::with_shard.
+pub fn split_by_worker<T, F>(pool: &tokio_io_pool::Handle, items: Vec<T>, shard: F) -> Vec<(u64, Vec<T>)>
+where
+    F: Fn(&T) -> u64,
+{
+    let mut groups: Vec<(_, u64, Vec<T>)> = Vec::new();
+    for item in items.into_iter() {
+        let shard = shard(&item);
+        let worker = pool.worker_id(shard);
+        match groups.iter_mut().find(|(w, _, _)| *w == worker) {
+            Some((_, _, group)) => group.push(item),
+            None => groups.push((worker, shard, vec![item])),
+        }
+    }
+    groups.into_iter().map(|(_, shard, group)| (shard, group)).collect()
+}
+
+// one_worker returns one of hashes if they are all owned by the same pool
+// worker, suitable for database::with_unlocked, or None if they aren't.
+pub fn one_worker(pool: &tokio_io_pool::Handle, hashes: &[u64]) -> Option<u64> {
+    let worker = pool.worker_id(hashes[0]);
+    if hashes.iter().all(|&hash| pool.worker_id(hash) == worker) {
+        Some(hashes[0])
+    } else {
+        None
+    }
+}
+
+// fetch_all reads every key with fetch on the shard owning it and returns
+// the reads in the order of keys, or the first error. Keys owned by
+// several workers are locked meanwhile, so that the reads are made at
+// once.
+pub async fn fetch_all<T>(
+    pool: &tokio_io_pool::Handle,
+    db: usize,
+    keys: Vec<Bytes>,
+    fetch: fn(&mut Database, &[u8]) -> Result<T, Error>,
+) -> Result<Vec<T>, Error>
+where
+    T: Send + 'static,
+{
+    let hashes: Vec<u64> = keys.iter().map(|key| shard_for(key)).collect();
+    if let Some(shard) = one_worker(pool, &hashes) {
+        return database::with_unlocked(pool, shard, db, hashes, move |db| keys.iter().map(|key| fetch(db, key)).collect()).await;
+    }
+    let locks = database::lock_keys(pool, db, hashes).await;
+    let reads = fetch_locked(pool, db, keys, fetch).await;
+    database::unlock_keys(pool, locks).await;
+    reads
+}
+
+// fetch_then_store combines the reads of keys made by fetch_all and hands
+// the result to store on the shard owning dst, which then logs the new
+// state of dst. It returns what store returned. Keys and dst are locked
+// meanwhile when they are owned by several workers.
+pub async fn fetch_then_store<T, R, C>(
+    pool: &tokio_io_pool::Handle,
+    db: usize,
+    keys: Vec<Bytes>,
+    dst: Bytes,
+    fetch: fn(&mut Database, &[u8]) -> Result<T, Error>,
+    combine: C,
+    store: fn(&mut Database, Bytes, R) -> i64,
+) -> Result<i64, Error>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    C: FnOnce(Vec<T>) -> R + Send + 'static,
+{
+    let mut hashes: Vec<u64> = keys.iter().map(|key| shard_for(key)).collect();
+    hashes.push(shard_for(&dst));
+    if let Some(shard) = one_worker(pool, &hashes) {
+        return database::with_unlocked(pool, shard, db, hashes, move |db| {
+            let reads = keys.iter().map(|key| fetch(db, key)).collect::<Result<Vec<T>, Error>>()?;
+            let n = store(db, dst.clone(), combine(reads));
+            aof::feed_key(db, &dst);
+            Ok(n)
+        })
+        .await;
+    }
+    let locks = database::lock_keys(pool, db, hashes).await;
+    let n = match fetch_locked(pool, db, keys, fetch).await {
+        Ok(reads) => {
+            let result = combine(reads);
+            let n = database::with_shard(pool, shard_for(&dst), db, move |db| {
+                let n = store(db, dst.clone(), result);
+                aof::feed_key(db, &dst);
+                n
+            })
+            .await;
+            Ok(n)
+        }
+        Err(e) => Err(e),
+    };
+    database::unlock_keys(pool, locks).await;
+    n
+}
+
+// fetch_locked reads every key with fetch, all of them being locked.
+async fn fetch_locked<T>(
+    pool: &tokio_io_pool::Handle,
+    db: usize,
+    keys: Vec<Bytes>,
+    fetch: fn(&mut Database, &[u8]) -> Result<T, Error>,
+) -> Result<Vec<T>, Error>
+where
+    T: Send + 'static,
+{
+    let reads = future::join_all(keys.into_iter().map(|key| database::with_shard(pool, shard_for(&key), db, move |db| fetch(db, &key)))).await;
+    reads.into_iter().collect()
+}
+
+// replay applies a command read back from the AOF to dbs, the databases of
+// a shard, db being the database selected by the commands before it. The
+// commands needing several databases are applied here, the others run the
+// way they would for a client. A command that fails is skipped, as it
+// failed when it was logged too.
+pub fn replay(dbs: &mut [Database], db: &mut usize, mut args: Args) -> Result<(), Error> {
+    let name = match args.own_bytes(0) {
+        Some(name) => Bytes::from(name.to_ascii_uppercase()),
+        None => return Err(Error::Err("invalid command")),
+    };
+    args.0[0] = resp::Msg::BulkString(Some(name.clone()));
+    let command = match COMMANDS.get(name.as_ref()) {
+        Some(c) => c(args)?,
+        None => return Err(Error::Error(format!("unknown command '{}'", String::from_utf8_lossy(&name)))),
+    };
+    match command {
+        Command::Select(select) => *db = select.index(),
+        Command::Flush(flush) => flush.apply(dbs, *db),
+        Command::Swapdb(swapdb) => swapdb.apply(dbs),
+        Command::Move(m) => {
+            m.apply(dbs, *db);
+        }
+        command => {
+            let command = command.to_execute();
+            if !command.writes() {
+                return Err(Error::Err("command doesn't write"));
+            }
+            let _ = command.exec(&mut dbs[*db]);
+        }
+    }
+    Ok(())
 }
 
 // https://stackoverflow.com/a/32552688/807701
@@ -63,6 +334,10 @@ impl Execute for Unimplemented {
         std::u64::MAX
     }
 
+    fn writes(&self) -> bool {
+        false
+    }
+
     fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
         Ok(resp::Msg::Error(format!(
             "NOIMPL Command '{}' is not implmented",
@@ -89,6 +364,10 @@ impl Execute for Quit {
         std::u64::MAX
     }
 
+    fn writes(&self) -> bool {
+        false
+    }
+
     fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
         Err(Error::Quit)
     }
@@ -103,6 +382,8 @@ pub enum Error {
     Err(&'static str),
     Error(String),
     WrongType,
+    Oom,
+    BusyKey,
     Quit,
 }
 
@@ -113,6 +394,8 @@ impl fmt::Display for Error {
                 f,
                 "WRONGTYPE Operation against a key holding the wrong kind of value"
             ),
+            Error::Oom => write!(f, "OOM command not allowed when used memory > 'maxmemory'."),
+            Error::BusyKey => write!(f, "BUSYKEY Target key name already exists."),
             Error::Err(s) => write!(f, "ERR {}", s),
             Error::Error(s) => write!(f, "ERR {}", s),
             Error::Quit => write!(f, "QUIT"),
@@ -124,6 +407,8 @@ impl error::Error for Error {
     fn description(&self) -> &str {
         match self {
             Error::WrongType => "Operation against a key holding the wrong kind of value",
+            Error::Oom => "command not allowed when used memory > 'maxmemory'.",
+            Error::BusyKey => "Target key name already exists.",
             Error::Err(s) => s,
             Error::Error(s) => s.as_ref(),
             Error::Quit => "QUIT",
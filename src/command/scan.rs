@@ -0,0 +1,223 @@
+use std::str;
+
+use bytes::Bytes;
+
+use super::{database, database::SetValue, database::Value as DBValue, format_float, glob, resp, shard_for, Args, Command, Coordinate, Database, Error, Execute, Reply};
+
+const SCAN_DEFAULT_COUNT: usize = 10;
+
+// SCAN_MAX_COUNT caps COUNT, which is only a hint of the work to do per
+// call, so that a huge one isn't taken at its word.
+const SCAN_MAX_COUNT: i64 = 1 << 20;
+
+// Scan walks the keyspace one shard at a time. The cursor holds the shard
+// index in its top bits and the scan_hash to resume from in the remaining
+// database::SCAN_HASH_BITS, so it needs no server side state.
+pub struct Scan(u64, Option<Bytes>, usize, Option<Bytes>); // cursor, pattern, count, type
+pub struct Cscan(ScanKind, Bytes, u64, Option<Bytes>, usize); // kind, key, cursor, pattern, count
+
+#[derive(Clone, Copy)]
+pub enum ScanKind {
+    Set,
+    Hash,
+    SortedSet,
+}
+
+fn parse_cursor(args: &Args, i: usize) -> Result<u64, Error> {
+    match &args[i] {
+        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => {
+            match str::from_utf8(b.as_ref()).ok().and_then(|s| s.parse::<u64>().ok()) {
+                Some(cursor) => Ok(cursor),
+                None => Err(Error::Err("invalid cursor")),
+            }
+        }
+        resp::Msg::Int(n) if *n >= 0 => Ok(*n as u64),
+        _ => Err(Error::Err("invalid cursor")),
+    }
+}
+
+// parse_options parses the MATCH, COUNT and (if allowed) TYPE options
+// starting at i.
+fn parse_options(args: &mut Args, mut i: usize, allow_type: bool) -> Result<(Option<Bytes>, usize, Option<Bytes>), Error> {
+    let (mut pattern, mut count, mut kind) = (None, SCAN_DEFAULT_COUNT, None);
+    while i < args.len() {
+        if i + 1 >= args.len() {
+            return Err(Error::Err("syntax error"));
+        }
+        let opt = match args.own_bytes(i) {
+            Some(opt) => opt.to_ascii_uppercase(),
+            None => return Err(Error::Err("syntax error")),
+        };
+        match opt.as_slice() {
+            b"MATCH" => pattern = args.own_bytes(i + 1).filter(|p| p.as_ref() != b"*"),
+            b"COUNT" => match args.int(i + 1) {
+                Some(n) if n >= 1 => count = n.min(SCAN_MAX_COUNT) as usize,
+                Some(_) => return Err(Error::Err("syntax error")),
+                None => return Err(Error::Err("value is not an integer or out of range")),
+            },
+            b"TYPE" if allow_type => kind = args.own_bytes(i + 1),
+            _ => return Err(Error::Err("syntax error")),
+        }
+        i += 2;
+    }
+    Ok((pattern, count, kind))
+}
+
+fn reply(cursor: u64, items: Vec<resp::Msg>) -> resp::Msg {
+    resp::Msg::Array(Some(vec![
+        resp::Msg::BulkString(Some(Bytes::from(cursor.to_string()))),
+        resp::Msg::Array(Some(items)),
+    ]))
+}
+
+// scan_shard returns a page of the keys of db matching pattern and kind,
+// see Database::scan.
+fn scan_shard(db: &mut Database, from: u64, count: usize, pattern: Option<&[u8]>, kind: Option<&[u8]>) -> (Vec<resp::Msg>, Option<u64>) {
+    let (keys, next) = db.scan(from, count);
+    let keys = keys
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|p| glob::matches(p, key)))
+        // looking the key up also drops it if it has expired
+        .filter(|key| match db.get(key) {
+            Some(value) => kind.is_none_or(|k| k.eq_ignore_ascii_case(value.type_name().as_bytes())),
+            None => false,
+        })
+        .map(|key| resp::Msg::BulkString(Some(key)))
+        .collect();
+    (keys, next)
+}
+
+impl Execute for Scan {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 2 {
+            return Err(Error::Err("wrong number of arguments for 'scan' command"));
+        }
+        let cursor = parse_cursor(&args, 1)?;
+        let (pattern, count, kind) = parse_options(&mut args, 2, true)?;
+        Ok(Scan(cursor, pattern, count, kind))
+    }
+
+    fn shard(&self) -> u64 {
+        self.0 >> database::SCAN_HASH_BITS
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let from = self.0 & ((1 << database::SCAN_HASH_BITS) - 1);
+        let (keys, next) = scan_shard(db, from, self.2, self.1.as_ref().map(|p| p.as_ref()), self.3.as_ref().map(|k| k.as_ref()));
+        Ok(reply(next.map_or(0, |h| (self.shard() << database::SCAN_HASH_BITS) | h), keys))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Scan(self)
+    }
+}
+
+impl Coordinate for Scan {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let shard = self.shard();
+        let from = self.0 & ((1 << database::SCAN_HASH_BITS) - 1);
+        let count = self.2;
+        let pattern = self.1.clone();
+        let kind = self.3.clone();
+        Box::pin(async move {
+            let shards = database::shards() as u64;
+            if shard >= shards {
+                return Ok(reply(0, Vec::new()));
+            }
+            let _ticket = database::quiesce().await;
+            let (keys, next) = database::with_shard(&pool, shard, db, move |db| {
+                scan_shard(db, from, count, pattern.as_ref().map(|p| p.as_ref()), kind.as_ref().map(|k| k.as_ref()))
+            })
+            .await;
+            let cursor = match next {
+                Some(h) => (shard << database::SCAN_HASH_BITS) | h,
+                None if shard + 1 < shards => (shard + 1) << database::SCAN_HASH_BITS,
+                None => 0,
+            };
+            Ok(reply(cursor, keys))
+        })
+    }
+}
+
+impl Execute for Cscan {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let kind = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"SSCAN" => ScanKind::Set,
+                b"HSCAN" => ScanKind::Hash,
+                b"ZSCAN" => ScanKind::SortedSet,
+                _ => return Err(Error::Err("invalid name for 'sscan' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'sscan' command")),
+        };
+        if args.len() < 3 {
+            return Err(Error::Err("wrong number of arguments for 'sscan' command"));
+        }
+        let cursor = parse_cursor(&args, 2)?;
+        let (pattern, count, _) = parse_options(&mut args, 3, false)?;
+        match args.own_bytes(1) {
+            Some(key) => Ok(Cscan(kind, key, cursor, pattern, count)),
+            None => Err(Error::Err("invalid parameter for 'sscan' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.1)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let (cursor, count) = (self.2, self.4);
+        let matches = |member: &Bytes| self.3.as_ref().is_none_or(|p| glob::matches(p, member));
+        let mut items = Vec::new();
+        let next = match (self.0, db.get_indexed(&self.1)) {
+            (_, None) => 0,
+            (ScanKind::Set, Some(DBValue::Set(SetValue::Int(set)))) => {
+                // intsets are small enough to always be returned whole
+                for member in set.iter().map(|i| Bytes::from(i.to_string())).filter(|m| matches(m)) {
+                    items.push(resp::Msg::BulkString(Some(member)));
+                }
+                0
+            }
+            (ScanKind::Set, Some(DBValue::Set(SetValue::Hash(set)))) => {
+                let (page, next) = set.page(cursor, count);
+                for member in page.into_iter().filter(|m| matches(m)) {
+                    items.push(resp::Msg::BulkString(Some(member)));
+                }
+                next
+            }
+            (ScanKind::Hash, Some(DBValue::HashMap(hash))) => {
+                let (page, next) = hash.page(cursor, count);
+                for field in page.into_iter().filter(|f| matches(f)) {
+                    let value = hash.get(&field).map(|v| v.to_bytes());
+                    items.push(resp::Msg::BulkString(Some(field)));
+                    items.push(resp::Msg::BulkString(value));
+                }
+                next
+            }
+            (ScanKind::SortedSet, Some(DBValue::SortedSet(zset))) => {
+                let (page, next) = zset.page(cursor, count);
+                for member in page.into_iter().filter(|m| matches(m)) {
+                    let score = zset.score(&member).map(format_float);
+                    items.push(resp::Msg::BulkString(Some(member)));
+                    items.push(resp::Msg::BulkString(score));
+                }
+                next
+            }
+            (_, Some(_)) => return Err(Error::WrongType),
+        };
+        Ok(reply(next, items))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Cscan(self)
+    }
+}
@@ -0,0 +1,458 @@
+use bytes::Bytes;
+use log::error;
+
+use super::{aof, database, db_index, resp, shard_for, snapshot, Args, Command, Coordinate, Database, Error, Execute, Reply};
+
+pub struct Dbsize;
+pub struct Flush(bool, bool); // all, async
+pub struct Swapdb(usize, usize);
+pub struct MemoryUsage(Bytes, usize); // key, samples
+pub struct MemoryStats;
+pub struct Save(bool); // background
+pub struct Lastsave;
+pub struct Rewriteaof;
+
+// memory parses the MEMORY USAGE and MEMORY STATS subcommands.
+pub fn memory(args: Args) -> Result<Command, Error> {
+    if args.len() < 2 {
+        return Err(Error::Err("wrong number of arguments for 'memory' command"));
+    }
+    let usage = match &args[1] {
+        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"USAGE") => true,
+        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"STATS") => false,
+        _ => return Err(Error::Err("unknown subcommand or wrong number of arguments for 'memory' command")),
+    };
+    if usage {
+        MemoryUsage::new(args)
+    } else {
+        MemoryStats::new(args)
+    }
+}
+
+impl Execute for Dbsize {
+    fn parse(args: Args) -> Result<Self, Error> {
+        if args.len() != 1 {
+            return Err(Error::Err("wrong number of arguments for 'dbsize' command"));
+        }
+        Ok(Dbsize)
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(db.len() as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Dbsize(self)
+    }
+}
+
+impl Coordinate for Dbsize {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        Box::pin(async move {
+            let _ticket = database::quiesce().await;
+            let sizes = database::with_every_shard(&pool, db, |db| db.len()).await;
+            Ok(resp::Msg::Int(sizes.into_iter().sum::<usize>() as i64))
+        })
+    }
+}
+
+impl Execute for Flush {
+    fn parse(args: Args) -> Result<Self, Error> {
+        let all = match &args[0] {
+            resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => match b.as_ref() {
+                b"FLUSHDB" => false,
+                b"FLUSHALL" => true,
+                _ => return Err(Error::Err("invalid name for 'flush' command")),
+            },
+            _ => return Err(Error::Err("invalid parameter for 'flush' command")),
+        };
+        match args.len() {
+            1 => Ok(Flush(all, false)),
+            2 => match &args[1] {
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"ASYNC") => Ok(Flush(all, true)),
+                _ => Err(Error::Err("syntax error")),
+            },
+            _ => Err(Error::Err("wrong number of arguments for 'flushdb' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        db.flush(self.1);
+        Ok(resp::Msg::Str("OK"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Flush(self)
+    }
+}
+
+impl Flush {
+    // apply empties dbs, the databases of a shard, the way FLUSHALL or
+    // FLUSHDB with the database numbered db selected does.
+    pub fn apply(&self, dbs: &mut [Database], db: usize) {
+        if self.0 {
+            for db in dbs.iter_mut() {
+                db.flush(self.1);
+            }
+        } else {
+            dbs[db].flush(self.1);
+        }
+    }
+}
+
+impl Coordinate for Flush {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let (all, lazy) = (self.0, self.1);
+        Box::pin(async move {
+            // a value moved between shards meanwhile would outlive the flush
+            let _ticket = database::quiesce().await;
+            database::with_every_shard_dbs(&pool, move |dbs| {
+                Flush(all, lazy).apply(dbs, db);
+                aof::feed(db, &[if all { "FLUSHALL" } else { "FLUSHDB" }]);
+            })
+            .await;
+            Ok(resp::Msg::Str("OK"))
+        })
+    }
+}
+
+impl Execute for Swapdb {
+    fn parse(args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'swapdb' command"));
+        }
+        if args.int(1).is_none() {
+            return Err(Error::Err("invalid first DB index"));
+        }
+        if args.int(2).is_none() {
+            return Err(Error::Err("invalid second DB index"));
+        }
+        let (a, b) = (db_index(&args, 1)?, db_index(&args, 2)?);
+        Ok(Swapdb(a, b))
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    // SWAPDB needs every database of the shard, so it is always coordinated.
+    fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
+        Err(Error::Err("'swapdb' command can't run on a single database"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Swapdb(self)
+    }
+}
+
+impl Swapdb {
+    // apply swaps the two databases among dbs, the databases of a shard.
+    pub fn apply(&self, dbs: &mut [Database]) {
+        database::swap(dbs, self.0, self.1);
+    }
+}
+
+// SWAPDB swaps the two databases of every shard. Shards swap concurrently,
+// once no command holds keys locked across shards: such a command could
+// otherwise move a value into the other database. Other commands spanning
+// several shards may still run while only some of them have swapped.
+impl Coordinate for Swapdb {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let (a, b) = (self.0, self.1);
+        Box::pin(async move {
+            let _ticket = database::exclusive().await;
+            database::with_every_shard_dbs(&pool, move |dbs| {
+                Swapdb(a, b).apply(dbs);
+                aof::feed(db, &[b"SWAPDB".to_vec(), a.to_string().into_bytes(), b.to_string().into_bytes()]);
+            })
+            .await;
+            Ok(resp::Msg::Str("OK"))
+        })
+    }
+}
+
+impl Execute for MemoryUsage {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let samples = match args.len() {
+            3 => database::SIZE_SAMPLES,
+            5 => match &args[3] {
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"SAMPLES") => match args.int(4) {
+                    Some(n) if n >= 0 => n as usize,
+                    _ => return Err(Error::Err("value is not an integer or out of range")),
+                },
+                _ => return Err(Error::Err("syntax error")),
+            },
+            _ => return Err(Error::Err("wrong number of arguments for 'memory' command")),
+        };
+        match args.own_bytes(2) {
+            Some(key) => Ok(MemoryUsage(key, samples)),
+            None => Err(Error::Err("invalid parameter for 'memory' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        match db.usage(&self.0, self.1) {
+            Some(n) => Ok(resp::Msg::Int(n as i64)),
+            None => Ok(resp::Msg::BulkString(None)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::MemoryUsage(self)
+    }
+}
+
+impl Execute for MemoryStats {
+    fn parse(args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'memory' command"));
+        }
+        Ok(MemoryStats)
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    // MEMORY STATS always coordinates across every shard.
+    fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
+        Err(Error::Err("'memory stats' command can't run on a single database"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::MemoryStats(self)
+    }
+}
+
+fn stat(name: &'static str, n: usize) -> Vec<resp::Msg> {
+    vec![resp::Msg::Str(name), resp::Msg::Int(n as i64)]
+}
+
+// MEMORY STATS reports the totals first, then the keys and bytes of every
+// shard and of every non-empty database summed over the shards.
+impl Coordinate for MemoryStats {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, _db: usize) -> Reply {
+        let pool = pool.clone();
+        Box::pin(async move {
+            let shards = database::with_every_shard_dbs(&pool, |dbs| {
+                dbs.iter_mut()
+                    .map(|db| (db.len(), db.used(), db.volatile_len(), db.evicted()))
+                    .collect::<Vec<_>>()
+            })
+            .await;
+
+            let mut dbs = vec![(0, 0, 0); database::DATABASES];
+            let mut per_shard = Vec::with_capacity(shards.len());
+            let (mut keys, mut bytes, mut evicted) = (0, 0, 0);
+            for shard in &shards {
+                let (mut shard_keys, mut shard_bytes) = (0, 0);
+                for (i, (k, b, v, e)) in shard.iter().enumerate() {
+                    dbs[i].0 += k;
+                    dbs[i].1 += b;
+                    dbs[i].2 += v;
+                    shard_keys += k;
+                    shard_bytes += b;
+                    evicted += e;
+                }
+                keys += shard_keys;
+                bytes += shard_bytes;
+                per_shard.push((shard_keys, shard_bytes));
+            }
+
+            let mut reply = vec![];
+            reply.extend(stat("keys.count", keys));
+            reply.extend(stat("dataset.bytes", bytes));
+            reply.extend(stat("evicted.keys", evicted));
+            reply.extend(stat("maxmemory", database::maxmemory()));
+            reply.push(resp::Msg::Str("maxmemory.policy"));
+            reply.push(resp::Msg::Str(database::policy().name()));
+            for (i, (k, b)) in per_shard.into_iter().enumerate() {
+                reply.push(resp::Msg::String(Bytes::from(format!("shard.{}", i))));
+                let mut fields = stat("keys", k);
+                fields.extend(stat("bytes", b));
+                reply.push(resp::Msg::Array(Some(fields)));
+            }
+            for (i, (k, b, v)) in dbs.into_iter().enumerate().filter(|(_, db)| db.0 > 0) {
+                reply.push(resp::Msg::String(Bytes::from(format!("db.{}", i))));
+                let mut fields = stat("keys", k);
+                fields.extend(stat("expires", v));
+                fields.extend(stat("bytes", b));
+                reply.push(resp::Msg::Array(Some(fields)));
+            }
+            Ok(resp::Msg::Array(Some(reply)))
+        })
+    }
+}
+
+impl Execute for Save {
+    fn parse(args: Args) -> Result<Self, Error> {
+        if args.len() != 1 {
+            return Err(Error::Err("wrong number of arguments for 'save' command"));
+        }
+        match &args[0] {
+            resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => match b.as_ref() {
+                b"SAVE" => Ok(Save(false)),
+                b"BGSAVE" => Ok(Save(true)),
+                _ => Err(Error::Err("invalid name for 'save' command")),
+            },
+            _ => Err(Error::Err("invalid parameter for 'save' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    // SAVE always coordinates across every shard.
+    fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
+        Err(Error::Err("'save' command can't run on a single database"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Save(self)
+    }
+}
+
+// SAVE writes the snapshot and replies once it is on disk, while BGSAVE
+// replies right away and lets the shards write it between requests.
+impl Coordinate for Save {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, _db: usize) -> Reply {
+        let pool = pool.clone();
+        let background = self.0;
+        Box::pin(async move {
+            if !snapshot::start() {
+                return Err(Error::Err("Background save already in progress"));
+            }
+            if !background {
+                let saved = snapshot::save(&pool, false).await;
+                snapshot::finish();
+                return match saved {
+                    Ok(()) => Ok(resp::Msg::Str("OK")),
+                    Err(e) => Err(Error::Error(format!("error saving the snapshot: {}", e))),
+                };
+            }
+            let bg = pool.clone();
+            let _ = pool.spawn_on(0, async move {
+                if let Err(e) = snapshot::save(&bg, true).await {
+                    error!("Background saving failed: {}", e);
+                }
+                snapshot::finish();
+            });
+            Ok(resp::Msg::Str("Background saving started"))
+        })
+    }
+}
+
+impl Execute for Rewriteaof {
+    fn parse(args: Args) -> Result<Self, Error> {
+        if args.len() != 1 {
+            return Err(Error::Err("wrong number of arguments for 'bgrewriteaof' command"));
+        }
+        Ok(Rewriteaof)
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    // BGREWRITEAOF always coordinates across every shard.
+    fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
+        Err(Error::Err("'bgrewriteaof' command can't run on a single database"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Rewriteaof(self)
+    }
+}
+
+// BGREWRITEAOF rewrites the AOF in the background. It takes a snapshot of
+// the databases the way BGSAVE does, so it can't run along with a snapshot.
+impl Coordinate for Rewriteaof {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, _db: usize) -> Reply {
+        let pool = pool.clone();
+        Box::pin(async move {
+            if !aof::enabled() {
+                return Err(Error::Err("append only file is not enabled"));
+            }
+            if !snapshot::start() {
+                return Err(Error::Err("Background save or append only file rewrite already in progress"));
+            }
+            let bg = pool.clone();
+            let _ = pool.spawn_on(0, async move {
+                if let Err(e) = aof::rewrite(&bg).await {
+                    error!("Background append only file rewriting failed: {}", e);
+                }
+                snapshot::finish();
+            });
+            Ok(resp::Msg::Str("Background append only file rewriting started"))
+        })
+    }
+}
+
+impl Execute for Lastsave {
+    fn parse(args: Args) -> Result<Self, Error> {
+        if args.len() != 1 {
+            return Err(Error::Err("wrong number of arguments for 'lastsave' command"));
+        }
+        Ok(Lastsave)
+    }
+
+    fn shard(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, _db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(snapshot::lastsave() as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Lastsave(self)
+    }
+}
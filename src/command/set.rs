@@ -0,0 +1,600 @@
+use std::collections::HashSet;
+use std::hash::BuildHasherDefault;
+
+use bytes::Bytes;
+use rand::seq::index;
+use rand::Rng;
+use seahash::SeaHasher;
+
+use super::{aof, database, database::SetValue, database::Value as DBValue, fetch_all, fetch_then_store, one_worker, resp, shard_for, Args, Command, Coordinate, Database, Error, Execute, Reply};
+
+pub struct Sadd(Bytes, Vec<Bytes>);
+pub struct Srem(Bytes, Vec<Bytes>);
+pub struct Sismember(Bytes, Bytes);
+pub struct Scard(Bytes);
+pub struct Spop(Bytes, Option<usize>); // key, count
+pub struct Srandmember(Bytes, Option<i64>); // key, count (negative allows repeats)
+pub struct Smembers(Bytes);
+pub struct Smove(Bytes, Bytes, Bytes); // source, destination, member
+pub struct Setop(SetopKind, Option<Bytes>, Vec<Bytes>); // op, destination (for *STORE), keys
+
+// SRANDMEMBER_MAX_REPEATS bounds the number of members SRANDMEMBER returns
+// with a negative count, which may repeat members and so isn't bounded by
+// the size of the set.
+const SRANDMEMBER_MAX_REPEATS: i64 = 1 << 24;
+
+#[derive(Clone, Copy)]
+pub enum SetopKind {
+    Inter,
+    Union,
+    Diff,
+}
+
+pub fn get_set<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a mut SetValue>, Error> {
+    match db.get_mut(key) {
+        Some(DBValue::Set(s)) => Ok(Some(s)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+// read_set is like get_set, for commands that don't modify the set.
+pub fn read_set<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a SetValue>, Error> {
+    match db.get(key) {
+        Some(DBValue::Set(v)) => Ok(Some(v)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn get_or_create_set<'a>(db: &'a mut Database, key: &Bytes) -> Result<&'a mut SetValue, Error> {
+    if get_set(db, key)?.is_none() {
+        db.insert(key.clone(), DBValue::Set(SetValue::new()));
+    }
+    Ok(get_set(db, key)?.unwrap())
+}
+
+// own_members moves the string arguments from start onwards out of args.
+fn own_members(args: &mut Args, start: usize, name: &'static str) -> Result<Vec<Bytes>, Error> {
+    let mut members = Vec::with_capacity(args.len() - start);
+    for i in start..args.len() {
+        match args.own_bytes(i) {
+            Some(member) => members.push(member),
+            None => return Err(Error::Error(format!("invalid parameter for '{}' command", name))),
+        }
+    }
+    Ok(members)
+}
+
+fn add(db: &mut Database, key: &Bytes, members: &[Bytes]) -> Result<i64, Error> {
+    let set = get_or_create_set(db, key)?;
+    Ok(members.iter().filter(|m| set.insert((*m).clone())).count() as i64)
+}
+
+fn remove(db: &mut Database, key: &[u8], members: &[Bytes]) -> Result<i64, Error> {
+    let (removed, empty) = match get_set(db, key)? {
+        Some(set) => {
+            let removed = members.iter().filter(|m| set.remove(m)).count();
+            (removed as i64, set.len() == 0)
+        }
+        None => return Ok(0),
+    };
+    if empty {
+        db.remove(key);
+    }
+    Ok(removed)
+}
+
+// smove moves member from src to dst, both keys must be owned by the
+// current shard.
+fn smove(db: &mut Database, src: &Bytes, dst: &Bytes, member: &Bytes) -> Result<bool, Error> {
+    read_set(db, dst)?;
+    if remove(db, src, std::slice::from_ref(member))? == 0 {
+        return Ok(false);
+    }
+    add(db, dst, std::slice::from_ref(member))?;
+    Ok(true)
+}
+
+// sadd_logged adds member to the set at key and logs it to the AOF.
+fn sadd_logged(db: &mut Database, key: &Bytes, member: Bytes) -> Result<i64, Error> {
+    let added = add(db, key, std::slice::from_ref(&member))?;
+    aof::feed(db.number(), &[&b"SADD"[..], &key[..], &member[..]]);
+    Ok(added)
+}
+
+// members returns a copy of the members of the set at key.
+fn members(db: &mut Database, key: &[u8]) -> Result<Option<Vec<Bytes>>, Error> {
+    Ok(read_set(db, key)?.map(|s| s.members()))
+}
+
+// setop combines the members of sets, a missing key is treated as an empty
+// set.
+fn setop(kind: SetopKind, mut sets: Vec<Option<Vec<Bytes>>>) -> Vec<Bytes> {
+    type Members = HashSet<Bytes, BuildHasherDefault<SeaHasher>>;
+    match kind {
+        SetopKind::Inter => {
+            if sets.iter().any(|s| s.is_none()) {
+                return Vec::new();
+            }
+            let mut sets: Vec<Vec<Bytes>> = sets.into_iter().map(|s| s.unwrap()).collect();
+            // walk the smallest set and probe the others
+            sets.sort_by_key(|s| s.len());
+            let mut sets = sets.into_iter();
+            let smallest = sets.next().unwrap_or_default();
+            let others: Vec<Members> = sets.map(|s| s.into_iter().collect()).collect();
+            smallest.into_iter().filter(|m| others.iter().all(|o| o.contains(m))).collect()
+        }
+        SetopKind::Union => {
+            let mut seen = Members::default();
+            let mut union = Vec::new();
+            for m in sets.into_iter().flatten().flatten() {
+                if !seen.contains(&m) {
+                    seen.insert(m.clone());
+                    union.push(m);
+                }
+            }
+            union
+        }
+        SetopKind::Diff => {
+            let first = match sets.first_mut().and_then(|s| s.take()) {
+                Some(first) => first,
+                None => return Vec::new(),
+            };
+            let others: Members = sets.into_iter().skip(1).flatten().flatten().collect();
+            first.into_iter().filter(|m| !others.contains(m)).collect()
+        }
+    }
+}
+
+// store replaces the value at key with a set of members (or deletes it if
+// members is empty), returns the size of the new set.
+fn store(db: &mut Database, key: Bytes, members: Vec<Bytes>) -> i64 {
+    if members.is_empty() {
+        db.remove(&key);
+        return 0;
+    }
+    let mut set = SetValue::new();
+    for m in members.into_iter() {
+        set.insert(m);
+    }
+    let len = set.len() as i64;
+    db.set(key, DBValue::Set(set), None);
+    len
+}
+
+fn reply(members: Vec<Bytes>) -> resp::Msg {
+    resp::Msg::Array(Some(members.into_iter().map(|m| resp::Msg::BulkString(Some(m))).collect()))
+}
+
+impl Execute for Sadd {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 3 {
+            return Err(Error::Err("wrong number of arguments for 'sadd' command"));
+        }
+        let members = own_members(&mut args, 2, "sadd")?;
+        match args.own_bytes(1) {
+            Some(key) => Ok(Sadd(key, members)),
+            None => Err(Error::Err("invalid parameter for 'sadd' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(add(db, &self.0, &self.1)?))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Sadd(self)
+    }
+}
+
+impl Execute for Srem {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 3 {
+            return Err(Error::Err("wrong number of arguments for 'srem' command"));
+        }
+        let members = own_members(&mut args, 2, "srem")?;
+        match args.own_bytes(1) {
+            Some(key) => Ok(Srem(key, members)),
+            None => Err(Error::Err("invalid parameter for 'srem' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(remove(db, &self.0, &self.1)?))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Srem(self)
+    }
+}
+
+impl Execute for Sismember {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'sismember' command"));
+        }
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(member)) => Ok(Sismember(key, member)),
+            _ => Err(Error::Err("invalid parameter for 'sismember' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        match read_set(db, &self.0)? {
+            Some(set) if set.contains(&self.1) => Ok(resp::Msg::Int(1)),
+            _ => Ok(resp::Msg::Int(0)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Sismember(self)
+    }
+}
+
+impl Execute for Scard {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'scard' command"));
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Scard(key)),
+            None => Err(Error::Err("invalid parameter for 'scard' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(read_set(db, &self.0)?.map_or(0, |s| s.len() as i64)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Scard(self)
+    }
+}
+
+impl Execute for Spop {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let count = match args.len() {
+            2 => None,
+            3 => match args.int(2) {
+                Some(count) if count >= 0 => Some(count as usize),
+                Some(_) => return Err(Error::Err("value is out of range, must be positive")),
+                None => return Err(Error::Err("value is not an integer or out of range")),
+            },
+            _ => return Err(Error::Err("wrong number of arguments for 'spop' command")),
+        };
+        match args.own_bytes(1) {
+            Some(key) => Ok(Spop(key, count)),
+            None => Err(Error::Err("invalid parameter for 'spop' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let mut rng = rand::thread_rng();
+        let (popped, empty) = match get_set(db, &self.0)? {
+            Some(set) => {
+                let count = self.1.unwrap_or(1).min(set.len());
+                let mut popped = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let pos = rng.gen_range(0, set.len());
+                    popped.push(set.remove_at(pos));
+                }
+                (popped, set.len() == 0)
+            }
+            None => (Vec::new(), false),
+        };
+        if empty {
+            db.remove(&self.0);
+        }
+        match self.1 {
+            Some(_) => Ok(reply(popped)),
+            None => Ok(resp::Msg::BulkString(popped.into_iter().next())),
+        }
+    }
+
+    // the members popped at random are logged as removed by SREM
+    fn propagate(&self, _args: Vec<Bytes>, _db: &mut Database, reply: &resp::Msg) -> Vec<Vec<Bytes>> {
+        let popped = match reply {
+            resp::Msg::BulkString(Some(member)) => vec![member.clone()],
+            resp::Msg::Array(Some(members)) => members
+                .iter()
+                .filter_map(|m| match m {
+                    resp::Msg::BulkString(Some(m)) => Some(m.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        if popped.is_empty() {
+            return Vec::new();
+        }
+        let mut srem = vec![Bytes::from_static(b"SREM"), self.0.clone()];
+        srem.extend(popped);
+        vec![srem]
+    }
+
+    fn to_command(self) -> Command {
+        Command::Spop(self)
+    }
+}
+
+impl Execute for Srandmember {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let count = match args.len() {
+            2 => None,
+            3 => match args.int(2) {
+                Some(count) if count < -SRANDMEMBER_MAX_REPEATS => return Err(Error::Err("value is out of range")),
+                Some(count) => Some(count),
+                None => return Err(Error::Err("value is not an integer or out of range")),
+            },
+            _ => return Err(Error::Err("wrong number of arguments for 'srandmember' command")),
+        };
+        match args.own_bytes(1) {
+            Some(key) => Ok(Srandmember(key, count)),
+            None => Err(Error::Err("invalid parameter for 'srandmember' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let mut rng = rand::thread_rng();
+        let set = match read_set(db, &self.0)? {
+            Some(set) => set,
+            None => match self.1 {
+                Some(_) => return Ok(resp::Msg::Array(Some(Vec::new()))),
+                None => return Ok(resp::Msg::BulkString(None)),
+            },
+        };
+        let count = match self.1 {
+            Some(count) => count,
+            None => return Ok(resp::Msg::BulkString(Some(set.get(rng.gen_range(0, set.len()))))),
+        };
+        let members: Vec<Bytes> = if count >= 0 {
+            // distinct members, sampled without replacement
+            let amount = (count as usize).min(set.len());
+            index::sample(&mut rng, set.len(), amount)
+                .into_iter()
+                .map(|pos| set.get(pos))
+                .collect()
+        } else {
+            (0..count.unsigned_abs())
+                .map(|_| set.get(rng.gen_range(0, set.len())))
+                .collect()
+        };
+        Ok(reply(members))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Srandmember(self)
+    }
+}
+
+impl Execute for Smembers {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'smembers' command"));
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Smembers(key)),
+            None => Err(Error::Err("invalid parameter for 'smembers' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(reply(members(db, &self.0)?.unwrap_or_default()))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Smembers(self)
+    }
+}
+
+impl Execute for Smove {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'smove' command"));
+        }
+        match (args.own_bytes(1), args.own_bytes(2), args.own_bytes(3)) {
+            (Some(src), Some(dst), Some(member)) => Ok(Smove(src, dst, member)),
+            _ => Err(Error::Err("invalid parameter for 'smove' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(smove(db, &self.0, &self.1, &self.2)? as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Smove(self)
+    }
+}
+
+// When the two keys are owned by different workers SMOVE locks both of
+// them first, like RENAME, so that nobody sees the member in both sets or
+// in neither.
+impl Coordinate for Smove {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let src = self.0.clone();
+        let dst = self.1.clone();
+        let member = self.2.clone();
+        Box::pin(async move {
+            let hashes = vec![shard_for(&src), shard_for(&dst)];
+            if let Some(shard) = one_worker(&pool, &hashes) {
+                let moved = database::with_unlocked(&pool, shard, db, hashes, move |db| -> Result<bool, Error> {
+                    let moved = smove(db, &src, &dst, &member)?;
+                    if moved {
+                        aof::feed(db.number(), &[&b"SMOVE"[..], &src[..], &dst[..], &member[..]]);
+                    }
+                    Ok(moved)
+                })
+                .await?;
+                return Ok(resp::Msg::Int(moved as i64));
+            }
+
+            let locks = database::lock_keys(&pool, db, hashes).await;
+            let moved = move_member(&pool, db, src, dst, member).await;
+            database::unlock_keys(&pool, locks).await;
+            Ok(resp::Msg::Int(moved? as i64))
+        })
+    }
+}
+
+// move_member moves member from the set at src to the set at dst, owned by
+// different shards, both keys must be locked. Each shard logs its side of
+// the move.
+async fn move_member(pool: &tokio_io_pool::Handle, db: usize, src: Bytes, dst: Bytes, member: Bytes) -> Result<bool, Error> {
+    let d = dst.clone();
+    database::with_shard(pool, shard_for(&dst), db, move |db| read_set(db, &d).map(|_| ())).await?;
+    let (s, m) = (src.clone(), member.clone());
+    let removed = database::with_shard(pool, shard_for(&src), db, move |db| -> Result<i64, Error> {
+        let removed = remove(db, &s, std::slice::from_ref(&m))?;
+        if removed > 0 {
+            aof::feed(db.number(), &[&b"SREM"[..], &s[..], &m[..]]);
+        }
+        Ok(removed)
+    })
+    .await?;
+    if removed == 0 {
+        return Ok(false);
+    }
+    database::with_shard(pool, shard_for(&dst), db, move |db| sadd_logged(db, &dst, member)).await?;
+    Ok(true)
+}
+
+impl Execute for Setop {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let (kind, is_store, name) = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"SINTER" => (SetopKind::Inter, false, "sinter"),
+                b"SINTERSTORE" => (SetopKind::Inter, true, "sinterstore"),
+                b"SUNION" => (SetopKind::Union, false, "sunion"),
+                b"SUNIONSTORE" => (SetopKind::Union, true, "sunionstore"),
+                b"SDIFF" => (SetopKind::Diff, false, "sdiff"),
+                b"SDIFFSTORE" => (SetopKind::Diff, true, "sdiffstore"),
+                _ => return Err(Error::Err("invalid name for 'sinter' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'sinter' command")),
+        };
+        let start = if is_store { 2 } else { 1 };
+        if args.len() <= start {
+            return Err(Error::Error(format!("wrong number of arguments for '{}' command", name)));
+        }
+        let keys = own_members(&mut args, start, name)?;
+        let dst = if is_store {
+            match args.own_bytes(1) {
+                Some(dst) => Some(dst),
+                None => return Err(Error::Error(format!("invalid parameter for '{}' command", name))),
+            }
+        } else {
+            None
+        };
+        Ok(Setop(kind, dst, keys))
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(self.1.as_ref().unwrap_or(&self.2[0]))
+    }
+
+    // only the *STORE variants write
+    fn writes(&self) -> bool {
+        self.1.is_some()
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let mut sets = Vec::with_capacity(self.2.len());
+        for key in self.2.iter() {
+            sets.push(members(db, key)?);
+        }
+        let result = setop(self.0, sets);
+        match &self.1 {
+            Some(dst) => Ok(resp::Msg::Int(store(db, dst.clone(), result))),
+            None => Ok(reply(result)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Setop(self)
+    }
+}
+
+impl Coordinate for Setop {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let kind = self.0;
+        let dst = self.1.clone();
+        let keys = self.2.clone();
+        Box::pin(async move {
+            match dst {
+                Some(dst) => {
+                    let n = fetch_then_store(&pool, db, keys, dst, members, move |sets| setop(kind, sets), store).await?;
+                    Ok(resp::Msg::Int(n))
+                }
+                None => Ok(reply(setop(kind, fetch_all(&pool, db, keys, members).await?))),
+            }
+        })
+    }
+}
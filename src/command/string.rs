@@ -1,4 +1,5 @@
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::str;
 use std::time;
 
@@ -7,7 +8,9 @@ use seahash::SeaHasher;
 use log::debug;
 use byteorder::{BigEndian, WriteBytesExt};
 
-use super::{database, database::Scalar, database::Value as DBValue, resp, Args, Command, Database, Error, Execute};
+use futures::future;
+
+use super::{aof, database, database::Scalar, database::Value as DBValue, resp, shard_for, split_by_worker, Args, Command, Coordinate, Database, Error, Execute, Reply, Scatter};
 
 pub struct Get(Bytes); //
 pub struct Set(Bytes, Bytes, time::Duration, SetOpt);
@@ -42,6 +45,60 @@ pub enum BitfieldCommand {
 pub struct Incr(Bytes, i64); // key offset
 pub struct GetRange(Bytes, i64, i64); // key, start, end
 pub struct SetRange(Bytes, u64, Bytes); // key, offset, value
+pub struct Mget(Vec<Bytes>);
+pub struct Mset(Vec<(Bytes, Bytes)>);
+pub struct Msetnx(Vec<(Bytes, Bytes)>);
+
+// MAX_DEADLINE is the latest deadline a key can be given, in milliseconds
+// since the unix epoch: deadlines are compared with signed milliseconds.
+const MAX_DEADLINE: u64 = i64::MAX as u64;
+
+// ttl converts the time to live n given to SET EX/PX, SETEX or PSETEX, in
+// seconds or milliseconds, to a duration. It fails unless n is positive and
+// small enough for a deadline to hold it.
+fn ttl(n: Option<u64>, seconds: bool) -> Result<time::Duration, Error> {
+    let ms = match n {
+        Some(n) if n > 0 && seconds => n.checked_mul(1000),
+        Some(n) if n > 0 => Some(n),
+        _ => None,
+    };
+    match ms {
+        Some(ms) if ms <= MAX_DEADLINE => Ok(time::Duration::from_millis(ms)),
+        _ => Err(Error::Err("invalid expire time in 'set' command")),
+    }
+}
+
+// own_pairs moves the key value pairs from start onwards out of args.
+fn own_pairs(args: &mut Args, start: usize, name: &'static str) -> Result<Vec<(Bytes, Bytes)>, Error> {
+    if args.len() <= start || !(args.len() - start).is_multiple_of(2) {
+        return Err(Error::Error(format!("wrong number of arguments for '{}' command", name)));
+    }
+    let mut pairs = Vec::with_capacity((args.len() - start) / 2);
+    for i in (start..args.len()).step_by(2) {
+        match (args.own_bytes(i), args.own_bytes(i + 1)) {
+            (Some(key), Some(value)) => pairs.push((key, value)),
+            _ => return Err(Error::Error(format!("invalid parameter for '{}' command", name))),
+        }
+    }
+    Ok(pairs)
+}
+
+fn mset(db: &mut Database, pairs: &[(Bytes, Bytes)]) {
+    for (key, value) in pairs.iter() {
+        db.set(key.clone(), DBValue::Scalar(Scalar::String(value.clone())), None);
+    }
+}
+
+// feed_mset logs an MSET of pairs to the AOF. MSETNX is logged that way,
+// once it checked that none of its keys exists.
+fn feed_mset(db: &Database, pairs: &[(Bytes, Bytes)]) {
+    let mut args = vec![&b"MSET"[..]];
+    for (key, value) in pairs.iter() {
+        args.push(key);
+        args.push(value);
+    }
+    aof::feed(db.number(), &args);
+}
 
 impl Execute for Get {
     fn parse(mut args: Args) -> Result<Self, Error> {
@@ -61,6 +118,10 @@ impl Execute for Get {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        false
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
         match db.get(&self.0) {
             Some(val) => match val {
@@ -131,13 +192,7 @@ impl Execute for Set {
                                                     ))
                                                 }
                                             };
-                                        if let Ok(n) = n {
-                                            if name.as_ref() == b"EX" {
-                                                cmd.2 = time::Duration::from_secs(n)
-                                            } else {
-                                                cmd.2 = time::Duration::from_millis(n)
-                                            }
-                                        }
+                                        cmd.2 = ttl(n.ok(), name.as_ref() == b"EX" || name.as_ref() == b"ex")?;
                                         iter.next();
                                     } else {
                                         return Err(Error::Err("syntax error."));
@@ -176,13 +231,7 @@ impl Execute for Set {
                     resp::Msg::Int(n) => Ok(n as u64),
                     _ => return Err(Error::Err("invalid parameter for 'set' command")),
                 };
-                if let Ok(n) = n {
-                    if cmd_name.as_ref() == b"EX" {
-                        cmd.2 = time::Duration::from_secs(n)
-                    } else {
-                        cmd.2 = time::Duration::from_millis(n)
-                    }
-                }
+                cmd.2 = ttl(n.ok(), cmd_name.as_ref() == b"SETEX")?;
             }
             _ => return Err(Error::Err("invalid name for 'set' command")),
         }
@@ -195,6 +244,10 @@ impl Execute for Set {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        true
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
         match self.3 {
             SetOpt::NX => {
@@ -210,11 +263,33 @@ impl Execute for Set {
             _ => {}
         };
 
+        let expires_at = match self.2.as_millis() {
+            0 => None,
+            ttl => match (database::now_ms() as u128).checked_add(ttl) {
+                Some(t) if t <= MAX_DEADLINE as u128 => Some(t as u64),
+                _ => return Err(Error::Err("invalid expire time in 'set' command")),
+            },
+        };
         // db.insert(self.0.clone(), DBValue::Scalar(Scalar::String(Bytes::from(self.1.as_ref()))));
-        db.insert(self.0.clone(), DBValue::Scalar(Scalar::String(self.1.clone())));
+        db.set(self.0.clone(), DBValue::Scalar(Scalar::String(self.1.clone())), expires_at);
         Ok(resp::Msg::Str("OK"))
     }
 
+    // a relative deadline is logged as an absolute one, which replaying the
+    // AOF later can't push back
+    fn propagate(&self, args: Vec<Bytes>, db: &mut Database, reply: &resp::Msg) -> Vec<Vec<Bytes>> {
+        if let resp::Msg::BulkString(None) = reply {
+            return Vec::new();
+        }
+        match db.expires_at(&self.0) {
+            Some(Some(t)) => vec![
+                vec![Bytes::from_static(b"SET"), self.0.clone(), self.1.clone()],
+                aof::pexpireat(&self.0, t),
+            ],
+            _ => vec![args],
+        }
+    }
+
     fn to_command(self) -> Command {
         Command::Set(self)
     }
@@ -244,6 +319,10 @@ impl Execute for Append {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        true
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
         let s = match db.get(&self.0) {
             Some(val) => match val {
@@ -289,6 +368,10 @@ impl Execute for Strlen {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        false
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
         match db.get(&self.0) {
             Some(val) => match val {
@@ -355,11 +438,15 @@ impl Execute for Setbit {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        true
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
         let byte_offset = (self.1 / 8) as usize;
         let bit_offset = (self.1 % 8) as usize;
-        match db.remove_entry(&self.0) {
-            Some((k, val)) => {
+        match db.get_mut(&self.0) {
+            Some(val) => {
                 let mut buff = match val {
                     DBValue::Scalar(Scalar::String(s)) => {
                         // Try to grab a mutable handle to the buffer. try_mut
@@ -368,7 +455,9 @@ impl Execute for Setbit {
                         // the handle in the db is a reference to a slice
                         // that was allocated from the network request (ex.
                         // a SET command).
-                        s.try_mut().unwrap_or_else(|s| BytesMut::from(&s[..]))
+                        mem::replace(s, Bytes::new())
+                            .try_mut()
+                            .unwrap_or_else(|s| BytesMut::from(&s[..]))
                     }
                     DBValue::Scalar(Scalar::Integer(i)) => BytesMut::from(i.to_string()),
                     _ => return Err(Error::WrongType),
@@ -388,7 +477,7 @@ impl Execute for Setbit {
                 } else {
                     buff[byte_offset] &= !(1 << bit_offset)
                 }
-                db.insert(k, DBValue::Scalar(Scalar::String(buff.freeze())));
+                *val = DBValue::Scalar(Scalar::String(buff.freeze()));
                 Ok(resp::Msg::Int(curr_value as i64))
             }
             None => {
@@ -448,6 +537,10 @@ impl Execute for Getbit {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        false
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
         let byte_offset = (self.1 / 8) as usize;
         let bit_offset = (self.1 % 8) as usize;
@@ -699,23 +792,28 @@ impl Execute for Bitfield {
         hasher.finish()
     }
 
+    // a BITFIELD made of GET subcommands only reads
+    fn writes(&self) -> bool {
+        self.1.iter().any(|c| matches!(c, BitfieldCommand::Set(..) | BitfieldCommand::IncrBy(..)))
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
         let mut tbuf = [0 as u8; 8];
         // let mut stored_buffer = None;
         // let mut new_stored_buffer = BytesMut::with_capacity(0);
         let mut has_stored = false;
 
-        let mut arr = match db.remove_entry(&self.0) {
-            Some((k, val)) => match val {
+        let mut arr = match db.get_mut(&self.0) {
+            Some(val) => match val {
                 DBValue::Scalar(Scalar::String(s)) => {
                     //let mut s2 = s.try_mut().unwrap();
                     // stored_buffer = Some(BytesMut::with_capacity(0));
                     has_stored = true;
-                    (k, s.try_mut().unwrap_or_else(|s| BytesMut::from(&s[..])))
+                    (self.0.clone(), mem::replace(s, Bytes::new()).try_mut().unwrap_or_else(|s| BytesMut::from(&s[..])))
                 },
                 DBValue::Scalar(Scalar::Integer(i)) => {
-                    (&mut tbuf as &mut[u8]).write_i64::<BigEndian>(i as i64).unwrap();
-                    (k, BytesMut::from(&mut tbuf as &[u8]))
+                    (&mut tbuf as &mut[u8]).write_i64::<BigEndian>(*i).unwrap();
+                    (self.0.clone(), BytesMut::from(&mut tbuf as &[u8]))
                 },
                 _ => return Err(Error::WrongType),
             },
@@ -925,10 +1023,14 @@ impl Execute for Incr {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        true
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
-        match db.remove_entry(&self.0) {
-            Some((k, val)) => {
-                let val = match val {
+        match db.get_mut(&self.0) {
+            Some(val) => {
+                let i = match val {
                     DBValue::Scalar(Scalar::String(s)) => match str::from_utf8(s.as_ref()) {
                         Ok(k) => if let Ok(i) = k.parse::<i64>() {
                             i
@@ -937,12 +1039,12 @@ impl Execute for Incr {
                         },
                         Err(_) => return Err(Error::WrongType),
                     },
-                    DBValue::Scalar(Scalar::Integer(i)) => i,
+                    DBValue::Scalar(Scalar::Integer(i)) => *i,
                     _ => return Err(Error::WrongType),
                 };
-                let new_val = val + self.1;
+                let new_val = i + self.1;
 
-                db.insert(k, DBValue::Scalar(Scalar::Integer(new_val)));
+                *val = DBValue::Scalar(Scalar::Integer(new_val));
                 Ok(resp::Msg::Int(new_val))
             }
             None => {
@@ -1003,6 +1105,10 @@ impl Execute for GetRange {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        false
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
         let haystack = match db.get(&self.0) {
             Some(val) => match val {
@@ -1082,14 +1188,18 @@ impl Execute for SetRange {
         hasher.finish()
     }
 
+    fn writes(&self) -> bool {
+        true
+    }
+
     fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
-        let (key, haystack) = match db.remove_entry(&self.0) {
-            Some((k, val)) => match val {
+        let (key, haystack) = match db.get_mut(&self.0) {
+            Some(val) => match val {
                 DBValue::Scalar(Scalar::String(s)) => {
-                    (k, s)
+                    (self.0.clone(), mem::replace(s, Bytes::new()))
                 },
                 DBValue::Scalar(Scalar::Integer(i)) => {
-                    (k, Bytes::from(i.to_string()))
+                    (self.0.clone(), Bytes::from(i.to_string()))
                 },
                 _ => return Err(Error::WrongType),
             },
@@ -1114,4 +1224,177 @@ impl Execute for SetRange {
     fn to_command(self) -> Command {
         Command::SetRange(self)
     }
-}
\ No newline at end of file
+}
+impl Execute for Mget {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 2 {
+            return Err(Error::Err("wrong number of arguments for 'mget' command"));
+        }
+        let mut keys = Vec::with_capacity(args.len() - 1);
+        for i in 1..args.len() {
+            match args.own_bytes(i) {
+                Some(key) => keys.push(key),
+                None => return Err(Error::Err("invalid parameter for 'mget' command")),
+            }
+        }
+        Ok(Mget(keys))
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0[0])
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Array(Some(
+            self.0
+                .iter()
+                .map(|key| match db.get(key) {
+                    Some(DBValue::Scalar(s)) => resp::Msg::BulkString(Some(s.to_bytes())),
+                    _ => resp::Msg::BulkString(None),
+                })
+                .collect(),
+        )))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Mget(self)
+    }
+}
+
+impl Scatter for Mget {
+    fn keys(&self) -> Vec<&[u8]> {
+        self.0.iter().map(|key| key.as_ref()).collect()
+    }
+
+    fn part(&self, positions: &[usize]) -> Box<dyn Execute> {
+        Box::new(Mget(positions.iter().map(|&i| self.0[i].clone()).collect()))
+    }
+
+    fn gather(&self, replies: Vec<(Vec<usize>, Result<resp::Msg, Error>)>) -> Result<resp::Msg, Error> {
+        let mut values: Vec<resp::Msg> = self.0.iter().map(|_| resp::Msg::BulkString(None)).collect();
+        for (positions, reply) in replies.into_iter() {
+            if let resp::Msg::Array(Some(part)) = reply? {
+                for (i, value) in positions.into_iter().zip(part) {
+                    values[i] = value;
+                }
+            }
+        }
+        Ok(resp::Msg::Array(Some(values)))
+    }
+}
+
+impl Execute for Mset {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        Ok(Mset(own_pairs(&mut args, 1, "mset")?))
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0[0].0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        mset(db, &self.0);
+        Ok(resp::Msg::Str("OK"))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Mset(self)
+    }
+}
+
+impl Scatter for Mset {
+    fn keys(&self) -> Vec<&[u8]> {
+        self.0.iter().map(|(key, _)| key.as_ref()).collect()
+    }
+
+    fn part(&self, positions: &[usize]) -> Box<dyn Execute> {
+        Box::new(Mset(positions.iter().map(|&i| self.0[i].clone()).collect()))
+    }
+
+    fn gather(&self, replies: Vec<(Vec<usize>, Result<resp::Msg, Error>)>) -> Result<resp::Msg, Error> {
+        for (_, reply) in replies.into_iter() {
+            reply?;
+        }
+        Ok(resp::Msg::Str("OK"))
+    }
+}
+
+impl Execute for Msetnx {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        Ok(Msetnx(own_pairs(&mut args, 1, "msetnx")?))
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0[0].0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        if self.0.iter().any(|(key, _)| db.contains_key(key)) {
+            return Ok(resp::Msg::Int(0));
+        }
+        mset(db, &self.0);
+        Ok(resp::Msg::Int(1))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Msetnx(self)
+    }
+}
+
+// When its keys span several workers MSETNX locks all of them first, like
+// RENAME. Every shard then checks its keys, and if none of them exists
+// every shard writes its own, before the keys are unlocked: nobody can see
+// or write some of the keys in between.
+impl Coordinate for Msetnx {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let pairs = self.0.clone();
+        Box::pin(async move {
+            let hashes: Vec<u64> = pairs.iter().map(|(key, _)| shard_for(key)).collect();
+            let parts = split_by_worker(&pool, pairs, |(key, _)| shard_for(key));
+            if parts.len() == 1 {
+                let (shard, pairs) = parts.into_iter().next().unwrap();
+                return database::with_unlocked(&pool, shard, db, hashes, move |db| {
+                    let msetnx = Msetnx(pairs);
+                    let reply = msetnx.exec(db)?;
+                    if let resp::Msg::Int(1) = reply {
+                        feed_mset(db, &msetnx.0);
+                    }
+                    Ok(reply)
+                })
+                .await;
+            }
+
+            let locks = database::lock_keys(&pool, db, hashes).await;
+            let exists = future::join_all(parts.iter().map(|(shard, pairs)| {
+                let keys: Vec<Bytes> = pairs.iter().map(|(key, _)| key.clone()).collect();
+                database::with_shard(&pool, *shard, db, move |db| keys.iter().any(|key| db.contains_key(key)))
+            }))
+            .await;
+            let set = !exists.into_iter().any(|exists| exists);
+            if set {
+                future::join_all(parts.into_iter().map(|(shard, pairs)| {
+                    database::with_shard(&pool, shard, db, move |db| {
+                        mset(db, &pairs);
+                        feed_mset(db, &pairs);
+                    })
+                }))
+                .await;
+            }
+            database::unlock_keys(&pool, locks).await;
+            Ok(resp::Msg::Int(set as i64))
+        })
+    }
+}
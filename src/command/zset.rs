@@ -0,0 +1,1017 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::str;
+
+use bytes::Bytes;
+use seahash::SeaHasher;
+
+use super::{database::SortedSetValue, database::Value as DBValue, fetch_then_store, format_float, range, resp, shard_for, Args, Command, Coordinate, Database, Error, Execute, Reply};
+use crate::skiplist::{SkipList, NIL};
+
+pub struct Zadd(Bytes, Vec<(f64, Bytes)>, ZaddOpts); // key, (score, member) pairs, flags
+pub struct Zrange(Bytes, i64, i64, bool, bool); // key, start, stop, with scores, reverse
+pub struct Zrank(Bytes, Bytes, bool); // key, member, reverse
+pub struct Zscore(Bytes, Bytes);
+pub struct Zrem(Bytes, Vec<Bytes>);
+pub struct Zcard(Bytes);
+pub struct Zcount(Bytes, Range);
+pub struct Zrangeby(Bytes, Range, bool, bool, Option<(i64, i64)>); // key, range, reverse, with scores, limit
+pub struct Zremrange(Bytes, Removal);
+pub struct Zpop(Bytes, i64, bool); // key, count, max
+pub struct Zstore(bool, Bytes, Vec<Bytes>, Vec<f64>, Aggregate); // intersect, destination, keys, weights, aggregate
+
+#[derive(Clone, Copy)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, redis treats it as zero
+            Aggregate::Sum => zero_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+pub enum Removal {
+    Rank(i64, i64),
+    Range(Range),
+}
+
+#[derive(Default)]
+pub struct ZaddOpts {
+    nx: bool,
+    xx: bool,
+    ch: bool,
+    incr: bool,
+}
+
+// ScoreRange is a [min, max] score interval, either end may be exclusive.
+pub struct ScoreRange {
+    min: f64,
+    max: f64,
+    min_ex: bool,
+    max_ex: bool,
+}
+
+impl ScoreRange {
+    fn parse(args: &Args, i: usize) -> Result<ScoreRange, Error> {
+        match (parse_score_bound(args, i), parse_score_bound(args, i + 1)) {
+            (Some((min, min_ex)), Some((max, max_ex))) => Ok(ScoreRange { min, max, min_ex, max_ex }),
+            _ => Err(Error::Err("min or max is not a float")),
+        }
+    }
+
+    pub fn above_min(&self, score: f64) -> bool {
+        if self.min_ex {
+            score > self.min
+        } else {
+            score >= self.min
+        }
+    }
+
+    pub fn below_max(&self, score: f64) -> bool {
+        if self.max_ex {
+            score < self.max
+        } else {
+            score <= self.max
+        }
+    }
+}
+
+// LexRange is a [min, max] interval of members, used when all the members
+// have the same score so they are ordered lexicographically.
+pub struct LexRange {
+    min: LexBound,
+    max: LexBound,
+}
+
+enum LexBound {
+    Min, // -
+    Max, // +
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+impl LexRange {
+    fn parse(args: &Args, i: usize) -> Result<LexRange, Error> {
+        match (parse_lex_bound(args, i), parse_lex_bound(args, i + 1)) {
+            (Some(min), Some(max)) => Ok(LexRange { min, max }),
+            _ => Err(Error::Err("min or max not valid string range item")),
+        }
+    }
+
+    pub fn above_min(&self, member: &[u8]) -> bool {
+        match &self.min {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(b) => member >= b.as_ref(),
+            LexBound::Exclusive(b) => member > b.as_ref(),
+        }
+    }
+
+    pub fn below_max(&self, member: &[u8]) -> bool {
+        match &self.max {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(b) => member <= b.as_ref(),
+            LexBound::Exclusive(b) => member < b.as_ref(),
+        }
+    }
+}
+
+fn parse_lex_bound(args: &Args, i: usize) -> Option<LexBound> {
+    match &args[i] {
+        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => match b.first() {
+            Some(b'-') if b.len() == 1 => Some(LexBound::Min),
+            Some(b'+') if b.len() == 1 => Some(LexBound::Max),
+            Some(b'[') => Some(LexBound::Inclusive(b.slice_from(1))),
+            Some(b'(') => Some(LexBound::Exclusive(b.slice_from(1))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub enum Range {
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+impl Range {
+    fn parse(args: &Args, i: usize, lex: bool) -> Result<Range, Error> {
+        if lex {
+            Ok(Range::Lex(LexRange::parse(args, i)?))
+        } else {
+            Ok(Range::Score(ScoreRange::parse(args, i)?))
+        }
+    }
+
+    fn above_min(&self, score: f64, member: &[u8]) -> bool {
+        match self {
+            Range::Score(r) => r.above_min(score),
+            Range::Lex(r) => r.above_min(member),
+        }
+    }
+
+    fn below_max(&self, score: f64, member: &[u8]) -> bool {
+        match self {
+            Range::Score(r) => r.below_max(score),
+            Range::Lex(r) => r.below_max(member),
+        }
+    }
+}
+
+// parse_score_bound parses a score range bound, a leading '(' makes it
+// exclusive.
+fn parse_score_bound(args: &Args, i: usize) -> Option<(f64, bool)> {
+    match &args[i] {
+        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => {
+            let (b, ex) = match b.first() {
+                Some(b'(') => (&b[1..], true),
+                _ => (&b[..], false),
+            };
+            str::from_utf8(b)
+                .ok()
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|f| !f.is_nan())
+                .map(|f| (f, ex))
+        }
+        resp::Msg::Int(n) => Some((*n as f64, false)),
+        _ => None,
+    }
+}
+
+fn zero_nan(f: f64) -> f64 {
+    if f.is_nan() {
+        0.0
+    } else {
+        f
+    }
+}
+
+pub fn get_zset<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a mut SortedSetValue>, Error> {
+    match db.get_mut(key) {
+        Some(DBValue::SortedSet(z)) => Ok(Some(z)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+// read_zset is like get_zset, for commands that don't modify the sorted set.
+pub fn read_zset<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a SortedSetValue>, Error> {
+    match db.get(key) {
+        Some(DBValue::SortedSet(v)) => Ok(Some(v)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn get_or_create_zset<'a>(db: &'a mut Database, key: &Bytes) -> Result<&'a mut SortedSetValue, Error> {
+    if get_zset(db, key)?.is_none() {
+        db.insert(key.clone(), DBValue::SortedSet(SortedSetValue::default()));
+    }
+    Ok(get_zset(db, key)?.unwrap())
+}
+
+// reply_range replies with up to count members starting at node n, walking
+// towards higher scores (or lower ones if rev is set) for as long as within
+// holds.
+fn reply_range<F>(list: &SkipList, mut n: usize, count: usize, rev: bool, with_scores: bool, within: F) -> resp::Msg
+where
+    F: Fn(f64, &[u8]) -> bool,
+{
+    let mut reply = Vec::new();
+    for _ in 0..count {
+        if n == NIL || !within(list.score(n), list.member(n)) {
+            break;
+        }
+        reply.push(resp::Msg::BulkString(Some(list.member(n).clone())));
+        if with_scores {
+            reply.push(resp::Msg::BulkString(Some(format_float(list.score(n)))));
+        }
+        n = if rev { list.prev(n) } else { list.next(n) };
+    }
+    resp::Msg::Array(Some(reply))
+}
+
+impl Execute for Zadd {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let mut opts = ZaddOpts::default();
+        let mut i = 2;
+        match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"ZADD" => {
+                    while i < args.len() {
+                        match &args[i] {
+                            resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => match b.as_ref() {
+                                b"NX" | b"nx" => opts.nx = true,
+                                b"XX" | b"xx" => opts.xx = true,
+                                b"CH" | b"ch" => opts.ch = true,
+                                b"INCR" | b"incr" => opts.incr = true,
+                                _ => break,
+                            },
+                            _ => break,
+                        }
+                        i += 1;
+                    }
+                }
+                b"ZINCRBY" => {
+                    if args.len() != 4 {
+                        return Err(Error::Err("wrong number of arguments for 'zincrby' command"));
+                    }
+                    opts.incr = true;
+                }
+                _ => return Err(Error::Err("invalid name for 'zadd' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'zadd' command")),
+        };
+        if args.len() < 4 {
+            return Err(Error::Err("wrong number of arguments for 'zadd' command"));
+        }
+        if !(args.len() - i).is_multiple_of(2) || i == args.len() {
+            return Err(Error::Err("syntax error"));
+        }
+        if opts.nx && opts.xx {
+            return Err(Error::Err("XX and NX options at the same time are not compatible"));
+        }
+        if opts.incr && args.len() - i != 2 {
+            return Err(Error::Err("INCR option supports a single increment-element pair"));
+        }
+        let mut pairs = Vec::with_capacity((args.len() - i) / 2);
+        for i in (i..args.len()).step_by(2) {
+            let score = match args.float(i) {
+                Some(score) => score,
+                None => return Err(Error::Err("value is not a valid float")),
+            };
+            match args.own_bytes(i + 1) {
+                Some(member) => pairs.push((score, member)),
+                None => return Err(Error::Err("invalid parameter for 'zadd' command")),
+            }
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Zadd(key, pairs, opts)),
+            None => Err(Error::Err("invalid parameter for 'zadd' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let opts = &self.2;
+        let zset = if opts.xx {
+            match get_zset(db, &self.0)? {
+                Some(zset) => zset,
+                None if opts.incr => return Ok(resp::Msg::BulkString(None)),
+                None => return Ok(resp::Msg::Int(0)),
+            }
+        } else {
+            get_or_create_zset(db, &self.0)?
+        };
+        let (mut added, mut changed) = (0, 0);
+        let mut result = None;
+        for (score, member) in self.1.iter() {
+            match zset.score(member) {
+                Some(old) => {
+                    if opts.nx {
+                        continue;
+                    }
+                    let score = if opts.incr { old + score } else { *score };
+                    if score.is_nan() {
+                        return Err(Error::Err("resulting score is not a number (NaN)"));
+                    }
+                    if score != old {
+                        zset.insert(member.clone(), score);
+                        changed += 1;
+                    }
+                    result = Some(score);
+                }
+                None => {
+                    if opts.xx {
+                        continue;
+                    }
+                    zset.insert(member.clone(), *score);
+                    added += 1;
+                    changed += 1;
+                    result = Some(*score);
+                }
+            }
+        }
+        if opts.incr {
+            Ok(resp::Msg::BulkString(result.map(format_float)))
+        } else if opts.ch {
+            Ok(resp::Msg::Int(changed))
+        } else {
+            Ok(resp::Msg::Int(added))
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zadd(self)
+    }
+}
+
+impl Execute for Zrange {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let rev = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"ZRANGE" => false,
+                b"ZREVRANGE" => true,
+                _ => return Err(Error::Err("invalid name for 'zrange' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'zrange' command")),
+        };
+        let with_scores = match args.len() {
+            4 => false,
+            5 => match &args[4] {
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"WITHSCORES") => true,
+                _ => return Err(Error::Err("syntax error")),
+            },
+            _ => return Err(Error::Err("wrong number of arguments for 'zrange' command")),
+        };
+        let (start, stop) = match (args.int(2), args.int(3)) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        match args.own_bytes(1) {
+            Some(key) => Ok(Zrange(key, start, stop, with_scores, rev)),
+            None => Err(Error::Err("invalid parameter for 'zrange' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let list = match read_zset(db, &self.0)? {
+            Some(zset) => zset.list(),
+            None => return Ok(resp::Msg::Array(Some(Vec::new()))),
+        };
+        let (start, end) = match range(self.1, self.2, list.len()) {
+            Some(r) => r,
+            None => return Ok(resp::Msg::Array(Some(Vec::new()))),
+        };
+        let n = if self.4 { list.by_rank(list.len() - 1 - start) } else { list.by_rank(start) };
+        Ok(reply_range(list, n, end - start, self.4, self.3, |_, _| true))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zrange(self)
+    }
+}
+
+impl Execute for Zrank {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let rev = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"ZRANK" => false,
+                b"ZREVRANK" => true,
+                _ => return Err(Error::Err("invalid name for 'zrank' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'zrank' command")),
+        };
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'zrank' command"));
+        }
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(member)) => Ok(Zrank(key, member, rev)),
+            _ => Err(Error::Err("invalid parameter for 'zrank' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let zset = match read_zset(db, &self.0)? {
+            Some(zset) => zset,
+            None => return Ok(resp::Msg::BulkString(None)),
+        };
+        match zset.rank(&self.1) {
+            Some(rank) if self.2 => Ok(resp::Msg::Int((zset.len() - 1 - rank) as i64)),
+            Some(rank) => Ok(resp::Msg::Int(rank as i64)),
+            None => Ok(resp::Msg::BulkString(None)),
+        }
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zrank(self)
+    }
+}
+
+impl Execute for Zscore {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 3 {
+            return Err(Error::Err("wrong number of arguments for 'zscore' command"));
+        }
+        match (args.own_bytes(1), args.own_bytes(2)) {
+            (Some(key), Some(member)) => Ok(Zscore(key, member)),
+            _ => Err(Error::Err("invalid parameter for 'zscore' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let score = read_zset(db, &self.0)?.and_then(|z| z.score(&self.1));
+        Ok(resp::Msg::BulkString(score.map(format_float)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zscore(self)
+    }
+}
+
+impl Execute for Zrem {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() < 3 {
+            return Err(Error::Err("wrong number of arguments for 'zrem' command"));
+        }
+        let mut members = Vec::with_capacity(args.len() - 2);
+        for i in 2..args.len() {
+            match args.own_bytes(i) {
+                Some(member) => members.push(member),
+                None => return Err(Error::Err("invalid parameter for 'zrem' command")),
+            }
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Zrem(key, members)),
+            None => Err(Error::Err("invalid parameter for 'zrem' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let (removed, empty) = match get_zset(db, &self.0)? {
+            Some(zset) => {
+                let removed = self.1.iter().filter(|m| zset.remove(m)).count();
+                (removed as i64, zset.len() == 0)
+            }
+            None => return Ok(resp::Msg::Int(0)),
+        };
+        if empty {
+            db.remove(&self.0);
+        }
+        Ok(resp::Msg::Int(removed))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zrem(self)
+    }
+}
+
+impl Execute for Zcard {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        if args.len() != 2 {
+            return Err(Error::Err("wrong number of arguments for 'zcard' command"));
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Zcard(key)),
+            None => Err(Error::Err("invalid parameter for 'zcard' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        Ok(resp::Msg::Int(read_zset(db, &self.0)?.map_or(0, |z| z.len() as i64)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zcard(self)
+    }
+}
+
+impl Execute for Zcount {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let lex = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"ZCOUNT" => false,
+                b"ZLEXCOUNT" => true,
+                _ => return Err(Error::Err("invalid name for 'zcount' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'zcount' command")),
+        };
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'zcount' command"));
+        }
+        let range = Range::parse(&args, 2, lex)?;
+        match args.own_bytes(1) {
+            Some(key) => Ok(Zcount(key, range)),
+            None => Err(Error::Err("invalid parameter for 'zcount' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let list = match read_zset(db, &self.0)? {
+            Some(zset) => zset.list(),
+            None => return Ok(resp::Msg::Int(0)),
+        };
+        let range = &self.1;
+        let (first, first_rank) = list.first_after(|score, member, _| !range.above_min(score, member));
+        let (last, last_rank) = list.last_before(|score, member, _| range.below_max(score, member));
+        if first == NIL || last == NIL || first_rank > last_rank {
+            return Ok(resp::Msg::Int(0));
+        }
+        Ok(resp::Msg::Int((last_rank - first_rank + 1) as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zcount(self)
+    }
+}
+
+impl Execute for Zrangeby {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let (rev, lex) = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"ZRANGEBYSCORE" => (false, false),
+                b"ZREVRANGEBYSCORE" => (true, false),
+                b"ZRANGEBYLEX" => (false, true),
+                b"ZREVRANGEBYLEX" => (true, true),
+                _ => return Err(Error::Err("invalid name for 'zrangebyscore' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'zrangebyscore' command")),
+        };
+        if args.len() < 4 {
+            return Err(Error::Err("wrong number of arguments for 'zrangebyscore' command"));
+        }
+        // the reverse variants take the bounds as max min
+        let range = if rev {
+            match Range::parse(&args, 2, lex)? {
+                Range::Score(r) => Range::Score(ScoreRange {
+                    min: r.max,
+                    max: r.min,
+                    min_ex: r.max_ex,
+                    max_ex: r.min_ex,
+                }),
+                Range::Lex(r) => Range::Lex(LexRange { min: r.max, max: r.min }),
+            }
+        } else {
+            Range::parse(&args, 2, lex)?
+        };
+        let (mut with_scores, mut limit) = (false, None);
+        let mut i = 4;
+        while i < args.len() {
+            match &args[i] {
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if !lex && b.eq_ignore_ascii_case(b"WITHSCORES") => {
+                    with_scores = true;
+                    i += 1;
+                }
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"LIMIT") && i + 2 < args.len() => {
+                    match (args.int(i + 1), args.int(i + 2)) {
+                        (Some(offset), Some(count)) => limit = Some((offset, count)),
+                        _ => return Err(Error::Err("value is not an integer or out of range")),
+                    }
+                    i += 3;
+                }
+                _ => return Err(Error::Err("syntax error")),
+            }
+        }
+        match args.own_bytes(1) {
+            Some(key) => Ok(Zrangeby(key, range, rev, with_scores, limit)),
+            None => Err(Error::Err("invalid parameter for 'zrangebyscore' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        false
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let list = match read_zset(db, &self.0)? {
+            Some(zset) => zset.list(),
+            None => return Ok(resp::Msg::Array(Some(Vec::new()))),
+        };
+        let (range, rev) = (&self.1, self.2);
+        let (offset, count) = self.4.unwrap_or((0, -1));
+        if offset < 0 {
+            return Ok(resp::Msg::Array(Some(Vec::new())));
+        }
+        let offset = offset as usize;
+        // find the first node of the range, then skip offset nodes by rank
+        let n = if rev {
+            let (last, rank) = list.last_before(|score, member, _| range.below_max(score, member));
+            if last == NIL || offset > rank {
+                return Ok(resp::Msg::Array(Some(Vec::new())));
+            }
+            if offset > 0 { list.by_rank(rank - offset) } else { last }
+        } else {
+            let (first, rank) = list.first_after(|score, member, _| !range.above_min(score, member));
+            if first == NIL {
+                return Ok(resp::Msg::Array(Some(Vec::new())));
+            }
+            if offset > 0 { list.by_rank(rank + offset) } else { first }
+        };
+        let count = if count < 0 { list.len() } else { count as usize };
+        Ok(reply_range(list, n, count, rev, self.3, |score, member| {
+            if rev {
+                range.above_min(score, member)
+            } else {
+                range.below_max(score, member)
+            }
+        }))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zrangeby(self)
+    }
+}
+
+impl Execute for Zremrange {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let name = args.own_bytes(0);
+        if args.len() != 4 {
+            return Err(Error::Err("wrong number of arguments for 'zremrangebyscore' command"));
+        }
+        let removal = match name {
+            Some(name) => match name.as_ref() {
+                b"ZREMRANGEBYRANK" => match (args.int(2), args.int(3)) {
+                    (Some(start), Some(stop)) => Removal::Rank(start, stop),
+                    _ => return Err(Error::Err("value is not an integer or out of range")),
+                },
+                b"ZREMRANGEBYSCORE" => Removal::Range(Range::parse(&args, 2, false)?),
+                b"ZREMRANGEBYLEX" => Removal::Range(Range::parse(&args, 2, true)?),
+                _ => return Err(Error::Err("invalid name for 'zremrangebyscore' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'zremrangebyscore' command")),
+        };
+        match args.own_bytes(1) {
+            Some(key) => Ok(Zremrange(key, removal)),
+            None => Err(Error::Err("invalid parameter for 'zremrangebyscore' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let zset = match get_zset(db, &self.0)? {
+            Some(zset) => zset,
+            None => return Ok(resp::Msg::Int(0)),
+        };
+        let removed = match &self.1 {
+            Removal::Rank(start, stop) => match range(*start, *stop, zset.len()) {
+                // ranks passed to the predicates are 1-based
+                Some((start, end)) => zset.remove_range(|_, _, rank| rank <= start, |_, _, rank| rank <= end),
+                None => 0,
+            },
+            Removal::Range(range) => zset.remove_range(
+                |score, member, _| !range.above_min(score, member),
+                |score, member, _| range.below_max(score, member),
+            ),
+        };
+        if zset.len() == 0 {
+            db.remove(&self.0);
+        }
+        Ok(resp::Msg::Int(removed as i64))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zremrange(self)
+    }
+}
+
+impl Execute for Zpop {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let max = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"ZPOPMIN" => false,
+                b"ZPOPMAX" => true,
+                _ => return Err(Error::Err("invalid name for 'zpopmin' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'zpopmin' command")),
+        };
+        let count = match args.len() {
+            2 => 1,
+            3 => match args.int(2) {
+                Some(count) => count,
+                None => return Err(Error::Err("value is not an integer or out of range")),
+            },
+            _ => return Err(Error::Err("wrong number of arguments for 'zpopmin' command")),
+        };
+        match args.own_bytes(1) {
+            Some(key) => Ok(Zpop(key, count, max)),
+            None => Err(Error::Err("invalid parameter for 'zpopmin' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.0)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let zset = match get_zset(db, &self.0)? {
+            Some(zset) => zset,
+            None => return Ok(resp::Msg::Array(Some(Vec::new()))),
+        };
+        let mut reply = Vec::new();
+        for _ in 0..self.1 {
+            let list = zset.list();
+            let n = if self.2 { list.last() } else { list.first() };
+            if n == NIL {
+                break;
+            }
+            let (member, score) = (list.member(n).clone(), list.score(n));
+            zset.remove(&member);
+            reply.push(resp::Msg::BulkString(Some(member)));
+            reply.push(resp::Msg::BulkString(Some(format_float(score))));
+        }
+        if zset.len() == 0 {
+            db.remove(&self.0);
+        }
+        Ok(resp::Msg::Array(Some(reply)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zpop(self)
+    }
+}
+
+// scores returns a copy of the members of the sorted set at key with their
+// scores, plain sets are accepted as well with every score set to 1.
+fn scores(db: &mut Database, key: &[u8]) -> Result<Option<Vec<(Bytes, f64)>>, Error> {
+    match db.get(key) {
+        Some(DBValue::SortedSet(z)) => {
+            let list = z.list();
+            let mut scores = Vec::with_capacity(list.len());
+            let mut n = list.first();
+            while n != NIL {
+                scores.push((list.member(n).clone(), list.score(n)));
+                n = list.next(n);
+            }
+            Ok(Some(scores))
+        }
+        Some(DBValue::Set(s)) => Ok(Some(s.members().into_iter().map(|m| (m, 1.0)).collect())),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+// zsetop combines the weighted scores of sets, a missing key is treated as
+// an empty set.
+fn zsetop(inter: bool, sets: Vec<Option<Vec<(Bytes, f64)>>>, weights: &[f64], aggregate: Aggregate) -> Vec<(Bytes, f64)> {
+    type Scores = HashMap<Bytes, f64, BuildHasherDefault<SeaHasher>>;
+    let sets: Vec<(Vec<(Bytes, f64)>, f64)> = sets
+        .into_iter()
+        .zip(weights.iter())
+        .map(|(s, w)| (s.unwrap_or_default(), *w))
+        .collect();
+    if inter {
+        if sets.iter().any(|(s, _)| s.is_empty()) {
+            return Vec::new();
+        }
+        // walk the smallest set and probe the others, the aggregate is
+        // still applied in key order
+        let smallest = (0..sets.len()).min_by_key(|&i| sets[i].0.len()).unwrap();
+        let others: Vec<(Scores, f64)> = sets.iter().map(|(s, w)| (s.iter().cloned().collect(), *w)).collect();
+        let mut result = Vec::new();
+        'members: for (member, _) in sets[smallest].0.iter() {
+            let mut score = None;
+            for (scores, weight) in others.iter() {
+                let s = match scores.get(member) {
+                    Some(s) => zero_nan(s * weight),
+                    None => continue 'members,
+                };
+                score = Some(score.map_or(s, |score| aggregate.apply(score, s)));
+            }
+            result.push((member.clone(), score.unwrap()));
+        }
+        result
+    } else {
+        let mut index: HashMap<Bytes, usize, BuildHasherDefault<SeaHasher>> = HashMap::default();
+        let mut result: Vec<(Bytes, f64)> = Vec::new();
+        for (set, weight) in sets.into_iter() {
+            for (member, score) in set.into_iter() {
+                let score = zero_nan(score * weight);
+                match index.get(&member) {
+                    Some(&i) => result[i].1 = aggregate.apply(result[i].1, score),
+                    None => {
+                        index.insert(member.clone(), result.len());
+                        result.push((member, score));
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+// store replaces the value at key with a sorted set of scores (or deletes
+// it if scores is empty), returns the size of the new sorted set.
+fn store(db: &mut Database, key: Bytes, scores: Vec<(Bytes, f64)>) -> i64 {
+    if scores.is_empty() {
+        db.remove(&key);
+        return 0;
+    }
+    let mut zset = SortedSetValue::default();
+    for (member, score) in scores.into_iter() {
+        zset.insert(member, score);
+    }
+    let len = zset.len() as i64;
+    db.set(key, DBValue::SortedSet(zset), None);
+    len
+}
+
+impl Execute for Zstore {
+    fn parse(mut args: Args) -> Result<Self, Error> {
+        let inter = match args.own_bytes(0) {
+            Some(name) => match name.as_ref() {
+                b"ZUNIONSTORE" => false,
+                b"ZINTERSTORE" => true,
+                _ => return Err(Error::Err("invalid name for 'zunionstore' command")),
+            },
+            None => return Err(Error::Err("invalid parameter for 'zunionstore' command")),
+        };
+        if args.len() < 4 {
+            return Err(Error::Err("wrong number of arguments for 'zunionstore' command"));
+        }
+        let numkeys = match args.int(2) {
+            Some(n) if n > 0 => n as usize,
+            Some(_) => return Err(Error::Err("at least 1 input key is needed for ZUNIONSTORE/ZINTERSTORE")),
+            None => return Err(Error::Err("value is not an integer or out of range")),
+        };
+        if numkeys > args.len() - 3 {
+            return Err(Error::Err("syntax error"));
+        }
+        let mut keys = Vec::with_capacity(numkeys);
+        for i in 3..3 + numkeys {
+            match args.own_bytes(i) {
+                Some(key) => keys.push(key),
+                None => return Err(Error::Err("invalid parameter for 'zunionstore' command")),
+            }
+        }
+        let mut weights = vec![1.0; numkeys];
+        let mut aggregate = Aggregate::Sum;
+        let mut i = 3 + numkeys;
+        while i < args.len() {
+            match &args[i] {
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b))
+                    if b.eq_ignore_ascii_case(b"WEIGHTS") && i + numkeys < args.len() =>
+                {
+                    for (j, weight) in weights.iter_mut().enumerate() {
+                        match args.float(i + 1 + j) {
+                            Some(w) => *weight = w,
+                            None => return Err(Error::Err("weight value is not a float")),
+                        }
+                    }
+                    i += numkeys + 1;
+                }
+                resp::Msg::String(b) | resp::Msg::BulkString(Some(b))
+                    if b.eq_ignore_ascii_case(b"AGGREGATE") && i + 1 < args.len() =>
+                {
+                    aggregate = match &args[i + 1] {
+                        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"SUM") => Aggregate::Sum,
+                        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"MIN") => Aggregate::Min,
+                        resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"MAX") => Aggregate::Max,
+                        _ => return Err(Error::Err("syntax error")),
+                    };
+                    i += 2;
+                }
+                _ => return Err(Error::Err("syntax error")),
+            }
+        }
+        match args.own_bytes(1) {
+            Some(dst) => Ok(Zstore(inter, dst, keys, weights, aggregate)),
+            None => Err(Error::Err("invalid parameter for 'zunionstore' command")),
+        }
+    }
+
+    fn shard(&self) -> u64 {
+        shard_for(&self.1)
+    }
+
+    fn writes(&self) -> bool {
+        true
+    }
+
+    fn exec(&self, db: &mut Database) -> Result<resp::Msg, Error> {
+        let mut sets = Vec::with_capacity(self.2.len());
+        for key in self.2.iter() {
+            sets.push(scores(db, key)?);
+        }
+        let result = zsetop(self.0, sets, &self.3, self.4);
+        Ok(resp::Msg::Int(store(db, self.1.clone(), result)))
+    }
+
+    fn to_command(self) -> Command {
+        Command::Zstore(self)
+    }
+}
+
+impl Coordinate for Zstore {
+    fn coordinate(&self, pool: &tokio_io_pool::Handle, db: usize) -> Reply {
+        let pool = pool.clone();
+        let inter = self.0;
+        let dst = self.1.clone();
+        let keys = self.2.clone();
+        let weights = self.3.clone();
+        let aggregate = self.4;
+        Box::pin(async move {
+            let combine = move |sets| zsetop(inter, sets, &weights, aggregate);
+            let n = fetch_then_store(&pool, db, keys, dst, scores, combine, store).await?;
+            Ok(resp::Msg::Int(n))
+        })
+    }
+}
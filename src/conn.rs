@@ -1,8 +1,14 @@
+use std::collections::VecDeque;
+use std::io;
+
 use tokio::codec::Framed;
 use tokio::net::TcpStream;
 use tokio::prelude::*;
 use tokio::sync::oneshot;
+use futures::future::{self, Either};
+use bytes::Bytes;
 
+use super::aof;
 use super::command::{self, Command};
 use super::database;
 use super::resp;
@@ -17,19 +23,64 @@ async fn conn(stream: TcpStream, worker_pool: tokio_io_pool::Handle, conn_no: us
     let (mut resp_out, mut resp_in) = framed.split();
 
     let mut requested_disconnect = false;
+    // the database picked by SELECT
+    let mut db = 0;
+    // requests read while the client was blocked
+    let mut pending = VecDeque::new();
     let conn_worker_shard = worker_pool.worker_id(conn_no as u64);
-    while let Some(frame) = resp_in.next().await {
+    loop {
+        let frame = match pending.pop_front() {
+            Some(frame) => frame,
+            None => match resp_in.next().await {
+                Some(frame) => frame,
+                None => break,
+            },
+        };
         let resp = match frame {
             Ok(msg) => {
                 match process_req(msg) {
-                    Ok(request) => {
+                    Ok((Command::Select(select), _, _)) => {
+                        db = select.index();
+                        resp::Msg::Str("OK")
+                    }
+                    Ok((request, denyoom, args)) => if let Some(coordinator) = request.to_coordinate() {
+                        if denyoom && !database::evict_everywhere(&worker_pool).await {
+                            resp::Msg::Error(format!("{}", command::Error::Oom))
+                        } else {
+                            let reply = coordinator.coordinate(&worker_pool, db);
+                            let reply = if coordinator.blocks() {
+                                match wait_blocked(reply, &mut resp_in, &mut pending).await {
+                                    Some(reply) => reply,
+                                    None => return,
+                                }
+                            } else {
+                                reply.await
+                            };
+                            match reply {
+                                Ok(r) => r,
+                                Err(e) => resp::Msg::Error(format!("{}", e)),
+                            }
+                        }
+                    } else if let Some(scatter) = request.to_scatter() {
+                        if denyoom && !database::evict_everywhere(&worker_pool).await {
+                            resp::Msg::Error(format!("{}", command::Error::Oom))
+                        } else {
+                            match scatter_gather(&worker_pool, conn_worker_shard, db, scatter, args).await {
+                                Ok(r) => r,
+                                Err(e) => resp::Msg::Error(format!("{}", e)),
+                            }
+                        }
+                    } else {
                         let cmd = request.to_execute();
                         let shard = cmd.shard();
                         if conn_worker_shard == worker_pool.worker_id(shard)
                             || shard == std::u64::MAX
                         {
                             // fast path
-                            match database::execute(cmd) {
+                            if shard != u64::MAX {
+                                database::unlocked(&[db], &[shard]).await;
+                            }
+                            match database::execute(cmd, db, denyoom, args) {
                                 Ok(r) => r,
                                 Err(command::Error::Quit) => {
                                     // enabling the following will cause command::Execute
@@ -44,7 +95,8 @@ async fn conn(stream: TcpStream, worker_pool: tokio_io_pool::Handle, conn_no: us
                             let (p, c) = oneshot::channel::<resp::Msg>();
                             // tokio::spawn_async(async {
                             let fut = async move {
-                                    let resp = match database::execute(request.to_execute()) {
+                                    database::unlocked(&[db], &[shard]).await;
+                                    let resp = match database::execute(request.to_execute(), db, denyoom, args) {
                                         Ok(r) => r,
                                         Err(e) => resp::Msg::Error(format!("{}", e)),
                                     };
@@ -73,7 +125,83 @@ async fn conn(stream: TcpStream, worker_pool: tokio_io_pool::Handle, conn_no: us
     }
 }
 
-fn process_req(msg: resp::Msg) -> Result<Command, command::Error> {
+// MAX_PENDING bounds the requests read ahead while a client is blocked.
+const MAX_PENDING: usize = 1024;
+
+// wait_blocked awaits reply, the reply of a command that may block the
+// client, while reading the requests the client sends meanwhile into
+// pending, decode errors included so that they are answered in order. It
+// returns None if the client disconnects first, reply is dropped then.
+// Once MAX_PENDING requests are read it stops reading until reply comes.
+async fn wait_blocked<S>(
+    mut reply: command::Reply,
+    resp_in: &mut S,
+    pending: &mut VecDeque<Result<resp::Msg, io::Error>>,
+) -> Option<Result<resp::Msg, command::Error>>
+where
+    S: Stream<Item = Result<resp::Msg, io::Error>> + Unpin,
+{
+    loop {
+        if pending.len() >= MAX_PENDING {
+            return Some(reply.await);
+        }
+        match future::select(reply, resp_in.next()).await {
+            Either::Left((r, _)) => return Some(r),
+            Either::Right((Some(frame), r)) => {
+                pending.push_back(frame);
+                reply = r;
+            }
+            Either::Right((None, _)) => return None,
+        }
+    }
+}
+
+// scatter_gather splits cmd into one part per worker owning some of its
+// keys, runs the parts concurrently and merges their replies back in key
+// order. A part owned by the connection's own worker is run inline. The
+// parts don't make room for themselves, the caller runs evict_everywhere
+// before so that either every part runs or none does. args are the
+// arguments of cmd when the AOF is enabled, every part is logged with the
+// arguments of its own keys.
+async fn scatter_gather(
+    worker_pool: &tokio_io_pool::Handle,
+    conn_worker_shard: usize,
+    db: usize,
+    cmd: &dyn command::Scatter,
+    args: Option<Vec<Bytes>>,
+) -> Result<resp::Msg, command::Error> {
+    let keys = cmd.keys();
+    let positions: Vec<usize> = (0..keys.len()).collect();
+    let parts = command::split_by_worker(worker_pool, positions, |&i| command::shard_for(keys[i]));
+    let replies = future::join_all(parts.into_iter().map(|(shard, positions)| {
+        let part = cmd.part(&positions);
+        let hashes: Vec<u64> = positions.iter().map(|&i| command::shard_for(keys[i])).collect();
+        let args = args.as_ref().map(|args| {
+            let stride = (args.len() - 1) / keys.len();
+            let mut part_args = vec![args[0].clone()];
+            for &i in positions.iter() {
+                part_args.extend_from_slice(&args[1 + i * stride..1 + (i + 1) * stride]);
+            }
+            part_args
+        });
+        async move {
+            let reply = if worker_pool.worker_id(shard) == conn_worker_shard {
+                database::unlocked(&[db], &hashes).await;
+                database::execute(&*part, db, false, args)
+            } else {
+                database::with_unlocked(worker_pool, shard, db, hashes, move |db| database::exec(&*part, db, args)).await
+            };
+            (positions, reply)
+        }
+    }))
+    .await;
+    cmd.gather(replies)
+}
+
+// process_req parses msg into a command, and tells whether the command is
+// one of command::DENYOOM. When the AOF is enabled it also returns the
+// arguments of the command, for it to be logged.
+fn process_req(msg: resp::Msg) -> Result<(Command, bool, Option<Vec<Bytes>>), command::Error> {
     match msg {
         resp::Msg::Array(Some(args)) => {
             if args.len() > 0 {
@@ -111,8 +239,10 @@ fn process_req(msg: resp::Msg) -> Result<Command, command::Error> {
                     }
                 }
 
+                let denyoom = command::DENYOOM.contains(command_name);
+                let logged = if aof::enabled() { Some(to_bytes(&args)) } else { None };
                 match command::COMMANDS.get(command_name) {
-                    Some(c) => c(args),
+                    Some(c) => c(args).map(|command| (command, denyoom, logged)),
                     _ => Err(command::Error::Error(format!(
                         "unknown command '{}'",
                         match std::str::from_utf8(command_name.as_ref()) {
@@ -130,3 +260,14 @@ fn process_req(msg: resp::Msg) -> Result<Command, command::Error> {
         )),
     }
 }
+
+// to_bytes copies args, as the parsed command takes them.
+fn to_bytes(args: &[resp::Msg]) -> Vec<Bytes> {
+    args.iter()
+        .map(|arg| match arg {
+            resp::Msg::String(b) | resp::Msg::BulkString(Some(b)) => b.clone(),
+            resp::Msg::Int(n) => Bytes::from(n.to_string()),
+            _ => Bytes::new(),
+        })
+        .collect()
+}
@@ -1,15 +1,136 @@
+use std::collections::{BTreeSet, VecDeque};
 use std::hash::BuildHasherDefault;
+use std::cmp;
+use std::mem;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use futures::channel::mpsc;
+use futures::future;
+use lazy_static::lazy_static;
+use rand::Rng;
 use seahash;
+use tokio::sync::oneshot;
+use tokio::timer::Interval;
 
+use super::aof;
 use super::command::{self, Execute};
+use super::indexset::IndexSet;
+use super::intset::{self, IntSet};
 use super::resp;
+use super::scanindex::ScanIndex;
+use super::skiplist::SkipList;
+use super::snapshot;
+
+// How often the active expire cycle runs on each shard, and the maximum
+// number of keys it will evict per run so a burst of expirations can't
+// stall the event loop.
+const ACTIVE_EXPIRE_CYCLE_INTERVAL: time::Duration = time::Duration::from_millis(100);
+const ACTIVE_EXPIRE_CYCLE_KEYS: usize = 1000;
+
+// Values made of more than LAZYFREE_THRESHOLD elements are freed on a
+// background thread by lazy_free.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+// DATABASES is the number of databases of every shard, numbered from 0 and
+// picked with SELECT.
+pub const DATABASES: usize = 16;
+
+// Under an LFU policy the access counter of a new key starts at
+// LFU_INIT_VAL, so that it isn't evicted right away, and grows
+// logarithmically, more slowly the higher LFU_LOG_FACTOR is.
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+// Policy picks the keys evicted once a shard uses more than its share of
+// maxmemory. The volatile policies only evict keys with a deadline.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Policy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+const POLICIES: [(Policy, &str); 8] = [
+    (Policy::NoEviction, "noeviction"),
+    (Policy::AllKeysLru, "allkeys-lru"),
+    (Policy::VolatileLru, "volatile-lru"),
+    (Policy::AllKeysLfu, "allkeys-lfu"),
+    (Policy::VolatileLfu, "volatile-lfu"),
+    (Policy::AllKeysRandom, "allkeys-random"),
+    (Policy::VolatileRandom, "volatile-random"),
+    (Policy::VolatileTtl, "volatile-ttl"),
+];
+
+impl Policy {
+    pub fn from_name(name: &str) -> Option<Policy> {
+        POLICIES.iter().find(|(_, n)| n.eq_ignore_ascii_case(name)).map(|(p, _)| *p)
+    }
+
+    pub fn name(self) -> &'static str {
+        POLICIES[self as usize].1
+    }
+}
+
+// MAXMEMORY is the memory limit in bytes (0 for none), split evenly across
+// the shards. MAXMEMORY_SAMPLES keys are sampled for every eviction.
+static MAXMEMORY: AtomicUsize = AtomicUsize::new(0);
+static MAXMEMORY_POLICY: AtomicUsize = AtomicUsize::new(Policy::NoEviction as usize);
+static MAXMEMORY_SAMPLES: AtomicUsize = AtomicUsize::new(5);
+
+pub fn set_maxmemory(bytes: usize, policy: Policy, samples: usize) {
+    MAXMEMORY.store(bytes, Ordering::SeqCst);
+    MAXMEMORY_POLICY.store(policy as usize, Ordering::SeqCst);
+    MAXMEMORY_SAMPLES.store(cmp::max(samples, 1), Ordering::SeqCst);
+}
+
+pub fn maxmemory() -> usize {
+    MAXMEMORY.load(Ordering::Relaxed)
+}
+
+pub fn policy() -> Policy {
+    POLICIES[MAXMEMORY_POLICY.load(Ordering::Relaxed)].0
+}
+
+// CLOCK is the LRU clock, in seconds, advanced by cron so that recording
+// an access doesn't need to read the time.
+static CLOCK: AtomicU32 = AtomicU32::new(0);
+
+pub fn lru_clock() -> u32 {
+    CLOCK.load(Ordering::Relaxed)
+}
+
+fn tick_clock() {
+    CLOCK.store((now_ms() / 1000) as u32, Ordering::Relaxed);
+}
+
+lazy_static! {
+    static ref LAZYFREE: Mutex<std_mpsc::Sender<Box<dyn Send>>> = {
+        let (tx, rx) = std_mpsc::channel::<Box<dyn Send>>();
+        thread::Builder::new()
+            .name("lazyfree".into())
+            .spawn(move || for value in rx.iter() {
+                drop(value);
+            })
+            .unwrap();
+        Mutex::new(tx)
+    };
+}
 
 #[derive(Clone, Copy)]
 enum DBState {
     None,
-    Ready(*mut Database),
+    Ready(*mut Vec<Database>),
 }
 
 pub enum Scalar {
@@ -17,58 +138,1549 @@ pub enum Scalar {
     Integer(i64),
 }
 
+impl Scalar {
+    pub fn size(&self) -> usize {
+        match self {
+            Scalar::String(b) => bytes_size(b),
+            Scalar::Integer(_) => mem::size_of::<Scalar>(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            Scalar::String(s) => s.clone(),
+            Scalar::Integer(i) => Bytes::from(i.to_string()),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub enum HashMapValue {
     Skip(),
     Hash()
 }
 
-#[allow(dead_code)]
+// Sets holding only integers are stored as a sorted IntSet until they grow
+// past SET_MAX_INTSET_ENTRIES members, after which (or as soon as a non
+// integer member is added) they are converted to an IndexSet.
+const SET_MAX_INTSET_ENTRIES: usize = 512;
+
 pub enum SetValue {
-    Skip(),
-    Hash()
+    Int(IntSet),
+    Hash(IndexSet),
+}
+
+impl SetValue {
+    pub fn new() -> SetValue {
+        SetValue::Int(IntSet::default())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SetValue::Int(s) => s.len(),
+            SetValue::Hash(s) => s.len(),
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            SetValue::Int(s) => intset::encode(member).is_some_and(|i| s.contains(i)),
+            SetValue::Hash(s) => s.contains(member),
+        }
+    }
+
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        if let SetValue::Int(s) = self {
+            match intset::encode(&member) {
+                Some(i) if s.len() < SET_MAX_INTSET_ENTRIES || s.contains(i) => return s.insert(i),
+                _ => self.convert(),
+            }
+        }
+        match self {
+            SetValue::Hash(s) => s.insert(member),
+            SetValue::Int(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            SetValue::Int(s) => intset::encode(member).is_some_and(|i| s.remove(i)),
+            SetValue::Hash(s) => s.remove(member),
+        }
+    }
+
+    // get returns the member at position pos, positions are stable as long
+    // as the set is not modified.
+    pub fn get(&self, pos: usize) -> Bytes {
+        match self {
+            SetValue::Int(s) => Bytes::from(s.get(pos).to_string()),
+            SetValue::Hash(s) => s.get(pos).clone(),
+        }
+    }
+
+    pub fn remove_at(&mut self, pos: usize) -> Bytes {
+        match self {
+            SetValue::Int(s) => Bytes::from(s.remove_at(pos).to_string()),
+            SetValue::Hash(s) => s.remove_at(pos),
+        }
+    }
+
+    pub fn members(&self) -> Vec<Bytes> {
+        match self {
+            SetValue::Int(s) => s.iter().map(|i| Bytes::from(i.to_string())).collect(),
+            SetValue::Hash(s) => s.iter().cloned().collect(),
+        }
+    }
+
+    fn convert(&mut self) {
+        if let SetValue::Int(s) = self {
+            let mut hs = IndexSet::with_capacity(s.len() + 1);
+            for i in s.iter() {
+                hs.insert(Bytes::from(i.to_string()));
+            }
+            *self = SetValue::Hash(hs);
+        }
+    }
+}
+
+// SortedSetValue indexes members both by name, to look up their score, and
+// by (score, member) in a skip list for rank and range queries.
+#[derive(Default)]
+pub struct SortedSetValue {
+    scores: std::collections::HashMap<Bytes, f64, BuildHasherDefault<seahash::SeaHasher>>,
+    list: SkipList,
+    scan: ScanIndex,
+    bytes: usize,
+}
+
+impl SortedSetValue {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    // bytes returns the total length of the members.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).cloned()
+    }
+
+    // insert sets the score of member, returns true if member was added and
+    // false if its score was updated.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        match self.scores.get_mut(&member) {
+            Some(old) => {
+                if *old != score {
+                    self.list.remove(*old, &member);
+                    self.list.insert(score, member);
+                    *old = score;
+                }
+                false
+            }
+            None => {
+                self.scores.insert(member.clone(), score);
+                self.scan.insert(&member);
+                self.bytes += member.len();
+                self.list.insert(score, member);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove_entry(member) {
+            Some((member, score)) => {
+                self.list.remove(score, &member);
+                self.scan.remove(&member);
+                self.bytes -= member.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // rank returns the 0-based rank of member, ordered from the lowest score.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        self.score(member).and_then(|score| self.list.rank(score, member))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.scores.iter().map(|(member, score)| (member, *score))
+    }
+
+    pub fn list(&self) -> &SkipList {
+        &self.list
+    }
+
+    // page returns a page of the members in hash order, see ScanIndex::page.
+    pub fn page(&mut self, cursor: u64, count: usize) -> (Vec<Bytes>, u64) {
+        self.scan.page(self.scores.keys(), cursor, count)
+    }
+
+    // remove_range removes a run of members from the skip list, see
+    // SkipList::remove_range, and returns the number of members removed.
+    pub fn remove_range<B, I>(&mut self, before: B, inside: I) -> usize
+    where
+        B: Fn(f64, &[u8], usize) -> bool,
+        I: Fn(f64, &[u8], usize) -> bool,
+    {
+        let removed = self.list.remove_range(before, inside);
+        for member in removed.iter() {
+            self.scores.remove(member);
+            self.scan.remove(member);
+            self.bytes -= member.len();
+        }
+        removed.len()
+    }
+}
+
+// HashValue maps the fields of a hash to their values, and keeps the
+// combined size of its fields and values up to date as they change.
+#[derive(Default)]
+pub struct HashValue {
+    fields: std::collections::HashMap<Bytes, Scalar, BuildHasherDefault<seahash::SeaHasher>>,
+    scan: ScanIndex,
+    size: usize,
+}
+
+impl HashValue {
+    pub fn with_capacity(n: usize) -> HashValue {
+        HashValue {
+            fields: std::collections::HashMap::with_capacity_and_hasher(n, Default::default()),
+            scan: ScanIndex::default(),
+            size: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    // size returns the number of bytes used by the fields and their values.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Scalar> {
+        self.fields.get(field)
+    }
+
+    pub fn contains_key(&self, field: &[u8]) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    // insert sets the value of field, returns its previous value if any.
+    pub fn insert(&mut self, field: Bytes, value: Scalar) -> Option<Scalar> {
+        self.size += value.size();
+        let old = self.fields.insert(field.clone(), value);
+        match &old {
+            Some(old) => self.size -= old.size(),
+            None => {
+                self.size += bytes_size(&field);
+                self.scan.insert(&field);
+            }
+        }
+        old
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> Option<Scalar> {
+        let (field, value) = self.fields.remove_entry(field)?;
+        self.scan.remove(&field);
+        self.size -= bytes_size(&field) + value.size();
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Scalar)> {
+        self.fields.iter()
+    }
+
+    // page returns a page of the fields in hash order, see ScanIndex::page.
+    pub fn page(&mut self, cursor: u64, count: usize) -> (Vec<Bytes>, u64) {
+        self.scan.page(self.fields.keys(), cursor, count)
+    }
 }
 
 #[allow(dead_code)]
 pub enum Value {
     Scalar(Scalar),
-    List(Vec<Scalar>),
-    HashMap(std::collections::HashMap<Bytes, Scalar, BuildHasherDefault<seahash::SeaHasher>>),
-    Set(),
-    SortedSet(),
+    List(VecDeque<Bytes>),
+    HashMap(HashValue),
+    Set(SetValue),
+    SortedSet(SortedSetValue),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Scalar(_) => "string",
+            Value::List(_) => "list",
+            Value::HashMap(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+
+    // encoding returns the name of the representation of the value, as
+    // reported by OBJECT ENCODING. Lists only have one, reported under the
+    // name redis uses for its default.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::Scalar(Scalar::Integer(_)) => "int",
+            Value::Scalar(Scalar::String(_)) => "raw",
+            Value::List(_) => "quicklist",
+            Value::HashMap(_) => "hashtable",
+            Value::Set(SetValue::Int(_)) => "intset",
+            Value::Set(SetValue::Hash(_)) => "hashtable",
+            Value::SortedSet(_) => "skiplist",
+        }
+    }
+
+    // size estimates the number of bytes used by the value. Lists are
+    // assumed to be made of elements about the size of samples of them
+    // picked at random, or all of them if samples is 0. Other collections
+    // keep track of the length of their members as they change.
+    pub fn size(&self, samples: usize) -> usize {
+        let pointer = mem::size_of::<usize>();
+        let bytes = mem::size_of::<Bytes>();
+        match self {
+            Value::Scalar(v) => v.size(),
+            Value::List(l) => sampled(l.len(), samples, |i| bytes_size(&l[i])),
+            Value::HashMap(h) => h.size() + h.len() * pointer,
+            Value::Set(SetValue::Int(s)) => s.len() * mem::size_of::<i64>(),
+            // every member is held by the vector and the index
+            Value::Set(SetValue::Hash(s)) => 2 * s.bytes() + s.len() * (2 * bytes + 2 * pointer),
+            // every member is held by the score index and a skip list node,
+            // which has 1.33 levels on average
+            Value::SortedSet(z) => 2 * z.bytes() + z.len() * (2 * bytes + 10 * pointer),
+        }
+    }
+
+    // elements returns the number of elements making up the value, a rough
+    // estimate of the work needed to free it.
+    pub fn elements(&self) -> usize {
+        match self {
+            Value::Scalar(_) => 1,
+            Value::List(l) => l.len(),
+            Value::HashMap(h) => h.len(),
+            Value::Set(s) => s.len(),
+            Value::SortedSet(z) => z.len(),
+        }
+    }
+}
+
+// SIZE_SAMPLES is the number of elements of a list sampled to estimate
+// its size when accounting it against maxmemory.
+pub const SIZE_SAMPLES: usize = 5;
+
+fn bytes_size(b: &Bytes) -> usize {
+    mem::size_of::<Bytes>() + b.len()
+}
+
+// sampled estimates the size of len elements from the sizes of samples of
+// them at random positions (all of them if samples is 0), size returning
+// the size of the element at a given position.
+fn sampled<F: Fn(usize) -> usize>(len: usize, samples: usize, size: F) -> usize {
+    if samples == 0 || samples >= len {
+        return (0..len).map(size).sum();
+    }
+    let mut rng = rand::thread_rng();
+    let total: usize = (0..samples).map(|_| size(rng.gen_range(0, len))).sum();
+    total * len / samples
+}
+
+// Waiter is a client blocked on an empty list (BLPOP and friends). A client
+// blocking on several keys registers a waiter on each of them, sharing the
+// same claim flag. Whichever shard flips the claim first serves the client,
+// the remaining waiters are dropped the next time they are looked at.
+//
+// A client moving the element it is served to another key (BRPOPLPUSH) is
+// sent a ticket holding the gate along with it, which it keeps until the
+// element got there, as if the keys were locked by lock_keys.
+pub struct Waiter {
+    pub claim: Arc<AtomicBool>,
+    pub left: bool,
+    pub moves: bool,
+    pub tx: mpsc::UnboundedSender<(Bytes, Bytes, Option<Ticket>)>,
+}
+
+impl Waiter {
+    pub fn is_done(&self) -> bool {
+        self.claim.load(Ordering::SeqCst) || self.tx.is_closed()
+    }
+
+    // try_claim marks the client as served, returns false if it was already
+    // served (or has given up waiting).
+    pub fn try_claim(&self) -> bool {
+        self.claim
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
+pub struct Entry {
+    pub value: Value,
+    // deadline in milliseconds since the unix epoch
+    pub expires_at: Option<u64>,
+    // position of the key in Database::slots, and in Database::volatile
+    // when it has a deadline
+    slot: usize,
+    volatile: usize,
+    // estimated size of the entry, as accounted in Database::used, and
+    // whether the value was modified in place since
+    size: usize,
+    dirty: bool,
+    // LRU clock of the last access, and logarithmic access counter (only
+    // maintained under an LFU policy)
+    access: u32,
+    freq: u8,
+    // epoch of the last snapshot the entry was written to, or that was in
+    // progress when it was added, see begin_save
+    saved: u64,
+}
+
+impl Entry {
+    fn new(key: &Bytes, value: Value, expires_at: Option<u64>, slot: usize, volatile: usize, saved: u64) -> Entry {
+        Entry {
+            size: entry_size(key, &value, SIZE_SAMPLES),
+            dirty: false,
+            value,
+            expires_at,
+            slot,
+            volatile,
+            access: lru_clock(),
+            freq: LFU_INIT_VAL,
+            saved,
+        }
+    }
+
+    // touch records an access to the entry.
+    fn touch(&mut self) {
+        let now = lru_clock();
+        match policy() {
+            Policy::AllKeysLfu | Policy::VolatileLfu => {
+                let freq = self.freq(now);
+                let base = freq.saturating_sub(LFU_INIT_VAL) as f64;
+                let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+                self.freq = if freq < 255 && rand::thread_rng().gen::<f64>() < p { freq + 1 } else { freq };
+            }
+            _ => {}
+        }
+        self.access = now;
+    }
+
+    // idle returns the number of seconds since the last access.
+    pub fn idle(&self, now: u32) -> u32 {
+        now.wrapping_sub(self.access)
+    }
+
+    // freq returns the access counter, decremented by one for every minute
+    // the entry wasn't accessed.
+    pub fn freq(&self, now: u32) -> u8 {
+        let minutes = self.idle(now) / 60;
+        self.freq.saturating_sub(cmp::min(minutes, 255) as u8)
+    }
 }
 
-pub type Database = std::collections::HashMap<Bytes, Value, BuildHasherDefault<seahash::SeaHasher>>;
+// entry_size estimates the size of an entry, its key is held by `entries`,
+// `scan` and `slots`.
+fn entry_size(key: &Bytes, value: &Value, samples: usize) -> usize {
+    mem::size_of::<Entry>() + 3 * bytes_size(key) + value.size(samples)
+}
+
+// unslot removes the key at slot from slots, a dense vector of keys, by
+// moving the last key in its place. position returns the field of an entry
+// holding its position in slots.
+fn unslot(
+    entries: &mut std::collections::HashMap<Bytes, Entry, BuildHasherDefault<seahash::SeaHasher>>,
+    slots: &mut Vec<Bytes>,
+    slot: usize,
+    position: fn(&mut Entry) -> &mut usize,
+) {
+    slots.swap_remove(slot);
+    if let Some(moved) = slots.get(slot) {
+        *position(entries.get_mut(moved).unwrap()) = slot;
+    }
+}
+
+// Database is a single shard's keyspace. Keys with a deadline are also
+// tracked in `expires`, ordered by deadline, so the active expire cycle
+// can find them without walking the whole table. Expired keys are otherwise
+// removed lazily whenever they are looked up.
+//
+// Every key is also kept in `scan`, ordered by scan_hash, which gives SCAN
+// a position to resume from that doesn't move when `entries` is resized,
+// and in `slots`, a dense vector RANDOMKEY and eviction can sample
+// uniformly. Keys with a deadline are in `volatile` as well, for the
+// volatile eviction policies.
+#[derive(Default)]
+pub struct Database {
+    entries: std::collections::HashMap<Bytes, Entry, BuildHasherDefault<seahash::SeaHasher>>,
+    expires: BTreeSet<(u64, Bytes)>,
+    scan: BTreeSet<(u64, Bytes)>,
+    slots: Vec<Bytes>,
+    volatile: Vec<Bytes>,
+    // estimated size of all the entries, and the slots of the entries
+    // modified in place since it was last updated
+    used: usize,
+    dirty: Vec<usize>,
+    // number of keys evicted to honour maxmemory
+    evicted: usize,
+    blocked: std::collections::HashMap<Bytes, VecDeque<Waiter>, BuildHasherDefault<seahash::SeaHasher>>,
+    // key hashes (as returned by command::shard_for) locked by lock_keys,
+    // along with the clients waiting for them to be unlocked.
+    locked: std::collections::HashMap<u64, Vec<oneshot::Sender<()>>>,
+    // snapshot in progress and its epoch, see begin_save
+    saving: Option<Saving>,
+    epoch: u64,
+    // keys whose blocked clients couldn't be served while the gate was
+    // closed to holders, served again by cron
+    stalled: Vec<Bytes>,
+    // number of the database, which belongs to its position in the shard
+    // rather than to its keys, like blocked
+    number: usize,
+}
+
+// Saving is the progress of a snapshot through a database: the position in
+// `scan` of the last key written, and the records written since the last
+// save_step. db is the number the database had when the snapshot started,
+// which it keeps even if SWAPDB moves it.
+struct Saving {
+    db: usize,
+    cursor: Option<(u64, Bytes)>,
+    buf: BytesMut,
+    format: Format,
+}
+
+// Format is the format of the records a snapshot is written in: those of
+// a snapshot file, or the commands of an AOF segment being rewritten.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Snapshot,
+    Aof,
+}
+
+impl Saving {
+    // write adds the record of the entry at key, unless it has expired.
+    fn write(&mut self, key: &[u8], e: &Entry) {
+        if e.expires_at.is_none_or(|t| t > now_ms()) {
+            match self.format {
+                Format::Snapshot => snapshot::write_entry(&mut self.buf, key, &e.value, e.expires_at),
+                Format::Aof => aof::write_entry(&mut self.buf, key, &e.value, e.expires_at),
+            }
+        }
+    }
+}
+
+// swap exchanges the keys of the databases a and b. Blocked clients and
+// key locks belong to a database number rather than to its keys, so they
+// stay where they are, and the blocked clients are served if their key
+// now holds a list.
+pub fn swap(dbs: &mut [Database], a: usize, b: usize) {
+    if a == b {
+        return;
+    }
+    dbs.swap(a, b);
+    let (low, high) = dbs.split_at_mut(std::cmp::max(a, b));
+    let (x, y) = (&mut low[std::cmp::min(a, b)], &mut high[0]);
+    std::mem::swap(&mut x.blocked, &mut y.blocked);
+    std::mem::swap(&mut x.locked, &mut y.locked);
+    std::mem::swap(&mut x.number, &mut y.number);
+    for db in [x, y].iter_mut() {
+        let keys: Vec<Bytes> = db.blocked.keys().cloned().collect();
+        for key in keys.iter() {
+            db.serve_blocked(key);
+        }
+    }
+}
+
+// lazy_free drops value, on a background thread if it is large.
+pub fn lazy_free(value: Value) {
+    if value.elements() > LAZYFREE_THRESHOLD {
+        drop_in_background(Box::new(value));
+    }
+}
+
+// drop_in_background hands v to the background thread to be dropped there.
+pub fn drop_in_background(v: Box<dyn Send>) {
+    if let Ok(tx) = LAZYFREE.lock() {
+        let _ = tx.send(v);
+    }
+}
+
+// SCAN_HASH_BITS is the width of scan_hash, leaving the top bits of a SCAN
+// cursor free to hold the shard index.
+pub const SCAN_HASH_BITS: u32 = 56;
+
+pub fn scan_hash(key: &[u8]) -> u64 {
+    seahash::hash(key) >> (64 - SCAN_HASH_BITS)
+}
+
+pub fn now_ms() -> u64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+impl Database {
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    // removes `key` if its deadline has passed, returns true if the key was
+    // removed. Nothing expires while the AOF is replayed, the log says when
+    // keys went away.
+    fn expire_if_needed(&mut self, key: &[u8]) -> bool {
+        let expires_at = match self.entries.get(key) {
+            Some(Entry { expires_at: Some(t), .. }) => *t,
+            _ => return false,
+        };
+        if expires_at > now_ms() || loading() {
+            return false;
+        }
+        self.expire(key);
+        true
+    }
+
+    // expire removes key once its deadline has passed, and logs its removal
+    // so that the AOF doesn't depend on when it is replayed.
+    fn expire(&mut self, key: &[u8]) {
+        self.remove(key);
+        aof::feed(self.number, &[&b"DEL"[..], key]);
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<&Value> {
+        self.expire_if_needed(key);
+        self.entries.get_mut(key).map(|e| {
+            e.touch();
+            &e.value
+        })
+    }
+
+    // get_indexed is like get, but lets the caller build the scan index of
+    // the value (see ScanIndex), which changes neither its contents nor its
+    // estimated size.
+    pub fn get_indexed(&mut self, key: &[u8]) -> Option<&mut Value> {
+        self.expire_if_needed(key);
+        self.entries.get_mut(key).map(|e| {
+            e.touch();
+            &mut e.value
+        })
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Value> {
+        self.expire_if_needed(key);
+        self.save_before_write(key);
+        let dirty = &mut self.dirty;
+        self.entries.get_mut(key).map(|e| {
+            e.touch();
+            if !e.dirty {
+                e.dirty = true;
+                dirty.push(e.slot);
+            }
+            &mut e.value
+        })
+    }
+
+    // add stores a new key, which must not exist.
+    fn add(&mut self, key: Bytes, value: Value, expires_at: Option<u64>) {
+        self.scan.insert((scan_hash(&key), key.clone()));
+        let slot = self.slots.len();
+        self.slots.push(key.clone());
+        let volatile = self.volatile.len();
+        if let Some(t) = expires_at {
+            self.expires.insert((t, key.clone()));
+            self.volatile.push(key.clone());
+        }
+        let entry = Entry::new(&key, value, expires_at, slot, volatile, self.epoch);
+        self.used += entry.size;
+        self.entries.insert(key, entry);
+    }
+
+    pub fn contains_key(&mut self, key: &[u8]) -> bool {
+        self.expire_if_needed(key);
+        self.entries.contains_key(key)
+    }
+
+    // insert replaces the value stored at key. If the key already exists its
+    // deadline is kept, as modifying a value (APPEND, INCR, ...) does not
+    // reset its time to live.
+    pub fn insert(&mut self, key: Bytes, value: Value) -> Option<Value> {
+        self.expire_if_needed(&key);
+        self.save_before_write(&key);
+        match self.entries.get_mut(&key) {
+            Some(e) => {
+                e.touch();
+                let size = entry_size(&key, &value, SIZE_SAMPLES);
+                self.used = self.used - e.size + size;
+                e.size = size;
+                Some(std::mem::replace(&mut e.value, value))
+            }
+            None => {
+                self.add(key, value, None);
+                None
+            }
+        }
+    }
+
+    // set stores value at key, discarding any previous value and deadline.
+    pub fn set(&mut self, key: Bytes, value: Value, expires_at: Option<u64>) -> Option<Value> {
+        let old = self.remove(&key);
+        self.add(key, value, expires_at);
+        old
+    }
+
+    // expires_at returns the deadline of key, or None if the key doesn't
+    // exist.
+    pub fn expires_at(&mut self, key: &[u8]) -> Option<Option<u64>> {
+        self.expire_if_needed(key);
+        self.entries.get(key).map(|e| e.expires_at)
+    }
+
+    // set_expires_at updates the deadline of key, returns false if the key
+    // doesn't exist.
+    pub fn set_expires_at(&mut self, key: &Bytes, expires_at: Option<u64>) -> bool {
+        self.expire_if_needed(key);
+        self.save_before_write(key);
+        let entry = match self.entries.get_mut(key) {
+            Some(e) => e,
+            None => return false,
+        };
+        let (old, volatile) = (std::mem::replace(&mut entry.expires_at, expires_at), entry.volatile);
+        match old {
+            Some(t) => {
+                self.expires.remove(&(t, key.clone()));
+                if expires_at.is_none() {
+                    unslot(&mut self.entries, &mut self.volatile, volatile, |e| &mut e.volatile);
+                }
+            }
+            None if expires_at.is_some() => {
+                entry.volatile = self.volatile.len();
+                self.volatile.push(key.clone());
+            }
+            None => {}
+        }
+        if let Some(t) = expires_at {
+            self.expires.insert((t, key.clone()));
+        }
+        true
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        self.save_before_write(key);
+        match self.entries.remove_entry(key) {
+            Some((k, e)) => {
+                self.scan.remove(&(scan_hash(&k), k.clone()));
+                self.used -= e.size;
+                unslot(&mut self.entries, &mut self.slots, e.slot, |e| &mut e.slot);
+                // the entry moved in its place has to be accounted from its
+                // new slot, which is already queued if the removed entry was
+                // dirty
+                if let Some(moved) = self.slots.get(e.slot) {
+                    if self.entries[moved].dirty && !e.dirty {
+                        self.dirty.push(e.slot);
+                    }
+                }
+                if let Some(t) = e.expires_at {
+                    self.expires.remove(&(t, k));
+                    unslot(&mut self.entries, &mut self.volatile, e.volatile, |e| &mut e.volatile);
+                }
+                Some(e.value)
+            }
+            None => None,
+        }
+    }
+
+    // take removes key and returns its value along with its deadline.
+    pub fn take(&mut self, key: &[u8]) -> Option<(Value, Option<u64>)> {
+        let expires_at = self.expires_at(key)?;
+        self.remove(key).map(|value| (value, expires_at))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        let now = now_ms();
+        self.entries
+            .iter()
+            .filter(move |(_, e)| e.expires_at.is_none_or(|t| t > now))
+            .map(|(k, _)| k)
+    }
+
+    // lock holds back the commands on keys hashing to hash until unlock is
+    // called, returns false if hash is already locked.
+    pub fn lock(&mut self, hash: u64) -> bool {
+        if self.locked.contains_key(&hash) {
+            return false;
+        }
+        self.locked.insert(hash, Vec::new());
+        true
+    }
+
+    pub fn unlock(&mut self, hash: u64) {
+        if let Some(waiters) = self.locked.remove(&hash) {
+            for tx in waiters.into_iter() {
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    // lock_waiter returns a receiver completed once the first of hashes that
+    // is locked gets unlocked, or None if none of them is locked.
+    pub fn lock_waiter(&mut self, hashes: &[u64]) -> Option<oneshot::Receiver<()>> {
+        let hash = hashes.iter().find(|h| self.locked.contains_key(h))?;
+        let waiters = self.locked.get_mut(hash).unwrap();
+        let (tx, rx) = oneshot::channel();
+        waiters.push(tx);
+        Some(rx)
+    }
+
+    // scan returns at least count keys (fewer at the end of the shard) in
+    // scan_hash order, starting at the first key whose scan_hash is at
+    // least from. It also returns the scan_hash to resume from, or None once
+    // the end of the shard was reached. Keys sharing a scan_hash are never
+    // split across calls, so none of them can be skipped.
+    pub fn scan(&self, from: u64, count: usize) -> (Vec<Bytes>, Option<u64>) {
+        let mut keys = Vec::with_capacity(cmp::min(count, self.scan.len()));
+        let mut last = None;
+        for (h, key) in self.scan.range((from, Bytes::new())..) {
+            if keys.len() >= count && last != Some(*h) {
+                return (keys, Some(*h));
+            }
+            keys.push(key.clone());
+            last = Some(*h);
+        }
+        (keys, None)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // entry returns the entry of key without recording an access to it.
+    pub fn entry(&mut self, key: &[u8]) -> Option<&Entry> {
+        self.expire_if_needed(key);
+        self.entries.get(key)
+    }
+
+    // usage estimates the number of bytes used by key, sampling samples
+    // elements of a collection (all of them if samples is 0).
+    pub fn usage(&mut self, key: &[u8], samples: usize) -> Option<usize> {
+        self.expire_if_needed(key);
+        self.entries.get_key_value(key).map(|(key, e)| entry_size(key, &e.value, samples))
+    }
+
+    pub fn volatile_len(&self) -> usize {
+        self.volatile.len()
+    }
+
+    pub fn evicted(&self) -> usize {
+        self.evicted
+    }
+
+    // used returns the estimated size of the database, once the entries
+    // modified in place since the last call are accounted again.
+    pub fn used(&mut self) -> usize {
+        for slot in self.dirty.drain(..) {
+            let key = match self.slots.get(slot) {
+                Some(key) => key,
+                None => continue,
+            };
+            let entry = self.entries.get_mut(key).unwrap();
+            if entry.dirty {
+                entry.dirty = false;
+                let size = entry_size(key, &entry.value, SIZE_SAMPLES);
+                self.used = self.used - entry.size + size;
+                entry.size = size;
+            }
+        }
+        self.used
+    }
+
+    // random_key returns a random key, or None if the database is empty.
+    pub fn random_key(&mut self) -> Option<Bytes> {
+        let mut rng = rand::thread_rng();
+        // give up after a few expired keys, like redis does
+        for _ in 0..100 {
+            if self.slots.is_empty() {
+                return None;
+            }
+            let key = self.slots[rng.gen_range(0, self.slots.len())].clone();
+            if self.contains_key(&key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    // flush removes every key. When lazy is set the removed keys are freed
+    // on a background thread.
+    pub fn flush(&mut self, lazy: bool) {
+        if let Some(save) = self.saving.as_mut() {
+            let epoch = self.epoch;
+            for (key, e) in self.entries.iter_mut().filter(|(_, e)| e.saved < epoch) {
+                e.saved = epoch;
+                save.write(key, e);
+            }
+        }
+        let entries = std::mem::take(&mut self.entries);
+        let expires = std::mem::take(&mut self.expires);
+        let scan = std::mem::take(&mut self.scan);
+        let slots = std::mem::take(&mut self.slots);
+        let volatile = std::mem::take(&mut self.volatile);
+        self.used = 0;
+        self.dirty.clear();
+        if lazy {
+            drop_in_background(Box::new((entries, expires, scan, slots, volatile)));
+        }
+    }
+
+    // block registers waiter to be served by the next push to key.
+    pub fn block(&mut self, key: Bytes, waiter: Waiter) {
+        self.blocked.entry(key).or_default().push_back(waiter);
+    }
+
+    // serve_blocked hands elements of the list at key to the clients blocked
+    // on it, in the order they started waiting. It must be called whenever
+    // elements are added to a list.
+    pub fn serve_blocked(&mut self, key: &Bytes) {
+        if self.blocked.is_empty() {
+            return;
+        }
+        let mut waiters = match self.blocked.remove(key) {
+            Some(waiters) => waiters,
+            None => return,
+        };
+        let (mut empty, mut stalled) = (false, false);
+        self.save_before_write(key);
+        if let Some(Entry { value: Value::List(list), .. }) = self.entries.get_mut(key) {
+            while let Some(waiter) = waiters.pop_front() {
+                if list.is_empty() {
+                    waiters.push_front(waiter);
+                    break;
+                }
+                let ticket = if waiter.moves && !waiter.is_done() {
+                    match try_hold() {
+                        Some(ticket) => Some(ticket),
+                        None => {
+                            waiters.push_front(waiter);
+                            stalled = true;
+                            break;
+                        }
+                    }
+                } else {
+                    None
+                };
+                if !waiter.try_claim() {
+                    continue;
+                }
+                let value = if waiter.left { list.pop_front() } else { list.pop_back() }.unwrap();
+                // the client went away after being claimed, give the element back
+                if let Err(e) = waiter.tx.unbounded_send((key.clone(), value, ticket)) {
+                    let (_, value, _) = e.into_inner();
+                    if waiter.left {
+                        list.push_front(value);
+                    } else {
+                        list.push_back(value);
+                    }
+                } else {
+                    let pop = if waiter.left { "LPOP" } else { "RPOP" };
+                    aof::defer(self.number, vec![Bytes::from(pop), key.clone()]);
+                }
+            }
+            empty = list.is_empty();
+        }
+        if empty {
+            self.remove(key);
+        }
+        if stalled {
+            self.stall(key.clone());
+        }
+        if !waiters.is_empty() {
+            self.blocked.insert(key.clone(), waiters);
+        }
+    }
+
+    // stall records that the clients blocked on key couldn't be served
+    // while the gate was closed to holders, see try_hold.
+    pub fn stall(&mut self, key: Bytes) {
+        if !self.stalled.contains(&key) {
+            self.stalled.push(key);
+        }
+    }
+
+    // serve_stalled serves the clients blocked on the keys stalled since
+    // the last call, unless the gate is still closed.
+    pub fn serve_stalled(&mut self) {
+        for key in self.stalled.split_off(0) {
+            self.serve_blocked(&key);
+        }
+    }
+
+    // prune_blocked drops the waiters of clients that have already been
+    // served or have timed out.
+    pub fn prune_blocked(&mut self) {
+        self.blocked.retain(|_, waiters| {
+            waiters.retain(|w| !w.is_done());
+            !waiters.is_empty()
+        });
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.entries.shrink_to_fit();
+    }
+
+    // eviction_candidate samples keys according to policy and returns the
+    // best one to evict along with its score, the higher the better.
+    // volatile-ttl doesn't need to sample, the key closest to its deadline
+    // is at hand in `expires`.
+    fn eviction_candidate(&self, policy: Policy, samples: usize) -> Option<(u64, Bytes)> {
+        let keys = match policy {
+            Policy::NoEviction => return None,
+            Policy::VolatileTtl => return self.expires.iter().next().map(|(t, key)| (u64::MAX - t, key.clone())),
+            Policy::AllKeysLru | Policy::AllKeysLfu | Policy::AllKeysRandom => &self.slots,
+            Policy::VolatileLru | Policy::VolatileLfu | Policy::VolatileRandom => &self.volatile,
+        };
+        if keys.is_empty() {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        let now = lru_clock();
+        let mut best: Option<(u64, &Bytes)> = None;
+        for _ in 0..samples {
+            let key = &keys[rng.gen_range(0, keys.len())];
+            let entry = &self.entries[key];
+            let score = match policy {
+                Policy::AllKeysLru | Policy::VolatileLru => entry.idle(now) as u64,
+                Policy::AllKeysLfu | Policy::VolatileLfu => 255 - entry.freq(now) as u64,
+                _ => rng.gen(),
+            };
+            if best.is_none_or(|(s, _)| score > s) {
+                best = Some((score, key));
+            }
+        }
+        best.map(|(score, key)| (score, key.clone()))
+    }
+
+    // begin_save starts a snapshot of the database, numbered db, in format.
+    // The snapshot holds the keys as they are now: they are written out by
+    // save_step, and any key about to be modified or removed before then
+    // is written first. The snapshot gets a new epoch, the keys it has to
+    // write are those last saved in an earlier one, keys added from now on
+    // belong to it already.
+    pub fn begin_save(&mut self, db: usize, format: Format) {
+        self.epoch += 1;
+        self.saving = Some(Saving {
+            db,
+            cursor: None,
+            buf: BytesMut::new(),
+            format,
+        });
+    }
+
+    // save_step writes up to n more keys of the snapshot in progress, and
+    // returns the database number of the snapshot, the records written
+    // since the last step, the number of keys looked at and whether the
+    // snapshot is complete. It returns None if no snapshot is in progress.
+    pub fn save_step(&mut self, n: usize) -> Option<(usize, Bytes, usize, bool)> {
+        let save = self.saving.as_mut()?;
+        let start = save.cursor.clone().map_or(Bound::Unbounded, Bound::Excluded);
+        let (mut done, mut finished) = (0, true);
+        for (hash, key) in self.scan.range((start, Bound::Unbounded)) {
+            if done == n {
+                finished = false;
+                break;
+            }
+            if let Some(e) = self.entries.get_mut(key) {
+                if e.saved < self.epoch {
+                    e.saved = self.epoch;
+                    save.write(key, e);
+                }
+            }
+            save.cursor = Some((*hash, key.clone()));
+            done += 1;
+        }
+        let step = (save.db, save.buf.take().freeze(), done, finished);
+        if finished {
+            self.saving = None;
+        }
+        Some(step)
+    }
+
+    pub fn abort_save(&mut self) {
+        self.saving = None;
+    }
+
+    // save_before_write writes key to the snapshot in progress, if it
+    // hasn't been yet, before it is modified.
+    fn save_before_write(&mut self, key: &[u8]) {
+        if let Some(save) = self.saving.as_mut() {
+            if let Some(e) = self.entries.get_mut(key) {
+                if e.saved < self.epoch {
+                    e.saved = self.epoch;
+                    save.write(key, e);
+                }
+            }
+        }
+    }
+
+    // evicts up to `limit` keys whose deadline has passed, returns the
+    // number of keys removed.
+    pub fn expire_cycle(&mut self, limit: usize) -> usize {
+        let now = now_ms();
+        let mut removed = 0;
+        while removed < limit {
+            let key = match self.expires.iter().next() {
+                Some((t, k)) if *t <= now => k.clone(),
+                _ => break,
+            };
+            self.expire(&key);
+            removed += 1;
+        }
+        removed
+    }
+}
 
 thread_local! {
     static DB: *mut DBState = Box::into_raw(Box::new(DBState::None));
 }
 
-pub fn execute(command: &dyn Execute) -> Result<resp::Msg, command::Error> {
-    DB.with(|f| match unsafe { **f } {
-        DBState::None => {
-            let mut db = Box::new(Database::default());
-            let r = command.exec(&mut db);
-            unsafe {
-                **f = DBState::Ready(Box::into_raw(db));
+// databases returns the empty databases of a shard.
+pub fn databases() -> Vec<Database> {
+    (0..DATABASES)
+        .map(|number| Database {
+            number,
+            ..Default::default()
+        })
+        .collect()
+}
+
+// with_dbs calls f with the databases of the current thread. f must not
+// call with_dbs (or execute) itself. The commands f fed to the AOF are
+// flushed once it returns.
+pub fn with_dbs<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut [Database]) -> T,
+{
+    DB.with(|s| {
+        let dbs_ptr = match unsafe { **s } {
+            DBState::None => {
+                let dbs = databases();
+                let dbs_ptr = Box::into_raw(Box::new(dbs));
+                unsafe {
+                    **s = DBState::Ready(dbs_ptr);
+                }
+                dbs_ptr
             }
+            DBState::Ready(dbs_ptr) => dbs_ptr,
+        };
+        let result = f(unsafe { &mut *dbs_ptr });
+        aof::flush();
+        result
+    })
+}
 
-            r
+// execute runs command on the database numbered db of the current thread.
+// Commands that may grow the dataset (denyoom) first make room for
+// themselves, and fail if the shard is still above its share of
+// maxmemory. args are the arguments the command was called with, when
+// the AOF is enabled.
+pub fn execute(command: &dyn Execute, db: usize, denyoom: bool, args: Option<Vec<Bytes>>) -> Result<resp::Msg, command::Error> {
+    with_dbs(|dbs| {
+        if denyoom && !evict(dbs) {
+            return Err(command::Error::Oom);
         }
-        DBState::Ready(db_ptr) => {
-            let db = unsafe { &mut *db_ptr };
-            command.exec(db)
+        exec(command, &mut dbs[db], args)
+    })
+}
+
+// exec runs command on db, and feeds it to the AOF if it succeeded and may
+// have written.
+pub fn exec(command: &dyn Execute, db: &mut Database, args: Option<Vec<Bytes>>) -> Result<resp::Msg, command::Error> {
+    let reply = command.exec(db)?;
+    if let Some(args) = args {
+        if command.writes() {
+            for args in command.propagate(args, db, &reply) {
+                aof::feed(db.number, &args);
+            }
         }
+    }
+    Ok(reply)
+}
+
+// evict evicts keys from dbs, the databases of a shard, according to the
+// maxmemory policy until the shard uses no more than its share of
+// maxmemory. It returns false if it couldn't get there.
+//
+// The memory used is estimated from the size of the keys and values
+// stored, which doesn't account for the overhead of the hash tables or of
+// the allocator.
+pub fn evict(dbs: &mut [Database]) -> bool {
+    let limit = maxmemory();
+    if limit == 0 {
+        return true;
+    }
+    let limit = limit / shards();
+    let (policy, samples) = (policy(), MAXMEMORY_SAMPLES.load(Ordering::Relaxed));
+    let mut used: usize = dbs.iter_mut().map(|db| db.used()).sum();
+    while used > limit {
+        let victim = dbs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, db)| db.eviction_candidate(policy, samples).map(|(score, key)| (score, i, key)))
+            .max_by_key(|(score, _, _)| *score);
+        match victim {
+            Some((_, i, key)) => {
+                let before = dbs[i].used();
+                dbs[i].remove(&key);
+                dbs[i].evicted += 1;
+                aof::feed(i, &[&b"DEL"[..], &key[..]]);
+                used -= before - dbs[i].used();
+            }
+            None => return false,
+        }
+    }
+    true
+}
+
+// evict_everywhere runs evict on every shard, it returns false if any of
+// them couldn't get under its share of maxmemory.
+pub async fn evict_everywhere(pool: &tokio_io_pool::Handle) -> bool {
+    if maxmemory() == 0 {
+        return true;
+    }
+    with_every_shard_dbs(pool, evict).await.into_iter().all(|ok| ok)
+}
+
+// unlocked waits until none of hashes is locked in any of the databases
+// dbs of the current thread. The caller must use the databases without
+// awaiting anything else in between, or another lock may be taken.
+pub async fn unlocked(dbs: &[usize], hashes: &[u64]) {
+    while let Some(rx) = with_dbs(|all| dbs.iter().filter_map(|&db| all[db].lock_waiter(hashes)).next()) {
+        let _ = rx.await;
+    }
+}
+
+// LOADING is set while the AOF is replayed, keys don't expire and aren't
+// evicted meanwhile.
+static LOADING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_loading(loading: bool) {
+    LOADING.store(loading, Ordering::SeqCst);
+}
+
+pub fn loading() -> bool {
+    LOADING.load(Ordering::Relaxed)
+}
+
+// SHARDS is the number of shards, one per worker of the pool. Shard i is
+// owned by worker i.
+static SHARDS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn set_shards(n: usize) {
+    tick_clock();
+    SHARDS.store(n, Ordering::SeqCst);
+}
+
+pub fn shards() -> usize {
+    SHARDS.load(Ordering::SeqCst)
+}
+
+// with_every_shard_dbs calls f with the databases of every shard,
+// concurrently, and returns the results in shard order.
+pub async fn with_every_shard_dbs<F, T>(pool: &tokio_io_pool::Handle, f: F) -> Vec<T>
+where
+    F: Fn(&mut [Database]) -> T + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    future::join_all((0..shards()).map(|i| with_shard_dbs(pool, i as u64, f.clone()))).await
+}
+
+// with_every_shard is like with_every_shard_dbs, for the database
+// numbered db only.
+pub async fn with_every_shard<F, T>(pool: &tokio_io_pool::Handle, db: usize, f: F) -> Vec<T>
+where
+    F: Fn(&mut Database) -> T + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    with_every_shard_dbs(pool, move |dbs| f(&mut dbs[db])).await
+}
+
+// with_shard_dbs calls f with the databases of the worker that owns shard
+// and returns its result.
+pub async fn with_shard_dbs<F, T>(pool: &tokio_io_pool::Handle, shard: u64, f: F) -> T
+where
+    F: FnOnce(&mut [Database]) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (p, c) = oneshot::channel::<T>();
+    let fut = async move {
+        let _ = p.send(with_dbs(f));
+    };
+    let _ = pool.spawn_on(shard, fut);
+    c.await.unwrap()
+}
+
+// with_shard is like with_shard_dbs, for the database numbered db only.
+pub async fn with_shard<F, T>(pool: &tokio_io_pool::Handle, shard: u64, db: usize, f: F) -> T
+where
+    F: FnOnce(&mut Database) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    with_shard_dbs(pool, shard, move |dbs| f(&mut dbs[db])).await
+}
+
+// with_unlocked_dbs is like with_shard_dbs, but waits for none of hashes
+// to be locked in any of the databases dbs before calling f.
+pub async fn with_unlocked_dbs<F, T>(pool: &tokio_io_pool::Handle, shard: u64, dbs: Vec<usize>, hashes: Vec<u64>, f: F) -> T
+where
+    F: FnOnce(&mut [Database]) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (p, c) = oneshot::channel::<T>();
+    let fut = async move {
+        unlocked(&dbs, &hashes).await;
+        let _ = p.send(with_dbs(f));
+    };
+    let _ = pool.spawn_on(shard, fut);
+    c.await.unwrap()
+}
+
+// with_unlocked is like with_unlocked_dbs, for the database numbered db
+// only.
+pub async fn with_unlocked<F, T>(pool: &tokio_io_pool::Handle, shard: u64, db: usize, hashes: Vec<u64>, f: F) -> T
+where
+    F: FnOnce(&mut Database) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    with_unlocked_dbs(pool, shard, vec![db], hashes, move |dbs| f(&mut dbs[db])).await
+}
+
+// lock locks hash in the database db of the shard owning it, waiting for
+// it to be unlocked first if needed.
+async fn lock(pool: &tokio_io_pool::Handle, db: usize, hash: u64) {
+    loop {
+        let waiter = with_shard(pool, hash, db, move |db| if db.lock(hash) { None } else { db.lock_waiter(&[hash]) }).await;
+        match waiter {
+            Some(rx) => {
+                let _ = rx.await;
+            }
+            None => return,
+        }
+    }
+}
+
+async fn unlock(pool: &tokio_io_pool::Handle, db: usize, hash: u64) {
+    with_shard(pool, hash, db, move |db| db.unlock(hash)).await
+}
+
+// Pass is the way a command goes through the gate. Commands locking keys
+// owned by several workers (lock_keys) hold the gate together, and so do
+// the commands looking at every shard (KEYS, DBSIZE) which need no such
+// lock to be held while they do. A command changing every shard (SWAPDB)
+// goes through alone.
+#[derive(Clone, Copy, PartialEq)]
+enum Pass {
+    Hold,
+    Quiesce,
+    Exclusive,
+}
+
+// Gate counts the commands that went through with each pass. Commands
+// that have to wait are let through in the order they came, so that a
+// stream of one kind can't starve the other.
+#[derive(Default)]
+struct Gate {
+    holders: usize,
+    quiescers: usize,
+    exclusive: bool,
+    next_id: u64,
+    queue: VecDeque<(u64, Pass, oneshot::Sender<()>)>,
+}
+
+impl Gate {
+    fn admits(&self, pass: Pass) -> bool {
+        match pass {
+            Pass::Hold => self.quiescers == 0 && !self.exclusive,
+            Pass::Quiesce => self.holders == 0 && !self.exclusive,
+            Pass::Exclusive => self.holders == 0 && self.quiescers == 0 && !self.exclusive,
+        }
+    }
+
+    fn enter(&mut self, pass: Pass) {
+        match pass {
+            Pass::Hold => self.holders += 1,
+            Pass::Quiesce => self.quiescers += 1,
+            Pass::Exclusive => self.exclusive = true,
+        }
+    }
+
+    fn leave(&mut self, pass: Pass) {
+        match pass {
+            Pass::Hold => self.holders -= 1,
+            Pass::Quiesce => self.quiescers -= 1,
+            Pass::Exclusive => self.exclusive = false,
+        }
+        self.admit();
+    }
+
+    // admit lets the waiting commands the gate now admits through, on
+    // their behalf. One dropped meanwhile leaves when its ticket is.
+    fn admit(&mut self) {
+        while let Some(&(_, pass, _)) = self.queue.front() {
+            if !self.admits(pass) {
+                return;
+            }
+            let (_, pass, tx) = self.queue.pop_front().unwrap();
+            self.enter(pass);
+            let _ = tx.send(());
+        }
+    }
+}
+
+lazy_static! {
+    static ref GATE: Mutex<Gate> = Mutex::new(Gate::default());
+}
+
+// Ticket is a pass through the gate, it is given back when dropped, even
+// if the command was dropped while still waiting for it.
+pub struct Ticket {
+    pass: Pass,
+    id: u64,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut gate = GATE.lock().unwrap();
+        match gate.queue.iter().position(|&(id, _, _)| id == self.id) {
+            Some(i) => {
+                gate.queue.remove(i);
+                gate.admit();
+            }
+            None => gate.leave(self.pass),
+        }
+    }
+}
+
+async fn enter(pass: Pass) -> Ticket {
+    let (ticket, rx) = {
+        let mut gate = GATE.lock().unwrap();
+        gate.next_id += 1;
+        let ticket = Ticket { pass, id: gate.next_id };
+        if gate.queue.is_empty() && gate.admits(pass) {
+            gate.enter(pass);
+            return ticket;
+        }
+        let (tx, rx) = oneshot::channel();
+        gate.queue.push_back((ticket.id, pass, tx));
+        (ticket, rx)
+    };
+    let _ = rx.await;
+    ticket
+}
+
+// try_hold holds the gate like lock_keys does, if that can be done without
+// waiting. It is how a shard moving an element to another one on behalf of
+// a blocked client (BRPOPLPUSH) keeps it from being seen in neither.
+pub fn try_hold() -> Option<Ticket> {
+    let mut gate = GATE.lock().unwrap();
+    if !gate.queue.is_empty() || !gate.admits(Pass::Hold) {
+        return None;
+    }
+    gate.next_id += 1;
+    gate.enter(Pass::Hold);
+    Some(Ticket {
+        pass: Pass::Hold,
+        id: gate.next_id,
     })
 }
 
+// quiesce waits until no command holds keys locked by lock_keys (or moves
+// an element for a blocked client, see try_hold), and keeps new ones from
+// locking any until the returned ticket is dropped. It lets a command look
+// at every shard without seeing a value in two places, or in none, while
+// it is moved between them.
+pub async fn quiesce() -> Ticket {
+    enter(Pass::Quiesce).await
+}
+
+// exclusive is like quiesce, but also waits for and holds back the other
+// commands that quiesce, which mustn't see the change a command makes to
+// every shard half done.
+pub async fn exclusive() -> Ticket {
+    enter(Pass::Exclusive).await
+}
+
+// Locks are the key hashes locked by lock_keys, to be handed back to
+// unlock_keys.
+pub struct Locks {
+    db: usize,
+    hashes: Vec<u64>,
+    _ticket: Ticket,
+}
+
+// lock_keys locks hashes, the hashes (as returned by command::shard_for)
+// of keys owned by several workers, in the database db. Every other
+// command on these keys waits until unlock_keys is called, so that a
+// command moving or combining their values across shards acts on all of
+// them at once. The holder must run its steps with with_shard, and must
+// not wait for other clients before unlocking.
+pub async fn lock_keys(pool: &tokio_io_pool::Handle, db: usize, mut hashes: Vec<u64>) -> Locks {
+    let ticket = enter(Pass::Hold).await;
+    // two commands locking the same hashes in the same order can't wait
+    // for each other
+    hashes.sort();
+    hashes.dedup();
+    for &hash in hashes.iter() {
+        lock(pool, db, hash).await;
+    }
+    Locks { db, hashes, _ticket: ticket }
+}
+
+pub async fn unlock_keys(pool: &tokio_io_pool::Handle, locks: Locks) {
+    for &hash in locks.hashes.iter().rev() {
+        unlock(pool, locks.db, hash).await;
+    }
+}
+
 #[allow(dead_code)]
 pub fn reclaim() {
     DB.with(|f| match unsafe { **f } {
         DBState::None => {}
-        DBState::Ready(db_ptr) => {
-            let db = unsafe { &mut *db_ptr };
-            db.shrink_to_fit();
+        DBState::Ready(dbs_ptr) => {
+            for db in unsafe { &mut *dbs_ptr }.iter_mut() {
+                db.shrink_to_fit();
+            }
         }
     });
 }
+
+// cron runs the periodic maintenance of the shard it is spawned on. It must
+// be spawned once on every worker of the pool. Besides expiring keys it
+// evicts them when commands that don't call evict themselves (coordinated
+// ones, or parts of scattered ones) took the shard above its share of
+// maxmemory.
+pub async fn cron() {
+    let mut interval = Interval::new_interval(ACTIVE_EXPIRE_CYCLE_INTERVAL);
+    while interval.next().await.is_some() {
+        tick_clock();
+        if loading() {
+            continue;
+        }
+        with_dbs(|dbs| {
+            for db in dbs.iter_mut() {
+                db.expire_cycle(ACTIVE_EXPIRE_CYCLE_KEYS);
+                db.prune_blocked();
+                db.serve_stalled();
+                // accounts the entries modified in place, so that they don't
+                // pile up when nothing else asks for the memory used
+                db.used();
+            }
+            evict(dbs);
+        });
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::VecDeque;
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{BufMut, Bytes, BytesMut};
+use lazy_static::lazy_static;
+
+use super::database::{HashValue, Scalar, SetValue, SortedSetValue, Value};
+use super::indexset::IndexSet;
+use super::intset::IntSet;
+
+// VERSION is the version of the serialization format. Payloads written by
+// a newer version are refused, as they may hold types we can't decode.
+pub const VERSION: u16 = 1;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_INTEGER: u8 = 1;
+const TYPE_LIST: u8 = 2;
+const TYPE_HASH: u8 = 3;
+const TYPE_SET: u8 = 4;
+const TYPE_INTSET: u8 = 5;
+const TYPE_ZSET: u8 = 6;
+
+const CORRUPT: &str = "Bad data format";
+
+// CRC64_POLY is the reflected Jones polynomial, the CRC64 variant redis
+// uses for its own DUMP payloads.
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+lazy_static! {
+    static ref CRC64_TABLE: [u64; 256] = {
+        let mut table = [0u64; 256];
+        for (i, slot) in table.iter_mut().enumerate() {
+            let mut crc = i as u64;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ CRC64_POLY } else { crc >> 1 };
+            }
+            *slot = crc;
+        }
+        table
+    };
+}
+
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, b| CRC64_TABLE[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8))
+}
+
+// dump serializes value as a DUMP payload: the encoded value, followed by
+// the format version and the CRC64 of everything before it, both little
+// endian.
+pub fn dump(value: &Value) -> Bytes {
+    let mut buf = BytesMut::new();
+    write_value(&mut buf, value);
+    buf.reserve(10);
+    buf.put_u16_le(VERSION);
+    let crc = crc64(0, &buf);
+    buf.put_u64_le(crc);
+    buf.freeze()
+}
+
+// restore checks the trailer of a DUMP payload and decodes the value.
+pub fn restore(payload: &[u8]) -> Result<Value, &'static str> {
+    if payload.len() < 10 {
+        return Err("DUMP payload is truncated");
+    }
+    let (body, crc) = payload.split_at(payload.len() - 8);
+    if crc64(0, body) != LittleEndian::read_u64(crc) {
+        return Err("DUMP payload checksum is wrong");
+    }
+    let (body, version) = body.split_at(body.len() - 2);
+    if LittleEndian::read_u16(version) > VERSION {
+        return Err("DUMP payload version is not supported");
+    }
+    let mut r = Reader::new(body);
+    let value = r.value()?;
+    if r.remaining() != 0 {
+        return Err(CORRUPT);
+    }
+    Ok(value)
+}
+
+// put_len writes n as an unsigned LEB128 varint, so that the many short
+// strings and small collections only take a byte of length.
+pub fn put_len(buf: &mut BytesMut, mut n: usize) {
+    buf.reserve(10);
+    while n >= 0x80 {
+        buf.put_u8((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.put_u8(n as u8);
+}
+
+pub fn put_bytes(buf: &mut BytesMut, b: &[u8]) {
+    put_len(buf, b.len());
+    buf.extend_from_slice(b);
+}
+
+fn put_scalar(buf: &mut BytesMut, s: &Scalar) {
+    match s {
+        Scalar::String(b) => {
+            buf.reserve(1);
+            buf.put_u8(TYPE_STRING);
+            put_bytes(buf, b);
+        }
+        Scalar::Integer(i) => {
+            buf.reserve(9);
+            buf.put_u8(TYPE_INTEGER);
+            buf.put_i64_le(*i);
+        }
+    }
+}
+
+pub fn write_value(buf: &mut BytesMut, value: &Value) {
+    match value {
+        Value::Scalar(s) => put_scalar(buf, s),
+        Value::List(list) => {
+            buf.reserve(1);
+            buf.put_u8(TYPE_LIST);
+            put_len(buf, list.len());
+            for item in list.iter() {
+                put_bytes(buf, item);
+            }
+        }
+        Value::HashMap(hash) => {
+            buf.reserve(1);
+            buf.put_u8(TYPE_HASH);
+            put_len(buf, hash.len());
+            for (field, value) in hash.iter() {
+                put_bytes(buf, field);
+                put_scalar(buf, value);
+            }
+        }
+        Value::Set(SetValue::Int(set)) => {
+            buf.reserve(1);
+            buf.put_u8(TYPE_INTSET);
+            put_len(buf, set.len());
+            buf.reserve(set.len() * 8);
+            for i in set.iter() {
+                buf.put_i64_le(*i);
+            }
+        }
+        Value::Set(SetValue::Hash(set)) => {
+            buf.reserve(1);
+            buf.put_u8(TYPE_SET);
+            put_len(buf, set.len());
+            for member in set.iter() {
+                put_bytes(buf, member);
+            }
+        }
+        Value::SortedSet(zset) => {
+            buf.reserve(1);
+            buf.put_u8(TYPE_ZSET);
+            put_len(buf, zset.len());
+            for (member, score) in zset.iter() {
+                put_bytes(buf, member);
+                buf.reserve(8);
+                buf.put_f64_le(score);
+            }
+        }
+    }
+}
+
+// Reader decodes the values and lengths written by write_value and
+// put_len from a buffer.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if self.remaining() < n {
+            return Err(CORRUPT);
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn len(&mut self) -> Result<usize, &'static str> {
+        let mut n = 0usize;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            n |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(CORRUPT)
+    }
+
+    // capacity bounds a decoded length by the bytes left, so a corrupt
+    // length can't make us allocate more than the payload could hold.
+    fn capacity(&self, n: usize) -> usize {
+        std::cmp::min(n, self.remaining())
+    }
+
+    pub fn bytes(&mut self) -> Result<Bytes, &'static str> {
+        let n = self.len()?;
+        Ok(Bytes::from(self.take(n)?))
+    }
+
+    fn i64(&mut self) -> Result<i64, &'static str> {
+        Ok(LittleEndian::read_i64(self.take(8)?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, &'static str> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    fn f64(&mut self) -> Result<f64, &'static str> {
+        let f = LittleEndian::read_f64(self.take(8)?);
+        if f.is_nan() {
+            return Err(CORRUPT);
+        }
+        Ok(f)
+    }
+
+    fn scalar(&mut self) -> Result<Scalar, &'static str> {
+        match self.u8()? {
+            TYPE_STRING => Ok(Scalar::String(self.bytes()?)),
+            TYPE_INTEGER => Ok(Scalar::Integer(self.i64()?)),
+            _ => Err(CORRUPT),
+        }
+    }
+
+    pub fn value(&mut self) -> Result<Value, &'static str> {
+        let value = match self.u8()? {
+            TYPE_STRING => Value::Scalar(Scalar::String(self.bytes()?)),
+            TYPE_INTEGER => Value::Scalar(Scalar::Integer(self.i64()?)),
+            TYPE_LIST => {
+                let n = self.len()?;
+                let mut list = VecDeque::with_capacity(self.capacity(n));
+                for _ in 0..n {
+                    list.push_back(self.bytes()?);
+                }
+                Value::List(list)
+            }
+            TYPE_HASH => {
+                let n = self.len()?;
+                let mut hash = HashValue::with_capacity(self.capacity(n));
+                for _ in 0..n {
+                    let field = self.bytes()?;
+                    hash.insert(field, self.scalar()?);
+                }
+                Value::HashMap(hash)
+            }
+            TYPE_INTSET => {
+                let n = self.len()?;
+                let mut set = IntSet::default();
+                for _ in 0..n {
+                    set.insert(self.i64()?);
+                }
+                Value::Set(SetValue::Int(set))
+            }
+            TYPE_SET => {
+                let n = self.len()?;
+                let mut set = IndexSet::with_capacity(self.capacity(n));
+                for _ in 0..n {
+                    set.insert(self.bytes()?);
+                }
+                Value::Set(SetValue::Hash(set))
+            }
+            TYPE_ZSET => {
+                let n = self.len()?;
+                let mut zset = SortedSetValue::default();
+                for _ in 0..n {
+                    let member = self.bytes()?;
+                    zset.insert(member, self.f64()?);
+                }
+                Value::SortedSet(zset)
+            }
+            _ => return Err(CORRUPT),
+        };
+        // collections are never stored empty
+        match &value {
+            Value::List(l) if l.is_empty() => Err(CORRUPT),
+            Value::HashMap(h) if h.is_empty() => Err(CORRUPT),
+            Value::Set(s) if s.len() == 0 => Err(CORRUPT),
+            Value::SortedSet(z) if z.len() == 0 => Err(CORRUPT),
+            _ => Ok(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: &Value) -> Value {
+        restore(&dump(value)).unwrap()
+    }
+
+    fn scalar_eq(a: &Scalar, b: &Scalar) -> bool {
+        match (a, b) {
+            (Scalar::String(a), Scalar::String(b)) => a == b,
+            (Scalar::Integer(a), Scalar::Integer(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    // same compares the contents of two values, hashes and sets by lookup
+    // as their iteration order depends on how they were built.
+    fn same(a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Scalar(a), Value::Scalar(b)) => scalar_eq(a, b),
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::HashMap(a), Value::HashMap(b)) => {
+                a.len() == b.len() && a.iter().all(|(f, v)| b.get(f).is_some_and(|w| scalar_eq(v, w)))
+            }
+            (Value::Set(SetValue::Int(a)), Value::Set(SetValue::Int(b))) => a.iter().eq(b.iter()),
+            (Value::Set(SetValue::Hash(a)), Value::Set(SetValue::Hash(b))) => {
+                a.len() == b.len() && a.iter().all(|m| b.contains(m))
+            }
+            (Value::SortedSet(a), Value::SortedSet(b)) => {
+                a.len() == b.len() && a.iter().all(|(m, s)| b.score(m) == Some(s))
+            }
+            _ => false,
+        }
+    }
+
+    fn values() -> Vec<Value> {
+        let mut hash = HashValue::with_capacity(0);
+        let mut set = IndexSet::with_capacity(0);
+        let mut ints = IntSet::default();
+        let mut zset = SortedSetValue::default();
+        for i in 0..300 {
+            hash.insert(Bytes::from(format!("f{}", i)), Scalar::String(Bytes::from(vec![b'x'; i])));
+            hash.insert(Bytes::from(format!("i{}", i)), Scalar::Integer(-(i as i64) << 40));
+            set.insert(Bytes::from(format!("m{}", i)));
+            ints.insert((i as i64 - 150) * 1_000_000_007);
+            zset.insert(Bytes::from(format!("z{}", i)), i as f64 / 3.0 - 20.0);
+        }
+        zset.insert(Bytes::from("inf"), f64::INFINITY);
+        vec![
+            Value::Scalar(Scalar::String(Bytes::new())),
+            Value::Scalar(Scalar::String(Bytes::from(vec![0xff; 1000]))),
+            Value::Scalar(Scalar::Integer(i64::MIN)),
+            Value::List((0..200).map(|i| Bytes::from(format!("{}", i))).collect()),
+            Value::HashMap(hash),
+            Value::Set(SetValue::Hash(set)),
+            Value::Set(SetValue::Int(ints)),
+            Value::SortedSet(zset),
+        ]
+    }
+
+    #[test]
+    fn crc64_check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn dump_and_restore() {
+        for value in values().iter() {
+            assert!(same(value, &round_trip(value)));
+        }
+    }
+
+    #[test]
+    fn restore_refuses_bad_payloads() {
+        let payload = dump(&Value::List((0..3).map(|i| Bytes::from(format!("{}", i))).collect()));
+        let mut flipped = payload.to_vec();
+        flipped[2] ^= 1;
+        assert!(restore(&flipped).is_err());
+        assert!(restore(&payload[..payload.len() - 1]).is_err());
+        assert!(restore(&payload[..5]).is_err());
+
+        // a payload written by a newer version, with a valid checksum
+        let mut newer = payload[..payload.len() - 10].to_vec();
+        newer.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        let crc = crc64(0, &newer);
+        newer.extend_from_slice(&crc.to_le_bytes());
+        assert!(restore(&newer).is_err());
+
+        // a collection that claims more items than it holds
+        let mut short = vec![TYPE_LIST, 5, 1, b'a'];
+        short.extend_from_slice(&VERSION.to_le_bytes());
+        let crc = crc64(0, &short);
+        short.extend_from_slice(&crc.to_le_bytes());
+        assert!(restore(&short).is_err());
+    }
+}
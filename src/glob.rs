@@ -0,0 +1,124 @@
+// matches reports whether string matches the glob style pattern, following
+// the rules of redis' KEYS: `*` matches any sequence, `?` any single byte,
+// `[abc]`, `[^abc]` and `[a-z]` match classes of bytes and `\` escapes the
+// byte that follows it.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (s..=string.len()).any(|i| matches(&pattern[p + 1..], &string[i..]));
+            }
+            b'?' => {
+                if s == string.len() {
+                    return false;
+                }
+                s += 1;
+            }
+            b'[' => {
+                if s == string.len() {
+                    return false;
+                }
+                p += 1;
+                let not = p < pattern.len() && pattern[p] == b'^';
+                if not {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    if p >= pattern.len() {
+                        // unterminated class, stop at the end of the pattern
+                        p -= 1;
+                        break;
+                    }
+                    match pattern[p] {
+                        b'\\' if p + 1 < pattern.len() => {
+                            p += 1;
+                            matched |= pattern[p] == string[s];
+                        }
+                        b']' => break,
+                        c if p + 2 < pattern.len() && pattern[p + 1] == b'-' => {
+                            let (lo, hi) = if c <= pattern[p + 2] { (c, pattern[p + 2]) } else { (pattern[p + 2], c) };
+                            matched |= string[s] >= lo && string[s] <= hi;
+                            p += 2;
+                        }
+                        c => matched |= c == string[s],
+                    }
+                    p += 1;
+                }
+                if matched == not {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                let c = if c == b'\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    pattern[p]
+                } else {
+                    c
+                };
+                if s == string.len() || string[s] != c {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+    s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"*", b"anything"));
+        assert!(matches(b"h*o", b"hello"));
+        assert!(matches(b"h*o", b"ho"));
+        assert!(!matches(b"h*o", b"hellx"));
+        assert!(matches(b"h**o*", b"hello world"));
+        assert!(matches(b"*llo", b"hello"));
+        assert!(matches(b"h?llo", b"hallo"));
+        assert!(!matches(b"h?llo", b"hllo"));
+        assert!(!matches(b"?", b""));
+        assert!(matches(b"", b""));
+        assert!(!matches(b"", b"a"));
+        assert!(!matches(b"hello", b"hello!"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches(b"h[ae]llo", b"hello"));
+        assert!(matches(b"h[ae]llo", b"hallo"));
+        assert!(!matches(b"h[ae]llo", b"hillo"));
+        assert!(matches(b"h[^e]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"h[a-c]llo", b"hbllo"));
+        assert!(matches(b"h[c-a]llo", b"hbllo"));
+        assert!(!matches(b"h[a-c]llo", b"hdllo"));
+        assert!(matches(b"[\\]]", b"]"));
+        assert!(!matches(b"[a]", b""));
+        // an unterminated class ends with the pattern
+        assert!(matches(b"[ab", b"b"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(b"h\\*llo", b"h*llo"));
+        assert!(!matches(b"h\\*llo", b"hello"));
+        assert!(matches(b"h\\?", b"h?"));
+        assert!(!matches(b"h\\?", b"hi"));
+        // a trailing backslash matches itself
+        assert!(matches(b"a\\", b"a\\"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+
+use bytes::Bytes;
+use seahash::SeaHasher;
+
+use super::scanindex::ScanIndex;
+
+// IndexSet is a hash set whose members are also stored in a dense vector,
+// which lets SPOP and SRANDMEMBER pick uniformly random members in constant
+// time. Removals swap the last member into the hole left behind.
+#[derive(Default)]
+pub struct IndexSet {
+    members: Vec<Bytes>,
+    index: HashMap<Bytes, usize, BuildHasherDefault<SeaHasher>>,
+    scan: ScanIndex,
+    bytes: usize,
+}
+
+impl IndexSet {
+    pub fn with_capacity(n: usize) -> IndexSet {
+        IndexSet {
+            members: Vec::with_capacity(n),
+            index: HashMap::with_capacity_and_hasher(n, Default::default()),
+            scan: ScanIndex::default(),
+            bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    // bytes returns the total length of the members.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.index.contains_key(member)
+    }
+
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        if self.index.contains_key(&member) {
+            return false;
+        }
+        self.index.insert(member.clone(), self.members.len());
+        self.scan.insert(&member);
+        self.bytes += member.len();
+        self.members.push(member);
+        true
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.index.get(member) {
+            Some(&pos) => {
+                self.remove_at(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, pos: usize) -> &Bytes {
+        &self.members[pos]
+    }
+
+    pub fn remove_at(&mut self, pos: usize) -> Bytes {
+        let member = self.members.swap_remove(pos);
+        self.index.remove(&member);
+        self.scan.remove(&member);
+        self.bytes -= member.len();
+        if pos < self.members.len() {
+            self.index.insert(self.members[pos].clone(), pos);
+        }
+        member
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Bytes> {
+        self.members.iter()
+    }
+
+    // page returns a page of the members in hash order, see ScanIndex::page.
+    pub fn page(&mut self, cursor: u64, count: usize) -> (Vec<Bytes>, u64) {
+        self.scan.page(self.members.iter(), cursor, count)
+    }
+}
@@ -0,0 +1,64 @@
+use std::str;
+
+// IntSet is the compact encoding used by sets that only hold integers. The
+// members are kept sorted so lookups are a binary search, which is faster
+// than hashing (and far smaller) for the small sets it is used for.
+#[derive(Default)]
+pub struct IntSet(Vec<i64>);
+
+// encode returns the integer value of member if member is the canonical
+// decimal representation of an i64, so that decoding it gives back exactly
+// the same bytes.
+pub fn encode(member: &[u8]) -> Option<i64> {
+    if member.is_empty() || member.len() > 20 {
+        return None;
+    }
+    let i = str::from_utf8(member).ok()?.parse::<i64>().ok()?;
+    if i.to_string().as_bytes() == member {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+impl IntSet {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn contains(&self, i: i64) -> bool {
+        self.0.binary_search(&i).is_ok()
+    }
+
+    pub fn insert(&mut self, i: i64) -> bool {
+        match self.0.binary_search(&i) {
+            Ok(_) => false,
+            Err(pos) => {
+                self.0.insert(pos, i);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, i: i64) -> bool {
+        match self.0.binary_search(&i) {
+            Ok(pos) => {
+                self.0.remove(pos);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn get(&self, pos: usize) -> i64 {
+        self.0[pos]
+    }
+
+    pub fn remove_at(&mut self, pos: usize) -> i64 {
+        self.0.remove(pos)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &i64> {
+        self.0.iter()
+    }
+}
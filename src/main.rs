@@ -1,20 +1,86 @@
 use std::env;
 use std::thread;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
-use log::info;
+use log::{error, info, warn};
 use tokio::net::TcpListener;
 use tokio::prelude::*;
 
 use cpuprofiler::PROFILER;
 const DO_PROFILE: bool = false;
 
+mod aof;
 mod command;
 mod conn;
 mod database;
+mod dump;
+mod glob;
+mod indexset;
+mod intset;
+mod rdb;
 mod resp;
+mod scanindex;
+mod skiplist;
+mod snapshot;
 
-async fn listen(addr: SocketAddr, worker_pool: tokio_io_pool::Handle) {
+async fn listen(addr: SocketAddr, worker_pool: tokio_io_pool::Handle, import: Option<PathBuf>) {
+    // the dataset is loaded before accepting connections, so that no
+    // client ever sees a partially loaded dataset. The AOF is more recent
+    // than the snapshot, if there is one it is loaded instead.
+    let mut aof_rewrite = true;
+    let loaded = if aof::enabled() {
+        aof::load(&worker_pool).await
+    } else {
+        Ok(None)
+    };
+    match loaded {
+        Ok(Some((keys, rewrite))) => {
+            info!("Loaded {} keys from the append only file", keys);
+            aof_rewrite = rewrite;
+        }
+        Ok(None) => match snapshot::load(&worker_pool).await {
+            Ok(0) => (),
+            Ok(keys) => info!("Loaded {} keys from the snapshot", keys),
+            Err(e) => {
+                error!("Unable to load the snapshot: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            error!("Unable to load the append only file: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = import {
+        match rdb::import(&worker_pool, &path).await {
+            Ok(report) => {
+                for skipped in report.skipped.iter() {
+                    warn!("Skipped {}, mkii has no such type", skipped);
+                }
+                info!(
+                    "Imported {} keys from {} (RDB version {}), skipped {}",
+                    report.keys,
+                    path.display(),
+                    report.version,
+                    report.skipped.len()
+                );
+            }
+            Err(e) => {
+                error!("Unable to import {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        aof_rewrite = true;
+    }
+    // the AOF is rewritten from the dataset when it doesn't hold all of
+    // it, then appended to from now on
+    if aof::enabled() {
+        if let Err(e) = aof::start(&worker_pool, aof_rewrite).await {
+            error!("Unable to open the append only file: {}", e);
+            std::process::exit(1);
+        }
+    }
     let listener = TcpListener::bind(&addr).await.expect("unable to bind TCP listener");
     info!("Database is listening on {}", &addr);
     let mut incoming = listener.incoming();
@@ -28,6 +94,34 @@ async fn listen(addr: SocketAddr, worker_pool: tokio_io_pool::Handle) {
     }
 }
 
+// parse_memory parses a number of bytes with an optional unit, the same way
+// redis reads its configuration: 1k is 1000 bytes and 1kb is 1024 bytes.
+fn parse_memory(s: &str) -> Option<usize> {
+    let s = s.to_ascii_lowercase();
+    let units: [(&str, usize); 6] = [
+        ("kb", 1 << 10),
+        ("mb", 1 << 20),
+        ("gb", 1 << 30),
+        ("k", 1000),
+        ("m", 1000 * 1000),
+        ("g", 1000 * 1000 * 1000),
+    ];
+    let (digits, unit) = match units.iter().find(|(suffix, _)| s.ends_with(suffix)) {
+        Some((suffix, unit)) => (&s[..s.len() - suffix.len()], *unit),
+        None => (&s[..], 1),
+    };
+    digits.parse::<usize>().ok().and_then(|n| n.checked_mul(unit))
+}
+
+// parse_yes_no parses a boolean option the way redis does.
+fn parse_yes_no(s: &str) -> Option<bool> {
+    match s.to_ascii_lowercase().as_ref() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
 fn main() {
     match env::var("RUST_LOG").ok() {
         Some(_) => (),
@@ -42,22 +136,52 @@ fn main() {
     let mut iopool_builder = tokio_io_pool::Builder::default();
     iopool_builder.name_prefix("pool-worker-");
 
-    let args: Vec<String> = env::args().collect();
-    let pool_size = if args.len() > 1 {
-        args[1].parse().expect("invalid pool_size")
-    } else {
-        0
+    // usage: mkii [pool_size] [--maxmemory <bytes>] [--maxmemory-policy <policy>]
+    //             [--maxmemory-samples <n>] [--dir <path>] [--import-rdb <path>]
+    //             [--appendonly yes|no] [--appendfsync always|everysec|no]
+    let mut args = env::args().skip(1).peekable();
+    let pool_size = match args.peek() {
+        Some(arg) if !arg.starts_with("--") => args.next().unwrap().parse().expect("invalid pool_size"),
+        _ => 0,
     };
-
-    if pool_size > 0 {
-        iopool_builder.pool_size(pool_size);
-        info!("Thread pool size: {}", pool_size);
-    } else {
-        info!("Thread pool size: default");
+    let (mut maxmemory, mut policy, mut samples) = (0, database::Policy::NoEviction, 5);
+    let mut import = None;
+    let (mut appendonly, mut appendfsync) = (false, aof::Fsync::Everysec);
+    while let Some(flag) = args.next() {
+        let value = args.next().expect("missing value for option");
+        match flag.as_ref() {
+            "--maxmemory" => maxmemory = parse_memory(&value).expect("invalid maxmemory"),
+            "--maxmemory-policy" => policy = database::Policy::from_name(&value).expect("invalid maxmemory-policy"),
+            "--maxmemory-samples" => samples = value.parse().expect("invalid maxmemory-samples"),
+            "--dir" => snapshot::set_dir(value.into()),
+            "--import-rdb" => import = Some(PathBuf::from(value)),
+            "--appendonly" => appendonly = parse_yes_no(&value).expect("invalid appendonly"),
+            "--appendfsync" => appendfsync = aof::Fsync::from_name(&value).expect("invalid appendfsync"),
+            _ => panic!("unknown option {}", flag),
+        }
     }
+    if maxmemory > 0 {
+        info!("Max memory: {} bytes, policy {}", maxmemory, policy.name());
+    }
+    database::set_maxmemory(maxmemory, policy, samples);
+    if appendonly {
+        info!("Append only file enabled, fsync policy {}", appendfsync.name());
+    }
+    aof::configure(appendonly, appendfsync);
 
     let core_ids = core_affinity::get_core_ids().unwrap();
     info!("CPU has {} cores", core_ids.len());
+
+    // the pool size is always set explicitly, as every worker needs to have
+    // its own shard maintenance task spawned on it.
+    let workers = if pool_size > 0 {
+        info!("Thread pool size: {}", pool_size);
+        pool_size
+    } else {
+        info!("Thread pool size: default ({})", core_ids.len());
+        core_ids.len()
+    };
+    iopool_builder.pool_size(workers);
     {
         let i = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
         iopool_builder.after_start(move || {
@@ -81,8 +205,12 @@ fn main() {
     }
 
     let mut iopool = iopool_builder.build().unwrap();
+    database::set_shards(workers);
+    for i in 0..workers {
+        let _ = iopool.handle().spawn_on(i as u64, database::cron());
+    }
     {
-        let server_fut = listen(addr, iopool.handle().clone());
+        let server_fut = listen(addr, iopool.handle().clone(), import);
         if DO_PROFILE {
             iopool.spawn(server_fut).unwrap();
 
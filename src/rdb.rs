@@ -0,0 +1,784 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use bytes::Bytes;
+
+use super::database::{self, HashValue, Scalar, SetValue, SortedSetValue, Value};
+use super::dump;
+use super::snapshot::{self, Entries};
+
+// Importing reads dump.rdb files written by redis 3.2 (RDB version 7) up to
+// 6.x (version 9), as well as older ones and the listpack encodings added
+// by 7.x. Keys holding types mkii doesn't have (streams and module types)
+// are skipped when their encoding allows it, and listed in the report.
+const MIN_VERSION: u32 = 1;
+const MAX_VERSION: u32 = 11;
+
+const OP_FUNCTION2: u8 = 0xf5;
+const OP_MODULE_AUX: u8 = 0xf7;
+const OP_IDLE: u8 = 0xf8;
+const OP_FREQ: u8 = 0xf9;
+const OP_AUX: u8 = 0xfa;
+const OP_RESIZEDB: u8 = 0xfb;
+const OP_EXPIRETIME_MS: u8 = 0xfc;
+const OP_EXPIRETIME: u8 = 0xfd;
+const OP_SELECTDB: u8 = 0xfe;
+const OP_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE: u8 = 6;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// IMPORT_BATCH is the number of keys read before handing them to their
+// shards.
+const IMPORT_BATCH: usize = 1024;
+
+// Report sums up an import: the number of keys imported, and a
+// description of each of the keys (or other records) that were skipped.
+#[derive(Default)]
+pub struct Report {
+    pub version: u32,
+    pub keys: usize,
+    pub skipped: Vec<String>,
+}
+
+fn invalid<S: Into<String>>(msg: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+// Reader reads an RDB file, left is the number of bytes left in it. The
+// lengths read from the file are checked against it before anything is
+// allocated for them, so that a corrupt length fails the import instead.
+struct Reader<R> {
+    inner: R,
+    left: u64,
+}
+
+impl<R: Read> Reader<R> {
+    fn fill(&mut self, b: &mut [u8]) -> io::Result<()> {
+        if b.len() as u64 > self.left {
+            return Err(invalid("RDB file is truncated"));
+        }
+        self.inner.read_exact(b)?;
+        self.left -= b.len() as u64;
+        Ok(())
+    }
+
+    fn read(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        if n as u64 > self.left {
+            return Err(invalid("RDB file is truncated"));
+        }
+        let mut b = vec![0; n];
+        self.fill(&mut b)?;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut b = [0; 1];
+        self.fill(&mut b)?;
+        Ok(b[0])
+    }
+
+    // len reads a length, or the kind of special encoding of a string when
+    // the second value is set.
+    fn len_or_encoding(&mut self) -> io::Result<(u64, bool)> {
+        let b = self.u8()?;
+        match b >> 6 {
+            0 => Ok(((b & 0x3f) as u64, false)),
+            1 => Ok(((((b & 0x3f) as u64) << 8) | self.u8()? as u64, false)),
+            3 => Ok(((b & 0x3f) as u64, true)),
+            _ => match b {
+                0x80 => Ok((BigEndian::read_u32(&self.read(4)?) as u64, false)),
+                0x81 => Ok((BigEndian::read_u64(&self.read(8)?), false)),
+                _ => Err(invalid("invalid length encoding")),
+            },
+        }
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        match self.len_or_encoding()? {
+            (n, false) => Ok(n),
+            (_, true) => Err(invalid("unexpected string encoding")),
+        }
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        let n = match self.len_or_encoding()? {
+            (n, false) => n,
+            (0, true) => return Ok((self.u8()? as i8).to_string().into_bytes()),
+            (1, true) => return Ok(LittleEndian::read_i16(&self.read(2)?).to_string().into_bytes()),
+            (2, true) => return Ok(LittleEndian::read_i32(&self.read(4)?).to_string().into_bytes()),
+            (3, true) => {
+                let (compressed, len) = (self.len()?, self.len()?);
+                let data = self.read(compressed as usize)?;
+                return lzf_decompress(&data, len as usize);
+            }
+            (_, true) => return Err(invalid("invalid string encoding")),
+        };
+        self.read(n as usize)
+    }
+
+    fn bytes(&mut self) -> io::Result<Bytes> {
+        Ok(Bytes::from(self.string()?))
+    }
+
+    // double reads a score as written by redis before RDB version 8.
+    fn double(&mut self) -> io::Result<f64> {
+        match self.u8()? {
+            253 => Err(invalid("invalid NaN score")),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            n => {
+                let b = self.read(n as usize)?;
+                score(&b)
+            }
+        }
+    }
+
+    fn binary_double(&mut self) -> io::Result<f64> {
+        Ok(LittleEndian::read_f64(&self.read(8)?))
+    }
+
+    fn value(&mut self, kind: u8) -> io::Result<Value> {
+        let value = match kind {
+            TYPE_STRING => Value::Scalar(Scalar::String(self.bytes()?)),
+            TYPE_LIST => {
+                let n = self.len()?;
+                list((0..n).map(|_| self.bytes()).collect::<io::Result<_>>()?)
+            }
+            TYPE_SET => {
+                let n = self.len()?;
+                set((0..n).map(|_| self.bytes()).collect::<io::Result<_>>()?)
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let mut zset = SortedSetValue::default();
+                for _ in 0..self.len()? {
+                    let member = self.bytes()?;
+                    let score = if kind == TYPE_ZSET { self.double()? } else { self.binary_double()? };
+                    zset.insert(member, score);
+                }
+                Value::SortedSet(zset)
+            }
+            TYPE_HASH => {
+                let n = self.len()?;
+                let mut pairs = vec![];
+                for _ in 0..n {
+                    pairs.push(self.bytes()?);
+                    pairs.push(self.bytes()?);
+                }
+                hash(pairs)
+            }
+            TYPE_HASH_ZIPMAP => hash(zipmap(&self.string()?)?),
+            TYPE_LIST_ZIPLIST => list(ziplist(&self.string()?)?),
+            TYPE_SET_INTSET => set(intset(&self.string()?)?),
+            TYPE_SET_LISTPACK => set(listpack(&self.string()?)?),
+            TYPE_ZSET_ZIPLIST => zset(ziplist(&self.string()?)?)?,
+            TYPE_ZSET_LISTPACK => zset(listpack(&self.string()?)?)?,
+            TYPE_HASH_ZIPLIST => hash(ziplist(&self.string()?)?),
+            TYPE_HASH_LISTPACK => hash(listpack(&self.string()?)?),
+            TYPE_LIST_QUICKLIST => {
+                let mut items = vec![];
+                for _ in 0..self.len()? {
+                    items.extend(ziplist(&self.string()?)?);
+                }
+                list(items)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut items = vec![];
+                for _ in 0..self.len()? {
+                    match self.len()? {
+                        // a single large element stored as is
+                        1 => items.push(self.bytes()?),
+                        2 => items.extend(listpack(&self.string()?)?),
+                        _ => return Err(invalid("invalid quicklist node")),
+                    }
+                }
+                list(items)
+            }
+            _ => return Err(invalid(format!("unknown value type {}", kind))),
+        };
+        Ok(value)
+    }
+
+    // skip_stream reads past a stream, mkii has no stream type.
+    fn skip_stream(&mut self, kind: u8) -> io::Result<()> {
+        for _ in 0..self.len()? {
+            self.string()?;
+            self.string()?;
+        }
+        // length and last id, then first id, max deleted id and entries
+        // added since version 2
+        let fields = if kind == TYPE_STREAM_LISTPACKS { 3 } else { 8 };
+        for _ in 0..fields {
+            self.len()?;
+        }
+        for _ in 0..self.len()? {
+            self.string()?;
+            self.len()?;
+            self.len()?;
+            if kind != TYPE_STREAM_LISTPACKS {
+                self.len()?;
+            }
+            for _ in 0..self.len()? {
+                self.read(16 + 8)?;
+                self.len()?;
+            }
+            for _ in 0..self.len()? {
+                self.string()?;
+                self.read(if kind == TYPE_STREAM_LISTPACKS_3 { 16 } else { 8 })?;
+                for _ in 0..self.len()? {
+                    self.read(16)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // skip_module reads past a value (or aux data) of the module type
+    // named name, which since RDB version 9 is a self-describing list of
+    // typed fields.
+    fn skip_module(&mut self, name: &str) -> io::Result<()> {
+        loop {
+            match self.len()? {
+                0 => return Ok(()),
+                1 | 2 => {
+                    self.len()?;
+                }
+                3 => {
+                    self.read(4)?;
+                }
+                4 => {
+                    self.read(8)?;
+                }
+                5 => {
+                    self.string()?;
+                }
+                _ => return Err(invalid(format!("invalid value of module type {}", name))),
+            }
+        }
+    }
+}
+
+// module_name decodes the name of a module type from its id, nine
+// characters of 6 bits followed by a 10 bit version.
+fn module_name(id: u64) -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    (0..9).map(|i| CHARSET[((id >> (10 + 6 * (8 - i))) & 63) as usize] as char).collect()
+}
+
+fn score(b: &[u8]) -> io::Result<f64> {
+    std::str::from_utf8(b)
+        .ok()
+        .and_then(|s| match s {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            _ => s.parse::<f64>().ok(),
+        })
+        .filter(|f| !f.is_nan())
+        .ok_or_else(|| invalid("invalid score"))
+}
+
+fn list(items: Vec<Bytes>) -> Value {
+    Value::List(items.into())
+}
+
+fn set(members: Vec<Bytes>) -> Value {
+    let mut set = SetValue::new();
+    for member in members {
+        set.insert(member);
+    }
+    Value::Set(set)
+}
+
+// hash builds a hash from its fields and values, one after the other.
+fn hash(pairs: Vec<Bytes>) -> Value {
+    let mut hash = HashValue::with_capacity(pairs.len() / 2);
+    let mut pairs = pairs.into_iter();
+    while let (Some(field), Some(value)) = (pairs.next(), pairs.next()) {
+        hash.insert(field, Scalar::String(value));
+    }
+    Value::HashMap(hash)
+}
+
+// zset builds a sorted set from its members and scores, one after the
+// other.
+fn zset(pairs: Vec<Bytes>) -> io::Result<Value> {
+    let mut zset = SortedSetValue::default();
+    let mut pairs = pairs.into_iter();
+    while let (Some(member), Some(score_)) = (pairs.next(), pairs.next()) {
+        zset.insert(member, score(&score_)?);
+    }
+    Ok(Value::SortedSet(zset))
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Scalar(_) => false,
+        Value::List(l) => l.is_empty(),
+        Value::HashMap(h) => h.is_empty(),
+        Value::Set(s) => s.len() == 0,
+        Value::SortedSet(z) => z.len() == 0,
+    }
+}
+
+// Cursor reads the compact encodings, which are blobs stored as strings.
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        // a blob may be shorter than the header pos starts after
+        match self.buf.len().checked_sub(self.pos) {
+            Some(left) if left >= n => {}
+            _ => return Err(invalid("truncated compact encoding")),
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+}
+
+fn int(i: i64) -> Bytes {
+    Bytes::from(i.to_string())
+}
+
+// ziplist decodes the entries of a ziplist: a header of 10 bytes, then
+// entries made of the length of the previous entry, an encoding and the
+// data, and a 0xff terminator.
+fn ziplist(buf: &[u8]) -> io::Result<Vec<Bytes>> {
+    let mut c = Cursor { buf, pos: 10 };
+    let mut items = vec![];
+    loop {
+        let prevlen = c.u8()?;
+        if prevlen == 0xff {
+            return Ok(items);
+        }
+        if prevlen == 0xfe {
+            c.take(4)?;
+        }
+        let enc = c.u8()?;
+        let item = match enc >> 6 {
+            0 => Bytes::from(c.take((enc & 0x3f) as usize)?),
+            1 => {
+                let n = (((enc & 0x3f) as usize) << 8) | c.u8()? as usize;
+                Bytes::from(c.take(n)?)
+            }
+            2 => {
+                let n = BigEndian::read_u32(c.take(4)?) as usize;
+                Bytes::from(c.take(n)?)
+            }
+            _ => match enc {
+                0xc0 => int(LittleEndian::read_i16(c.take(2)?) as i64),
+                0xd0 => int(LittleEndian::read_i32(c.take(4)?) as i64),
+                0xe0 => int(LittleEndian::read_i64(c.take(8)?)),
+                0xf0 => int(LittleEndian::read_i24(c.take(3)?) as i64),
+                0xfe => int(c.u8()? as i8 as i64),
+                0xf1..=0xfd => int((enc & 0x0f) as i64 - 1),
+                _ => return Err(invalid("invalid ziplist encoding")),
+            },
+        };
+        items.push(item);
+    }
+}
+
+// listpack decodes the entries of a listpack: a header of 6 bytes, then
+// entries made of an encoding, the data and the length of both, and a 0xff
+// terminator.
+fn listpack(buf: &[u8]) -> io::Result<Vec<Bytes>> {
+    let mut c = Cursor { buf, pos: 6 };
+    let mut items = vec![];
+    loop {
+        let start = c.pos;
+        let enc = c.u8()?;
+        let item = if enc == 0xff {
+            return Ok(items);
+        } else if enc & 0x80 == 0 {
+            int((enc & 0x7f) as i64)
+        } else if enc & 0xc0 == 0x80 {
+            Bytes::from(c.take((enc & 0x3f) as usize)?)
+        } else if enc & 0xe0 == 0xc0 {
+            let n = (((enc & 0x1f) as i64) << 8) | c.u8()? as i64;
+            int(if n >= 1 << 12 { n - (1 << 13) } else { n })
+        } else if enc & 0xf0 == 0xe0 {
+            let n = (((enc & 0x0f) as usize) << 8) | c.u8()? as usize;
+            Bytes::from(c.take(n)?)
+        } else {
+            match enc {
+                0xf0 => {
+                    let n = LittleEndian::read_u32(c.take(4)?) as usize;
+                    Bytes::from(c.take(n)?)
+                }
+                0xf1 => int(LittleEndian::read_i16(c.take(2)?) as i64),
+                0xf2 => int(LittleEndian::read_i24(c.take(3)?) as i64),
+                0xf3 => int(LittleEndian::read_i32(c.take(4)?) as i64),
+                0xf4 => int(LittleEndian::read_i64(c.take(8)?)),
+                _ => return Err(invalid("invalid listpack encoding")),
+            }
+        };
+        // skip the backlen, the size of the entry so far in 7 bit groups
+        let size = c.pos - start;
+        c.take(match size {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2_097_150 => 3,
+            2_097_151..=268_435_454 => 4,
+            _ => 5,
+        })?;
+        items.push(item);
+    }
+}
+
+// intset decodes an intset: the width of its integers, their number and
+// the integers.
+fn intset(buf: &[u8]) -> io::Result<Vec<Bytes>> {
+    let mut c = Cursor { buf, pos: 0 };
+    let width = LittleEndian::read_u32(c.take(4)?) as usize;
+    let n = LittleEndian::read_u32(c.take(4)?) as usize;
+    (0..n)
+        .map(|_| {
+            let b = c.take(width)?;
+            match width {
+                2 => Ok(int(LittleEndian::read_i16(b) as i64)),
+                4 => Ok(int(LittleEndian::read_i32(b) as i64)),
+                8 => Ok(int(LittleEndian::read_i64(b))),
+                _ => Err(invalid("invalid intset encoding")),
+            }
+        })
+        .collect()
+}
+
+// zipmap decodes the fields and values of a zipmap, the encoding of small
+// hashes before redis 2.6.
+fn zipmap(buf: &[u8]) -> io::Result<Vec<Bytes>> {
+    let mut c = Cursor { buf, pos: 1 };
+    let mut pairs = vec![];
+    loop {
+        let field = match zipmap_len(&mut c)? {
+            Some(n) => c.take(n)?,
+            None => return Ok(pairs),
+        };
+        let n = zipmap_len(&mut c)?.ok_or_else(|| invalid("invalid zipmap encoding"))?;
+        let free = c.u8()? as usize;
+        let value = c.take(n)?;
+        c.take(free)?;
+        pairs.push(Bytes::from(field));
+        pairs.push(Bytes::from(value));
+    }
+}
+
+// zipmap_len reads a length in a zipmap, None at its end.
+fn zipmap_len(c: &mut Cursor) -> io::Result<Option<usize>> {
+    match c.u8()? {
+        0xff => Ok(None),
+        0xfe => Ok(Some(LittleEndian::read_u32(c.take(4)?) as usize)),
+        n => Ok(Some(n as usize)),
+    }
+}
+
+// LZF_MAX_RATIO bounds the length of an LZF compressed string once
+// decompressed: at best 3 bytes of input repeat 264 bytes of output.
+const LZF_MAX_RATIO: usize = 88;
+
+// lzf_decompress decompresses the LZF compressed strings of RDB files, len
+// is the length of the decompressed string.
+fn lzf_decompress(input: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let corrupt = || invalid("invalid LZF compressed string");
+    if len > input.len().saturating_mul(LZF_MAX_RATIO) {
+        return Err(corrupt());
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            if out.len() + literal.len() > len {
+                return Err(corrupt());
+            }
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            let mut n = ctrl >> 5;
+            if n == 7 {
+                n += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            let back = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or_else(corrupt)? as usize + 1;
+            i += 1;
+            if back > out.len() || out.len() + n + 2 > len {
+                return Err(corrupt());
+            }
+            let from = out.len() - back;
+            for j in 0..n + 2 {
+                let b = out[from + j];
+                out.push(b);
+            }
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+// verify checks the CRC64 which, since RDB version 5, ends the file. It is
+// 0 if redis was configured not to compute it.
+fn verify(path: &Path) -> io::Result<()> {
+    let mut f = BufReader::new(File::open(path)?);
+    let mut left = f.get_ref().metadata()?.len().checked_sub(8).ok_or_else(|| invalid("RDB file is truncated"))?;
+    let mut crc = 0;
+    let mut buf = vec![0; 1 << 16];
+    while left > 0 {
+        let n = std::cmp::min(left, buf.len() as u64) as usize;
+        f.read_exact(&mut buf[..n])?;
+        crc = dump::crc64(crc, &buf[..n]);
+        left -= n as u64;
+    }
+    let mut trailer = [0; 8];
+    f.read_exact(&mut trailer)?;
+    match LittleEndian::read_u64(&trailer) {
+        0 => Ok(()),
+        expected if expected == crc => Ok(()),
+        _ => Err(invalid("RDB file checksum is wrong")),
+    }
+}
+
+// import reads the RDB file at path into the shards owning its keys, its
+// checksum is verified first so that a corrupt file isn't partly imported.
+// Keys are handed to their shards in batches as they are read, keys
+// already expired are left out.
+pub async fn import(pool: &tokio_io_pool::Handle, path: &Path) -> io::Result<Report> {
+    let file = File::open(path)?;
+    let left = file.metadata()?.len();
+    let mut r = Reader {
+        inner: BufReader::new(file),
+        left,
+    };
+    let header = r.read(9)?;
+    let version = match std::str::from_utf8(&header[5..]).ok().and_then(|v| v.parse::<u32>().ok()) {
+        Some(v) if &header[..5] == b"REDIS" => v,
+        _ => return Err(invalid("not an RDB file")),
+    };
+    if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+        return Err(invalid(format!("unsupported RDB version {}", version)));
+    }
+    if version >= 5 {
+        verify(path)?;
+    }
+
+    let now = database::now_ms();
+    let mut report = Report { version, ..Default::default() };
+    let (mut batch, mut db, mut expires_at) = (Entries::new(), 0, None);
+    loop {
+        match r.u8()? {
+            OP_EOF => break,
+            OP_SELECTDB => {
+                db = r.len()? as usize;
+                if db >= database::DATABASES {
+                    return Err(invalid(format!("DB index {} is out of range", db)));
+                }
+            }
+            OP_RESIZEDB => {
+                r.len()?;
+                r.len()?;
+            }
+            OP_AUX => {
+                r.string()?;
+                r.string()?;
+            }
+            OP_EXPIRETIME_MS => expires_at = Some(LittleEndian::read_u64(&r.read(8)?)),
+            OP_EXPIRETIME => expires_at = Some(LittleEndian::read_u32(&r.read(4)?) as u64 * 1000),
+            OP_IDLE => {
+                r.len()?;
+            }
+            OP_FREQ => {
+                r.u8()?;
+            }
+            OP_MODULE_AUX => {
+                let name = module_name(r.len()?);
+                r.len()?;
+                r.len()?;
+                r.skip_module(&name)?;
+                report.skipped.push(format!("aux data of module type {}", name));
+            }
+            OP_FUNCTION2 => {
+                r.string()?;
+                report.skipped.push("function library".to_string());
+            }
+            kind => {
+                let key = r.bytes()?;
+                let deadline = expires_at.take();
+                let value = match kind {
+                    TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                        r.skip_stream(kind)?;
+                        report.skipped.push(format!("stream {:?}", String::from_utf8_lossy(&key)));
+                        continue;
+                    }
+                    TYPE_MODULE_2 => {
+                        let name = module_name(r.len()?);
+                        r.skip_module(&name)?;
+                        report.skipped.push(format!("key {:?} of module type {}", String::from_utf8_lossy(&key), name));
+                        continue;
+                    }
+                    TYPE_MODULE => {
+                        return Err(invalid(format!(
+                            "key '{}' holds a value of a module type that can't be skipped",
+                            String::from_utf8_lossy(&key)
+                        )))
+                    }
+                    _ => r.value(kind)?,
+                };
+                if is_empty(&value) || deadline.is_some_and(|t| t <= now) {
+                    continue;
+                }
+                batch.push((db, key, value, deadline));
+                report.keys += 1;
+                if batch.len() >= IMPORT_BATCH {
+                    snapshot::distribute(pool, std::mem::take(&mut batch)).await;
+                }
+            }
+        }
+    }
+    snapshot::distribute(pool, batch).await;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: Vec<Bytes>) -> Vec<String> {
+        items.iter().map(|b| String::from_utf8_lossy(b).into_owned()).collect()
+    }
+
+    #[test]
+    fn decode_ziplist() {
+        let mut buf = vec![0; 10];
+        buf.extend_from_slice(&[0, 0x03, b'a', b'b', b'c']);
+        buf.extend_from_slice(&[5, 0xf8]);
+        buf.extend_from_slice(&[2, 0xc0, 0xfe, 0xff]);
+        buf.extend_from_slice(&[4, 0xf0, 0x60, 0x79, 0xfe]);
+        buf.extend_from_slice(&[5, 0xd0]);
+        buf.extend_from_slice(&100_000i32.to_le_bytes());
+        buf.extend_from_slice(&[6, 0xe0]);
+        buf.extend_from_slice(&i64::MIN.to_le_bytes());
+        buf.extend_from_slice(&[10, 0xfe, 0xfb]);
+        buf.extend_from_slice(&[3, 0x41, 0x2c]);
+        buf.extend_from_slice(&[b'x'; 300]);
+        buf.extend_from_slice(&[0xfe, 0x2f, 0x01, 0, 0, 0x80]);
+        buf.extend_from_slice(&300u32.to_be_bytes());
+        buf.extend_from_slice(&[b'y'; 300]);
+        buf.push(0xff);
+        let items = strings(ziplist(&buf).unwrap());
+        assert_eq!(&items[..7], ["abc", "7", "-2", "-100000", "100000", "-9223372036854775808", "-5"]);
+        assert_eq!(items[7], "x".repeat(300));
+        assert_eq!(items[8], "y".repeat(300));
+        assert_eq!(items.len(), 9);
+
+        // no terminator
+        assert!(ziplist(&buf[..buf.len() - 1]).is_err());
+        assert!(ziplist(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn decode_listpack() {
+        let mut buf = vec![0; 6];
+        buf.extend_from_slice(&[0x05, 1]);
+        buf.extend_from_slice(&[0x82, b'h', b'i', 3]);
+        buf.extend_from_slice(&[0xdf, 0xff, 2]);
+        buf.extend_from_slice(&[0xe0, 0xc8]);
+        buf.extend_from_slice(&[b'x'; 200]);
+        buf.extend_from_slice(&[0x4a, 0x01]);
+        buf.extend_from_slice(&[0xf1, 0xd4, 0xfe, 4]);
+        buf.extend_from_slice(&[0xf2, 0x60, 0x79, 0xfe, 4]);
+        buf.push(0xf3);
+        buf.extend_from_slice(&100_000i32.to_le_bytes());
+        buf.push(5);
+        buf.push(0xf4);
+        buf.extend_from_slice(&i64::MAX.to_le_bytes());
+        buf.push(9);
+        buf.push(0xf0);
+        buf.extend_from_slice(&5u32.to_le_bytes());
+        buf.extend_from_slice(b"hello");
+        buf.push(10);
+        buf.push(0xff);
+        let items = strings(listpack(&buf).unwrap());
+        assert_eq!(&items[..3], ["5", "hi", "-1"]);
+        assert_eq!(items[3], "x".repeat(200));
+        assert_eq!(&items[4..], ["-300", "-100000", "100000", "9223372036854775807", "hello"]);
+
+        assert!(listpack(&buf[..buf.len() - 1]).is_err());
+        assert!(listpack(&[0, 0, 0, 0, 0, 0, 0xf5, 1, 0xff]).is_err());
+    }
+
+    #[test]
+    fn decode_intset() {
+        let mut buf = vec![];
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        for i in [-1i16, 0, 300].iter() {
+            buf.extend_from_slice(&i.to_le_bytes());
+        }
+        assert_eq!(strings(intset(&buf).unwrap()), ["-1", "0", "300"]);
+        assert!(intset(&buf[..buf.len() - 1]).is_err());
+
+        let mut buf = vec![];
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        for i in [i64::MIN, i64::MAX].iter() {
+            buf.extend_from_slice(&i.to_le_bytes());
+        }
+        assert_eq!(strings(intset(&buf).unwrap()), ["-9223372036854775808", "9223372036854775807"]);
+
+        let mut buf = vec![];
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&[0, 0, 0]);
+        assert!(intset(&buf).is_err());
+    }
+
+    #[test]
+    fn decompress_lzf() {
+        // a literal followed by a short and a long back reference, both
+        // overlapping the bytes they copy
+        let input = [0x02, b'a', b'b', b'c', 0x20, 0x02, 0xe0, 0x00, 0x05];
+        assert_eq!(lzf_decompress(&input, 15).unwrap(), b"abcabcabcabcabc".to_vec());
+
+        // the decompressed length doesn't match
+        assert!(lzf_decompress(&input, 14).is_err());
+        assert!(lzf_decompress(&input, 16).is_err());
+        // a back reference before the start of the output
+        assert!(lzf_decompress(&[0x20, 0x00], 3).is_err());
+        // a truncated literal
+        assert!(lzf_decompress(&[0x05, b'a'], 6).is_err());
+        // a length the input could never decompress to
+        assert!(lzf_decompress(&[0x00, b'a'], 1 << 20).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::BTreeSet;
+
+use bytes::Bytes;
+
+// Collections with at most SCAN_COMPACT_LEN members are returned whole by
+// SSCAN, HSCAN and ZSCAN, in a single call.
+const SCAN_COMPACT_LEN: usize = 128;
+
+// ScanIndex orders the members of a collection by hash, so that SSCAN,
+// HSCAN and ZSCAN resume where they left off instead of hashing and
+// sorting the whole collection on every call. Collections that are never
+// scanned don't pay for it: it is built by the first page, and kept up to
+// date by the collection from then on.
+#[derive(Default)]
+pub struct ScanIndex(Option<BTreeSet<(u64, Bytes)>>);
+
+impl ScanIndex {
+    pub fn insert(&mut self, member: &Bytes) {
+        if let Some(index) = self.0.as_mut() {
+            index.insert((seahash::hash(member), member.clone()));
+        }
+    }
+
+    pub fn remove(&mut self, member: &Bytes) {
+        if let Some(index) = self.0.as_mut() {
+            index.remove(&(seahash::hash(member), member.clone()));
+        }
+    }
+
+    // page returns at least count members (fewer at the end) in hash order,
+    // starting at the first member whose hash is at least cursor, along with
+    // the cursor to resume from, 0 once the end was reached. Members sharing
+    // a hash are never split across calls. members must be every member of
+    // the collection, small collections are returned whole from them
+    // without building the index.
+    pub fn page<'a, I>(&mut self, members: I, cursor: u64, count: usize) -> (Vec<Bytes>, u64)
+    where
+        I: ExactSizeIterator<Item = &'a Bytes>,
+    {
+        if members.len() <= SCAN_COMPACT_LEN {
+            return (members.cloned().collect(), 0);
+        }
+        let mut page = Vec::with_capacity(count.min(members.len()));
+        let index = self.0.get_or_insert_with(|| members.map(|m| (seahash::hash(m), m.clone())).collect());
+        let mut last = None;
+        for (h, member) in index.range((cursor, Bytes::new())..) {
+            if page.len() >= count && last != Some(*h) {
+                return (page, *h);
+            }
+            page.push(member.clone());
+            last = Some(*h);
+        }
+        (page, 0)
+    }
+}
@@ -0,0 +1,356 @@
+use std::mem;
+
+use bytes::Bytes;
+use rand::Rng;
+
+// SkipList keeps the members of a sorted set ordered by (score, member).
+// Each link records how many nodes it skips over, so the rank of a node can
+// be computed on the way down, making rank queries logarithmic as well.
+//
+// Nodes live in a Vec and link to each other by index, freed slots are
+// reused by later inserts. The node at index HEAD is the sentinel head.
+const MAX_LEVEL: usize = 32;
+const HEAD: usize = 0;
+pub const NIL: usize = usize::MAX;
+
+#[derive(Clone)]
+struct Level {
+    forward: usize,
+    span: usize,
+}
+
+struct Node {
+    member: Bytes,
+    score: f64,
+    backward: usize,
+    levels: Vec<Level>,
+}
+
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    tail: usize,
+}
+
+fn random_level() -> usize {
+    let mut rng = rand::thread_rng();
+    let mut level = 1;
+    // each level is promoted with a probability of 1/4
+    while level < MAX_LEVEL && rng.gen_range(0, 4) == 0 {
+        level += 1;
+    }
+    level
+}
+
+impl Default for SkipList {
+    fn default() -> SkipList {
+        SkipList {
+            nodes: vec![Node {
+                member: Bytes::new(),
+                score: 0.0,
+                backward: NIL,
+                levels: vec![Level { forward: NIL, span: 0 }; MAX_LEVEL],
+            }],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            tail: NIL,
+        }
+    }
+}
+
+impl SkipList {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn member(&self, n: usize) -> &Bytes {
+        &self.nodes[n].member
+    }
+
+    pub fn score(&self, n: usize) -> f64 {
+        self.nodes[n].score
+    }
+
+    // first and last return the lowest and highest ranked nodes, or NIL if
+    // the list is empty.
+    pub fn first(&self) -> usize {
+        self.nodes[HEAD].levels[0].forward
+    }
+
+    pub fn last(&self) -> usize {
+        self.tail
+    }
+
+    pub fn next(&self, n: usize) -> usize {
+        self.nodes[n].levels[0].forward
+    }
+
+    pub fn prev(&self, n: usize) -> usize {
+        self.nodes[n].backward
+    }
+
+    // seek walks down the list and returns, for every level, the last node
+    // for which before(score, member, rank) holds along with its 1-based
+    // rank. The rank passed to before is the 1-based rank of the node being
+    // looked at.
+    fn seek<F>(&self, before: F) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL])
+    where
+        F: Fn(f64, &[u8], usize) -> bool,
+    {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            loop {
+                let Level { forward, span } = self.nodes[x].levels[i];
+                if forward == NIL {
+                    break;
+                }
+                let next = &self.nodes[forward];
+                if !before(next.score, &next.member, rank[i] + span) {
+                    break;
+                }
+                rank[i] += span;
+                x = forward;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    // last_before returns the last node for which before holds (NIL if there
+    // is none) and its 0-based rank.
+    pub fn last_before<F>(&self, before: F) -> (usize, usize)
+    where
+        F: Fn(f64, &[u8], usize) -> bool,
+    {
+        let (update, rank) = self.seek(before);
+        match update[0] {
+            HEAD => (NIL, 0),
+            n => (n, rank[0] - 1),
+        }
+    }
+
+    // first_after returns the first node for which before doesn't hold (NIL
+    // if there is none) and its 0-based rank.
+    pub fn first_after<F>(&self, before: F) -> (usize, usize)
+    where
+        F: Fn(f64, &[u8], usize) -> bool,
+    {
+        let (update, rank) = self.seek(before);
+        (self.nodes[update[0]].levels[0].forward, rank[0])
+    }
+
+    // insert adds member with score, member must not already be in the list.
+    pub fn insert(&mut self, score: f64, member: Bytes) {
+        let (mut update, mut rank) = self.seek(|s, m, _| s < score || (s == score && m < member.as_ref()));
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            backward: if update[0] == HEAD { NIL } else { update[0] },
+            levels: vec![Level { forward: NIL, span: 0 }; level],
+        };
+        let x = match self.free.pop() {
+            Some(x) => {
+                self.nodes[x] = node;
+                x
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let u = update[i];
+            let Level { forward, span } = self.nodes[u].levels[i];
+            self.nodes[x].levels[i] = Level {
+                forward,
+                span: span - (rank[0] - rank[i]),
+            };
+            self.nodes[u].levels[i] = Level {
+                forward: x,
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &u) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[u].levels[i].span += 1;
+        }
+
+        match self.nodes[x].levels[0].forward {
+            NIL => self.tail = x,
+            f => self.nodes[f].backward = x,
+        }
+        self.len += 1;
+    }
+
+    fn unlink(&mut self, x: usize, update: &[usize; MAX_LEVEL]) -> Bytes {
+        for (i, &u) in update.iter().enumerate().take(self.level) {
+            if self.nodes[u].levels[i].forward == x {
+                let Level { forward, span } = self.nodes[x].levels[i];
+                self.nodes[u].levels[i].span += span;
+                self.nodes[u].levels[i].span -= 1;
+                self.nodes[u].levels[i].forward = forward;
+            } else {
+                self.nodes[u].levels[i].span -= 1;
+            }
+        }
+        let backward = self.nodes[x].backward;
+        match self.nodes[x].levels[0].forward {
+            NIL => self.tail = backward,
+            f => self.nodes[f].backward = backward,
+        }
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].forward == NIL {
+            self.level -= 1;
+        }
+        self.len -= 1;
+        self.free.push(x);
+        let node = &mut self.nodes[x];
+        node.levels = Vec::new();
+        mem::replace(&mut node.member, Bytes::new())
+    }
+
+    // remove deletes member with score, returns false if it wasn't found.
+    pub fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let (update, _) = self.seek(|s, m, _| s < score || (s == score && m < member));
+        let x = self.nodes[update[0]].levels[0].forward;
+        if x == NIL || self.nodes[x].score != score || self.nodes[x].member != member {
+            return false;
+        }
+        self.unlink(x, &update);
+        true
+    }
+
+    // remove_range deletes the run of nodes starting at the first node for
+    // which before doesn't hold and continuing while inside holds. It
+    // returns the removed members.
+    pub fn remove_range<B, I>(&mut self, before: B, inside: I) -> Vec<Bytes>
+    where
+        B: Fn(f64, &[u8], usize) -> bool,
+        I: Fn(f64, &[u8], usize) -> bool,
+    {
+        let (update, rank) = self.seek(before);
+        let mut removed = Vec::new();
+        let mut rank = rank[0] + 1;
+        loop {
+            let x = self.nodes[update[0]].levels[0].forward;
+            if x == NIL || !inside(self.nodes[x].score, &self.nodes[x].member, rank) {
+                break;
+            }
+            removed.push(self.unlink(x, &update));
+            rank += 1;
+        }
+        removed
+    }
+
+    // rank returns the 0-based rank of member with score.
+    pub fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let (n, rank) = self.last_before(|s, m, _| s < score || (s == score && m <= member));
+        if n != NIL && self.nodes[n].member == member {
+            Some(rank)
+        } else {
+            None
+        }
+    }
+
+    // by_rank returns the node with the 0-based rank, or NIL if rank is out
+    // of range.
+    pub fn by_rank(&self, rank: usize) -> usize {
+        if rank >= self.len {
+            return NIL;
+        }
+        self.last_before(|_, _, r| r <= rank + 1).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // check compares the list against model, which is kept sorted by
+    // (score, member), walking it both ways and by rank.
+    fn check(list: &SkipList, model: &[(f64, Bytes)]) {
+        assert_eq!(list.len(), model.len());
+        let mut n = list.first();
+        for (score, member) in model.iter() {
+            assert_eq!((list.score(n), list.member(n)), (*score, member));
+            n = list.next(n);
+        }
+        assert_eq!(n, NIL);
+        let mut n = list.last();
+        for (score, member) in model.iter().rev() {
+            assert_eq!((list.score(n), list.member(n)), (*score, member));
+            n = list.prev(n);
+        }
+        assert_eq!(n, NIL);
+        for (i, (score, member)) in model.iter().enumerate() {
+            assert_eq!(list.rank(*score, member), Some(i));
+            assert_eq!(list.member(list.by_rank(i)), member);
+        }
+        assert_eq!(list.by_rank(model.len()), NIL);
+    }
+
+    #[test]
+    fn random_operations() {
+        let mut rng = rand::thread_rng();
+        let mut list = SkipList::default();
+        let mut model: Vec<(f64, Bytes)> = Vec::new();
+        for round in 0..2000 {
+            let score = rng.gen_range(0, 20) as f64;
+            let member = Bytes::from(format!("m{}", rng.gen_range(0, 200)));
+            match rng.gen_range(0, 10) {
+                0..=5 => {
+                    if !model.iter().any(|(_, m)| *m == member) {
+                        list.insert(score, member.clone());
+                        model.push((score, member));
+                        model.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    }
+                }
+                6..=7 => {
+                    let found = model.iter().position(|(s, m)| *s == score && *m == member);
+                    assert_eq!(list.remove(score, &member), found.is_some());
+                    if let Some(i) = found {
+                        model.remove(i);
+                    }
+                    // a member stored under another score is not removed
+                    if let Some((s, m)) = model.first().cloned() {
+                        assert!(!list.remove(s + 0.5, &m));
+                    }
+                }
+                8 => {
+                    let start = rng.gen_range(0, model.len() + 1);
+                    let stop = start + rng.gen_range(0, 5);
+                    let removed = list.remove_range(|_, _, r| r <= start, |_, _, r| r <= stop + 1);
+                    let expected: Vec<Bytes> = model.drain(start..model.len().min(stop + 1)).map(|(_, m)| m).collect();
+                    assert_eq!(removed, expected);
+                }
+                _ => {
+                    let min = score;
+                    let max = score + rng.gen_range(0, 3) as f64;
+                    let removed = list.remove_range(|s, _, _| s < min, |s, _, _| s <= max);
+                    let expected: Vec<Bytes> = model.iter().filter(|(s, _)| *s >= min && *s <= max).map(|(_, m)| m.clone()).collect();
+                    model.retain(|(s, _)| *s < min || *s > max);
+                    assert_eq!(removed, expected);
+                }
+            }
+            if round % 50 == 0 {
+                check(&list, &model);
+            }
+        }
+        check(&list, &model);
+    }
+}
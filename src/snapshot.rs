@@ -0,0 +1,456 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future;
+use lazy_static::lazy_static;
+use log::info;
+
+use super::command::shard_for;
+use super::database::{self, Database, Value};
+use super::dump;
+
+// A snapshot is one file per shard, named after the shard and the
+// generation of the snapshot, tied together by a manifest. The manifest is
+// only replaced once every shard file of a new generation is complete, so
+// it always points at a whole snapshot.
+//
+// A shard file starts with a header (MAGIC, the format version, the shard
+// and the number of shards), then holds records, and ends with OP_EOF and
+// the CRC64 of everything before it. The records are OP_SELECTDB followed
+// by a database number, which applies to the entries after it, and
+// OP_ENTRY followed by a key and a value encoded as in DUMP payloads,
+// preceded by OP_EXPIRE_MS and a deadline if the key has one.
+const MAGIC: &[u8] = b"MKII";
+const HEADER_LEN: usize = 14;
+const OP_ENTRY: u8 = 0xfa;
+const OP_EXPIRE_MS: u8 = 0xfc;
+const OP_SELECTDB: u8 = 0xfe;
+const OP_EOF: u8 = 0xff;
+
+const MANIFEST: &str = "mkii.manifest";
+const MANIFEST_HEADER: &str = "mkii-manifest";
+
+// SAVE_CHUNK is the number of keys BGSAVE writes at a time before letting
+// the shard serve requests again.
+const SAVE_CHUNK: usize = 1024;
+
+lazy_static! {
+    static ref DIR: Mutex<PathBuf> = Mutex::new(PathBuf::from("."));
+}
+
+// SAVING is set while a snapshot is being written, only one can be at a
+// time. LASTSAVE is the unix time of the last complete snapshot.
+static SAVING: AtomicBool = AtomicBool::new(false);
+static LASTSAVE: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_dir(dir: PathBuf) {
+    *DIR.lock().unwrap() = dir;
+}
+
+pub fn dir() -> PathBuf {
+    DIR.lock().unwrap().clone()
+}
+
+pub fn lastsave() -> u64 {
+    LASTSAVE.load(Ordering::SeqCst)
+}
+
+// start marks a snapshot as in progress, returns false if one already is.
+pub fn start() -> bool {
+    !SAVING.swap(true, Ordering::SeqCst)
+}
+
+pub fn finish() {
+    SAVING.store(false, Ordering::SeqCst);
+}
+
+// write_entry appends the record of key to buf.
+pub fn write_entry(buf: &mut BytesMut, key: &[u8], value: &Value, expires_at: Option<u64>) {
+    buf.reserve(10);
+    if let Some(t) = expires_at {
+        buf.put_u8(OP_EXPIRE_MS);
+        buf.put_u64_le(t);
+    }
+    buf.put_u8(OP_ENTRY);
+    dump::put_bytes(buf, key);
+    dump::write_value(buf, value);
+}
+
+// Manifest lists the shard files of a snapshot.
+pub struct Manifest {
+    pub generation: u64,
+    pub time: u64,
+    pub shards: Vec<ShardFile>,
+}
+
+pub struct ShardFile {
+    pub name: String,
+    pub len: u64,
+    pub crc: u64,
+}
+
+impl Manifest {
+    // read reads the manifest in dir, returns None if there is none.
+    pub fn read(dir: &Path) -> io::Result<Option<Manifest>> {
+        let text = match fs::read_to_string(dir.join(MANIFEST)) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Manifest::parse(&text).map(Some).ok_or_else(|| invalid("invalid snapshot manifest"))
+    }
+
+    fn parse(text: &str) -> Option<Manifest> {
+        let mut lines = text.lines().map(|l| l.split_whitespace().collect::<Vec<_>>());
+        let mut field = |name: &str| match lines.next() {
+            Some(ref l) if l.len() == 2 && l[0] == name => Some(l[1].to_string()),
+            _ => None,
+        };
+        if field(MANIFEST_HEADER)?.parse::<u16>().ok()? > dump::VERSION {
+            return None;
+        }
+        let generation = field("generation")?.parse().ok()?;
+        let time = field("time")?.parse().ok()?;
+        let n: usize = field("shards")?.parse().ok()?;
+        let mut shards = Vec::with_capacity(n);
+        for (i, l) in lines.enumerate() {
+            if l.len() != 5 || l[0] != "shard" || l[1].parse() != Ok(i) {
+                return None;
+            }
+            shards.push(ShardFile {
+                name: l[2].to_string(),
+                len: l[3].parse().ok()?,
+                crc: u64::from_str_radix(l[4], 16).ok()?,
+            });
+        }
+        if shards.len() != n {
+            return None;
+        }
+        Some(Manifest { generation, time, shards })
+    }
+
+    // write replaces the manifest in dir, atomically.
+    fn write(&self, dir: &Path) -> io::Result<()> {
+        let mut text = format!(
+            "{} {}\ngeneration {}\ntime {}\nshards {}\n",
+            MANIFEST_HEADER,
+            dump::VERSION,
+            self.generation,
+            self.time,
+            self.shards.len()
+        );
+        for (i, shard) in self.shards.iter().enumerate() {
+            text.push_str(&format!("shard {} {} {} {:016x}\n", i, shard.name, shard.len, shard.crc));
+        }
+        replace_file(dir, MANIFEST, text.as_bytes())
+    }
+}
+
+// replace_file replaces the file name in dir with contents, atomically: a
+// crash leaves either the old file or the new one.
+pub fn replace_file(dir: &Path, name: &str, contents: &[u8]) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", name));
+    let mut f = File::create(&tmp)?;
+    f.write_all(contents)?;
+    f.sync_all()?;
+    fs::rename(&tmp, dir.join(name))?;
+    File::open(dir)?.sync_all()
+}
+
+// Writer writes a shard file, keeping the running CRC64 of its content.
+struct Writer {
+    file: BufWriter<File>,
+    len: u64,
+    crc: u64,
+}
+
+impl Writer {
+    fn create(path: &Path, shard: usize, shards: usize) -> io::Result<Writer> {
+        let mut w = Writer {
+            file: BufWriter::new(File::create(path)?),
+            len: 0,
+            crc: 0,
+        };
+        let mut header = BytesMut::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.put_u16_le(dump::VERSION);
+        header.put_u32_le(shard as u32);
+        header.put_u32_le(shards as u32);
+        w.write(&header)?;
+        Ok(w)
+    }
+
+    fn write(&mut self, b: &[u8]) -> io::Result<()> {
+        self.file.write_all(b)?;
+        self.len += b.len() as u64;
+        self.crc = dump::crc64(self.crc, b);
+        Ok(())
+    }
+
+    fn select(&mut self, db: usize) -> io::Result<()> {
+        let mut buf = BytesMut::with_capacity(11);
+        buf.put_u8(OP_SELECTDB);
+        dump::put_len(&mut buf, db);
+        self.write(&buf)
+    }
+
+    // close writes the trailer and syncs the file to disk, it returns the
+    // length and CRC64 of the file.
+    fn close(mut self) -> io::Result<(u64, u64)> {
+        self.write(&[OP_EOF])?;
+        let crc = self.crc;
+        self.file.write_all(&crc.to_le_bytes())?;
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok((self.len + 8, crc))
+    }
+}
+
+// step writes up to chunk keys of the snapshot in progress in dbs, the
+// databases of a shard, returns true once all of them are written.
+fn step(dbs: &mut [Database], w: &mut Writer, chunk: usize) -> io::Result<bool> {
+    let (mut budget, mut finished) = (chunk, true);
+    for db in dbs.iter_mut() {
+        if let Some((n, records, done, complete)) = db.save_step(budget) {
+            budget -= done;
+            if !records.is_empty() {
+                w.select(n)?;
+                w.write(&records)?;
+            }
+            finished &= complete;
+        }
+    }
+    Ok(finished)
+}
+
+fn shard_file_name(generation: u64, shard: usize) -> String {
+    format!("mkii-{}-{}.snap", generation, shard)
+}
+
+fn begin(dbs: &mut [Database]) {
+    for (i, db) in dbs.iter_mut().enumerate() {
+        db.begin_save(i, database::Format::Snapshot);
+    }
+}
+
+// save_shard writes the snapshot of shard, which must have begun.
+async fn save_shard(pool: tokio_io_pool::Handle, path: PathBuf, shard: usize, chunk: usize) -> io::Result<(u64, u64)> {
+    let mut w = match Writer::create(&path, shard, database::shards()) {
+        Ok(w) => w,
+        Err(e) => {
+            database::with_shard_dbs(&pool, shard as u64, abort).await;
+            return Err(e);
+        }
+    };
+    loop {
+        let (returned, result) = database::with_shard_dbs(&pool, shard as u64, move |dbs| {
+            let result = step(dbs, &mut w, chunk);
+            (w, result)
+        })
+        .await;
+        w = returned;
+        match result {
+            Ok(true) => break,
+            Ok(false) => continue,
+            Err(e) => {
+                database::with_shard_dbs(&pool, shard as u64, abort).await;
+                return Err(e);
+            }
+        }
+    }
+    w.close()
+}
+
+fn abort(dbs: &mut [Database]) {
+    for db in dbs.iter_mut() {
+        db.abort_save();
+    }
+}
+
+// save writes a snapshot of every shard to the snapshot directory, then
+// points the manifest at it and removes the previous snapshot. Unless
+// background is set, every shard writes its snapshot in one go, without
+// serving requests in between. The caller must have called start.
+//
+// The shards begin their snapshot one after the other, with the gate
+// quiesced so that no value is on its way from one shard to another
+// meanwhile: a value moved before is in the snapshot where it was moved
+// to, one moved afterwards where it was moved from.
+pub async fn save(pool: &tokio_io_pool::Handle, background: bool) -> io::Result<()> {
+    let dir = dir();
+    let previous = Manifest::read(&dir).unwrap_or(None);
+    let now = database::now_ms();
+    let generation = match &previous {
+        Some(m) if m.generation >= now => m.generation + 1,
+        _ => now,
+    };
+    let shards = database::shards();
+    let chunk = if background { SAVE_CHUNK } else { usize::MAX };
+    let quiesced = database::quiesce().await;
+    database::with_every_shard_dbs(pool, begin).await;
+    drop(quiesced);
+    let names: Vec<String> = (0..shards).map(|i| shard_file_name(generation, i)).collect();
+    let results = future::join_all(
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| save_shard(pool.clone(), dir.join(name), i, chunk)),
+    )
+    .await;
+
+    let mut manifest = Manifest {
+        generation,
+        time: now / 1000,
+        shards: Vec::with_capacity(shards),
+    };
+    let mut failed = None;
+    for (name, result) in names.iter().zip(results) {
+        match result {
+            Ok((len, crc)) => manifest.shards.push(ShardFile { name: name.clone(), len, crc }),
+            Err(e) => failed = Some(e),
+        }
+    }
+    if let Some(e) = failed.map_or_else(|| manifest.write(&dir).err(), Some) {
+        for name in names.iter() {
+            let _ = fs::remove_file(dir.join(name));
+        }
+        return Err(e);
+    }
+    if let Some(previous) = previous {
+        for shard in previous.shards.iter() {
+            let _ = fs::remove_file(dir.join(&shard.name));
+        }
+    }
+    LASTSAVE.store(manifest.time, Ordering::SeqCst);
+    Ok(())
+}
+
+// Entries are entries read from a snapshot: their database number, key,
+// value and deadline.
+pub type Entries = Vec<(usize, Bytes, Value, Option<u64>)>;
+
+pub fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// read_file reads the shard file at path, checking it against its entry in
+// a manifest listing shards files. Keys past their deadline are skipped.
+fn read_file(path: &Path, file: &ShardFile, shards: usize) -> io::Result<Entries> {
+    let data = fs::read(path)?;
+    if data.len() as u64 != file.len || data.len() < HEADER_LEN + 9 {
+        return Err(invalid("snapshot file is truncated"));
+    }
+    let (body, trailer) = data.split_at(data.len() - 8);
+    let crc = LittleEndian::read_u64(trailer);
+    if crc != file.crc || dump::crc64(0, body) != crc {
+        return Err(invalid("snapshot file checksum is wrong"));
+    }
+    if &body[..MAGIC.len()] != MAGIC || LittleEndian::read_u32(&body[10..HEADER_LEN]) as usize != shards {
+        return Err(invalid("not a file of this snapshot"));
+    }
+    if LittleEndian::read_u16(&body[4..6]) > dump::VERSION {
+        return Err(invalid("snapshot version is not supported"));
+    }
+
+    let now = database::now_ms();
+    let mut r = dump::Reader::new(&body[HEADER_LEN..]);
+    let (mut entries, mut db, mut expires_at) = (Entries::new(), 0, None);
+    loop {
+        match r.u8().map_err(invalid)? {
+            OP_SELECTDB => {
+                db = r.len().map_err(invalid)?;
+                if db >= database::DATABASES {
+                    return Err(invalid("DB index is out of range"));
+                }
+            }
+            OP_EXPIRE_MS => expires_at = Some(r.u64().map_err(invalid)?),
+            OP_ENTRY => {
+                let key = r.bytes().map_err(invalid)?;
+                let value = r.value().map_err(invalid)?;
+                match expires_at.take() {
+                    Some(t) if t <= now => {}
+                    t => entries.push((db, key, value, t)),
+                }
+            }
+            OP_EOF if r.remaining() == 0 => return Ok(entries),
+            _ => return Err(invalid("Bad data format")),
+        }
+    }
+}
+
+fn insert(dbs: &mut [Database], entries: Entries) {
+    for (db, key, value, expires_at) in entries {
+        dbs[db].set(key, value, expires_at);
+    }
+}
+
+// route splits entries by the worker owning their key, found the same way
+// commands find the shard of their keys.
+fn route(pool: &tokio_io_pool::Handle, entries: Entries) -> Vec<Entries> {
+    let mut owned: Vec<Entries> = (0..database::shards()).map(|_| Entries::new()).collect();
+    for entry in entries {
+        owned[pool.worker_id(shard_for(&entry.1))].push(entry);
+    }
+    owned
+}
+
+// hand_over inserts owned, as returned by route, into every shard.
+async fn hand_over(pool: &tokio_io_pool::Handle, owned: Vec<Entries>) {
+    let handed = owned
+        .into_iter()
+        .enumerate()
+        .filter(|(_, entries)| !entries.is_empty())
+        .map(|(worker, entries)| database::with_shard_dbs(pool, worker as u64, move |dbs| insert(dbs, entries)));
+    future::join_all(handed).await;
+}
+
+// distribute inserts entries into the shards owning their keys.
+pub async fn distribute(pool: &tokio_io_pool::Handle, entries: Entries) {
+    hand_over(pool, route(pool, entries)).await
+}
+
+// load reads the snapshot in the snapshot directory, if there is one, into
+// the shards and returns the number of keys loaded. Shard file i is read
+// by worker i. If the snapshot was saved with a different number of
+// shards, the files are spread over the workers, and the worker reading a
+// file hands the keys it doesn't own to their owner.
+pub async fn load(pool: &tokio_io_pool::Handle) -> io::Result<usize> {
+    let dir = dir();
+    let manifest = match Manifest::read(&dir)? {
+        Some(manifest) => manifest,
+        None => return Ok(0),
+    };
+    let (saved, shards) = (manifest.shards.len(), database::shards());
+    if saved != shards {
+        info!("Snapshot was saved with {} shards, rehashing its keys onto {}", saved, shards);
+    }
+    let read = future::join_all(manifest.shards.into_iter().enumerate().map(|(i, file)| {
+        let (path, pool) = (dir.join(&file.name), pool.clone());
+        async move {
+            let shard = pool.clone();
+            database::with_shard_dbs(&shard, i as u64, move |dbs| -> io::Result<(usize, Vec<Entries>)> {
+                let mut owned = route(&pool, read_file(&path, &file, saved)?);
+                let mine = std::mem::take(&mut owned[pool.worker_id(i as u64)]);
+                let n = mine.len();
+                insert(dbs, mine);
+                Ok((n, owned))
+            })
+            .await
+        }
+    }))
+    .await;
+
+    let mut keys = 0;
+    for result in read {
+        let (n, owned) = result?;
+        keys += n + owned.iter().map(|entries| entries.len()).sum::<usize>();
+        hand_over(pool, owned).await;
+    }
+    LASTSAVE.store(manifest.time, Ordering::SeqCst);
+    Ok(keys)
+}